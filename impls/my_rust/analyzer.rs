@@ -0,0 +1,487 @@
+//! Pre-analysis pass that compiles `MalType` code into a tree of `Node`s.
+//!
+//! `eval` re-dispatches on special-form names and re-runs `macroexpand` every
+//! time a form is evaluated. Here that work is done once per form: special
+//! forms are resolved into their own node kind, macro calls are expanded and
+//! the result is what gets executed by `run`.
+//!
+//! Function bodies and the sub-forms of a `do` are analyzed lazily, on their
+//! first execution, so that a macro defined earlier in the same `do` (which is
+//! how `load-file` evaluates a whole file) is known when its uses are analyzed.
+//!
+//! A symbol bound by a `let*`, `fn*` or `catch*` around it is resolved to the
+//! `Env` it is bound in, counted out from the innermost one, and its index
+//! there, so running it takes no string lookups. Other symbols are looked up
+//! by name, and so is a local whose `Env` turns out not to hold it at that
+//! index, as when a function is called with too few arguments.
+
+use std::{cell::RefCell, ops::Deref, rc::Rc};
+
+use crate::{
+    env::*,
+    fn_body, freshen,
+    interpreter::{call_builtin, tick, unbound},
+    is_macro_call, macroexpand,
+    namespace::eval_env,
+    quasiquote,
+    types::*,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node {
+    Const(MalType),
+    Symbol(String),
+    /// A local: how many `Env`s out it is bound, and its index there.
+    Local(usize, usize, String),
+    Vector(Vec<Rc<Node>>),
+    Dictionary(Vec<Rc<Node>>),
+    Set(Vec<Rc<Node>>),
+    Def(MalType, Rc<Node>),
+    DefMacro(MalType, Rc<Node>),
+    Let(Vec<(MalType, Rc<Node>)>, Rc<Node>),
+    Do(Vec<Rc<Node>>),
+    If(Rc<Node>, Rc<Node>, Option<Rc<Node>>),
+    Try(Rc<Node>, Option<(MalType, Rc<Node>)>),
+    Fn(MalType, Rc<Node>),
+    Eval(Rc<Node>),
+    /// An expansion of `quasiquote`, and the symbols it made for its `foo#`,
+    /// which each run makes fresh ones for.
+    Quasiquote(Rc<Node>, Vec<MalType>),
+    Macroexpand(MalType),
+    Call(Rc<Node>, Vec<Rc<Node>>),
+    Lazy(MalType, Scope, RefCell<Option<Rc<Node>>>),
+}
+
+/// The locals bound where a form is analyzed, innermost `Env` first, or None
+/// at the top level.
+pub type Scope = Option<Rc<Frame>>;
+
+/// The names one `Env` of a `Scope` binds, in the order they are bound.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Frame {
+    names: RefCell<Vec<String>>,
+    outer: Scope,
+}
+
+/// A scope with a new `Env` inside `outer`, binding `names`.
+fn frame<'a>(outer: &Scope, names: impl IntoIterator<Item = &'a MalType>) -> Scope {
+    let frame = Rc::new(Frame {
+        names: RefCell::new(Vec::new()),
+        outer: outer.clone(),
+    });
+    names.into_iter().for_each(|name| frame.bind(name));
+    Some(frame)
+}
+
+impl Frame {
+    /// Binds `name` after the names already bound, unless it is already, as
+    /// `env_set` does.
+    fn bind(&self, name: &MalType) {
+        let MalType::Symbol(name) = name else {
+            return;
+        };
+        let mut names = self.names.borrow_mut();
+        if !names.contains(name) {
+            names.push(name.clone());
+        }
+    }
+}
+
+/// How many `Env`s out from the innermost one `name` is bound in `scope`, and
+/// its index there.
+fn resolve(scope: &Scope, name: &str) -> Option<(usize, usize)> {
+    let mut scope = scope;
+    let mut depth = 0;
+    while let Some(frame) = scope {
+        if let Some(index) = frame.names.borrow().iter().position(|n| n == name) {
+            return Some((depth, index));
+        }
+        scope = &frame.outer;
+        depth += 1;
+    }
+    None
+}
+
+/// Entry point with the same signature as the tree-walking `eval`, so it can be
+/// stored in `MalType::MalFunc` and selected as the REPL evaluator.
+pub fn eval(ast: MalType, env: Env) -> MalType {
+    let node = match ast {
+        MalType::Analyzed(node) => node,
+        ast => analyze(&ast, &env, &None),
+    };
+
+    run(node, env)
+}
+
+/// Analyzes `ast` where the locals of `scope` are bound, in `env`, which it
+/// expands the macros it calls in.
+pub fn analyze(ast: &MalType, env: &Env, scope: &Scope) -> Rc<Node> {
+    let node = match ast {
+        MalType::Symbol(s) => match resolve(scope, s) {
+            Some((depth, index)) => Node::Local(depth, index, s.clone()),
+            None => Node::Symbol(s.clone()),
+        },
+        MalType::Vector(vector) => Node::Vector(analyze_all(vector, env, scope)),
+        MalType::Dictionary(dict) => Node::Dictionary(analyze_all(dict, env, scope)),
+        MalType::Set(set) => Node::Set(set.iter().map(|i| analyze(i, env, scope)).collect()),
+        MalType::List(list) if list.is_empty() => Node::Const(ast.clone()),
+        MalType::List(list) => return analyze_list(ast, list, env, scope),
+        _ => Node::Const(ast.clone()),
+    };
+
+    Rc::new(node)
+}
+
+fn analyze_all(items: &[MalType], env: &Env, scope: &Scope) -> Vec<Rc<Node>> {
+    items.iter().map(|i| analyze(i, env, scope)).collect()
+}
+
+fn analyze_list(ast: &MalType, list: &[MalType], env: &Env, scope: &Scope) -> Rc<Node> {
+    let local = |name: &MalType| {
+        if let Some(frame) = scope {
+            frame.bind(name);
+        }
+    };
+
+    let node = match &list[0] {
+        MalType::Symbol(s) if s.eq("quote") => Node::Const(list[1].clone()),
+        MalType::Symbol(s) if s.eq("quasiquoteexpand") => Node::Const(quasiquote(&list[1]).0),
+        MalType::Symbol(s) if s.eq("quasiquote") => match quasiquote(&list[1]) {
+            (expanded, made) if made.is_empty() => return analyze(&expanded, env, scope),
+            (expanded, made) => Node::Quasiquote(analyze(&expanded, env, scope), made),
+        },
+        MalType::Symbol(s) if s.eq("macroexpand") => Node::Macroexpand(list[1].clone()),
+        MalType::Symbol(s) if s.eq("eval") => Node::Eval(analyze(&list[1], env, scope)),
+        MalType::Symbol(s) if s.eq("def!") => {
+            local(&list[1]);
+            Node::Def(list[1].clone(), analyze(&list[2], env, scope))
+        }
+        MalType::Symbol(s) if s.eq("defmacro!") => {
+            local(&list[1]);
+            Node::DefMacro(list[1].clone(), analyze(&list[2], env, scope))
+        }
+        MalType::Symbol(s) if s.eq("let*") => {
+            let bindings = match &list[1] {
+                MalType::List(l) | MalType::Vector(l) => l,
+                _ => {
+                    println!(
+                        "ERROR: first element `{}` in let* binding is not a List/Vector",
+                        list[1]
+                    );
+                    return Rc::new(Node::Const(MalType::Nil));
+                }
+            };
+
+            let let_env = env_new(Some(env.clone()));
+            let let_scope = frame(scope, []);
+            let bindings = bindings
+                .chunks_exact(2)
+                .map(|pair| {
+                    let value = analyze(&pair[1], &let_env, &let_scope);
+                    let_scope.as_ref().unwrap().bind(&pair[0]);
+                    (pair[0].clone(), value)
+                })
+                .collect();
+
+            Node::Let(bindings, analyze(&list[2], &let_env, &let_scope))
+        }
+        MalType::Symbol(s) if s.eq("do") => Node::Do(
+            list.iter()
+                .skip(1)
+                .map(|item| Rc::new(Node::Lazy(item.clone(), scope.clone(), RefCell::new(None))))
+                .collect(),
+        ),
+        MalType::Symbol(s) if s.eq("if") => Node::If(
+            analyze(&list[1], env, scope),
+            analyze(&list[2], env, scope),
+            list.get(3).map(|e| analyze(e, env, scope)),
+        ),
+        MalType::Symbol(s) if s.eq("try*") => {
+            let catch = match list.get(2) {
+                Some(MalType::List(catch))
+                    if catch.first() == Some(&MalType::Symbol("catch*".to_owned())) =>
+                {
+                    let catch_env = env_new(Some(env.clone()));
+                    let catch_scope = frame(scope, [&catch[1]]);
+                    let handler = analyze(&catch[2], &catch_env, &catch_scope);
+                    Some((catch[1].clone(), handler))
+                }
+                _ => None,
+            };
+
+            Node::Try(analyze(&list[1], env, scope), catch)
+        }
+        MalType::Symbol(s) if s.eq("fn*") => {
+            let params = match &list[1] {
+                MalType::List(params) | MalType::Vector(params) => &params[..],
+                _ => &[],
+            };
+            let rest = MalType::Symbol("&".to_owned());
+            let params = params.iter().filter(|p| **p != rest);
+            let body = list[fn_body(list)].clone();
+            Node::Fn(
+                list[1].clone(),
+                Rc::new(Node::Lazy(body, frame(scope, params), RefCell::new(None))),
+            )
+        }
+        MalType::Symbol(s) if resolve(scope, s).is_none() && is_macro_call(ast, env) => {
+            return analyze(&macroexpand(ast.clone(), env), env, scope);
+        }
+        _ => Node::Call(
+            analyze(&list[0], env, scope),
+            analyze_all(&list[1..], env, scope),
+        ),
+    };
+
+    Rc::new(node)
+}
+
+pub fn run(mut node: Rc<Node>, mut env: Env) -> MalType {
+    loop {
+        if let Err(err) = tick() {
+            return err;
+        }
+
+        let next = match node.deref() {
+            Node::Const(value) => return value.clone(),
+            Node::Symbol(s) => {
+                return env_get(&env, s).unwrap_or_else(|| unbound(s));
+            }
+            Node::Local(depth, index, s) => {
+                return env_slot(&env, *depth, *index, s)
+                    .or_else(|| env_get(&env, s))
+                    .unwrap_or_else(|| unbound(s));
+            }
+            Node::Vector(items) => {
+                return match run_all(items, &env) {
+                    Ok(items) => MalType::Vector(items),
+                    Err(err) => err,
+                };
+            }
+            Node::Dictionary(items) => {
+                return match run_all(items, &env) {
+                    Ok(items) => MalType::Dictionary(items),
+                    Err(err) => err,
+                };
+            }
+            Node::Set(items) => {
+                return match run_all(items, &env) {
+                    Ok(items) => MalType::Set(items.into_iter().collect()),
+                    Err(err) => err,
+                };
+            }
+            Node::Def(symbol, value) => {
+                let v = run(value.clone(), env.clone());
+
+                if v.is_error() {
+                    return v;
+                }
+
+                env_set(&env, symbol, v.clone());
+                return v;
+            }
+            Node::DefMacro(symbol, value) => {
+                let mut v = run(value.clone(), env.clone());
+
+                if v.is_error() {
+                    return v;
+                }
+
+                if let MalType::MalFunc { is_macro, .. } = &mut v {
+                    *is_macro = true;
+                } else {
+                    println!("Returned a non-MalFunc from evaluating {}", symbol);
+                    return MalType::Nil;
+                }
+
+                env_set(&env, symbol, v.clone());
+                return v;
+            }
+            Node::Let(bindings, body) => {
+                let let_env = env_new(Some(env.clone()));
+
+                for (s, v) in bindings {
+                    let value = run(v.clone(), let_env.clone());
+                    if value.is_error() {
+                        return value;
+                    }
+
+                    env_set(&let_env, s, value);
+                }
+
+                // tco
+                env = let_env;
+                body.clone()
+            }
+            Node::Do(items) => {
+                let Some((last, init)) = items.split_last() else {
+                    return MalType::Nil;
+                };
+
+                for item in init {
+                    let value = run(item.clone(), env.clone());
+                    if value.is_error() {
+                        return value;
+                    }
+                }
+
+                // tco
+                last.clone()
+            }
+            Node::If(condition, then, otherwise) => match run(condition.clone(), env.clone()) {
+                err @ MalType::Error(_) => return err,
+                MalType::Nil | MalType::False => match otherwise {
+                    Some(otherwise) => otherwise.clone(),
+                    None => return MalType::Nil,
+                },
+                _ => then.clone(),
+            },
+            Node::Try(body, catch) => match (run(body.clone(), env.clone()), catch) {
+                (MalType::Error(thrown), Some((symbol, handler))) => {
+                    let catch_env = env_new(Some(env.clone()));
+                    env_set(&catch_env, symbol, *thrown);
+
+                    // tco
+                    env = catch_env;
+                    handler.clone()
+                }
+                (res, _) => return res,
+            },
+            Node::Fn(params, body) => {
+                return MalType::MalFunc {
+                    params: Box::new(params.clone()),
+                    body: Box::new(MalType::Analyzed(body.clone())),
+                    env: Some(env.clone()),
+                    eval,
+                    is_macro: false,
+                    pos: None,
+                };
+            }
+            Node::Eval(form) => {
+                let form = run(form.clone(), env.clone());
+                if form.is_error() {
+                    return form;
+                }
+
+                env = eval_env(&env);
+                analyze(&form, &env, &None)
+            }
+            Node::Quasiquote(expanded, made) => {
+                return freshen(run(expanded.clone(), env), made);
+            }
+            Node::Macroexpand(form) => return macroexpand(form.clone(), &env),
+            Node::Call(func, args) => {
+                let func = run(func.clone(), env.clone());
+                if func.is_error() {
+                    return func;
+                }
+
+                let args = match run_all(args, &env) {
+                    Ok(args) => args,
+                    Err(err) => return err,
+                };
+
+                match &func {
+                    MalType::Func(func) => return call_builtin(*func, args),
+                    MalType::MalFunc {
+                        params,
+                        body,
+                        env: func_env,
+                        ..
+                    } if let MalType::Analyzed(body) = body.deref() => {
+                        // tco
+                        env = env_bind(func_env.clone(), params.deref().clone(), args);
+                        body.clone()
+                    }
+                    MalType::MalFunc { .. }
+                    | MalType::Compiled(_)
+                    | MalType::Native(_)
+                    | MalType::WithMeta(..) => return func.apply(args),
+                    _ => return MalType::Nil,
+                }
+            }
+            Node::Lazy(form, scope, analyzed) => {
+                let cached = analyzed.borrow().clone();
+                match cached {
+                    Some(analyzed) => analyzed,
+                    None => {
+                        let node = analyze(form, &env, scope);
+                        *analyzed.borrow_mut() = Some(node.clone());
+                        node
+                    }
+                }
+            }
+        };
+
+        node = next;
+    }
+}
+
+/// Runs every node in order, stopping at the first one that raises an error.
+fn run_all(nodes: &[Rc<Node>], env: &Env) -> Result<Vec<MalType>, MalType> {
+    nodes
+        .iter()
+        .map(|node| match run(node.clone(), env.clone()) {
+            err @ MalType::Error(_) => Err(err),
+            value => Ok(value),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{interpreter::Interpreter, printer::print_string, reader::read_str};
+
+    fn rep(interpreter: &Interpreter, source: &str) -> String {
+        print_string(&interpreter.eval(read_str(source)), true)
+    }
+
+    /// The body of the `let*` `source` analyzes to.
+    fn let_body(source: &str) -> Rc<Node> {
+        let interpreter = Interpreter::new(eval, Vec::new());
+        match analyze(&read_str(source), &interpreter.env, &None).deref() {
+            Node::Let(_, body) => body.clone(),
+            other => panic!("not a let*: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn resolves_locals_to_where_they_are_bound() {
+        let body = let_body("(let* [a 1 b 2] b)");
+        assert_eq!(*body, Node::Local(0, 1, "b".to_owned()));
+
+        let Node::Let(_, inner) = let_body("(let* [a 1] (let* [b 2] a))").deref().clone() else {
+            panic!("not a let*");
+        };
+        assert_eq!(*inner, Node::Local(1, 0, "a".to_owned()));
+
+        assert_eq!(*let_body("(let* [a 1] b)"), Node::Symbol("b".to_owned()));
+    }
+
+    #[test]
+    fn a_local_shadows_a_macro() {
+        let body = let_body("(let* [cond (fn* [x] x)] (cond 1))");
+        assert!(matches!(body.deref(), Node::Call(..)));
+    }
+
+    #[test]
+    fn looks_a_local_up_by_name_when_it_is_not_at_its_index() {
+        let interpreter = Interpreter::new(eval, Vec::new());
+        rep(&interpreter, "(def! y 5)");
+        assert_eq!(rep(&interpreter, "((fn* [x y] y) 1)"), "5");
+        assert_eq!(
+            rep(&interpreter, "(let* [a 1] (do (def! b (+ a 1)) b))"),
+            "2"
+        );
+    }
+
+    #[test]
+    fn makes_fresh_auto_gensyms_each_run() {
+        let interpreter = Interpreter::new(eval, Vec::new());
+        rep(&interpreter, "(def! f (fn* [] `[x# x#]))");
+        let same = "(let* [v (f)] (= (nth v 0) (nth v 1)))";
+        assert_eq!(rep(&interpreter, same), "true");
+        assert_eq!(rep(&interpreter, "(= (f) (f))"), "false");
+    }
+}
@@ -0,0 +1,468 @@
+//! Bytecode compiler for the `vm` backend.
+//!
+//! Every `fn*` is compiled into its own `Proto`: a flat list of `Op`s, the
+//! constants they refer to and the protos of the functions nested in it.
+//! Parameters and `let*` bindings live in numbered local slots. A nested
+//! function reaches the locals of the functions around it through upvalues;
+//! the slots it captures are marked as boxed so that the enclosing frame keeps
+//! them in a shared cell instead of inline.
+//!
+//! Symbols that are not bound lexically are looked up in the global `Env` at
+//! runtime, and macros are expanded against that same `Env` at compile time.
+
+use std::rc::Rc;
+
+use crate::{env::*, fn_body, is_macro_call, macroexpand, quasiquote, types::*};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Const(usize),
+    Global(usize),
+    Local(usize),
+    Upvalue(usize),
+    Declare(usize),
+    SetLocal(usize),
+    Def(usize),
+    DefMacro(usize),
+    Pop,
+    Jump(usize),
+    JumpIfFalse(usize),
+    Closure(usize),
+    Call(usize),
+    TailCall(usize),
+    Return,
+    Vector(usize),
+    Dictionary(usize),
+    Set(usize),
+    Eval,
+    /// Makes fresh symbols for the ones the expansion of a quasiquote made,
+    /// in the value on top of the stack.
+    Freshen(usize),
+    Macroexpand(usize),
+    Try(usize),
+    EndTry,
+}
+
+/// Where a closure takes each of its upvalues from when it is created: a local
+/// slot of the enclosing frame, or one of the enclosing closure's upvalues.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capture {
+    Local(usize),
+    Upvalue(usize),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Proto {
+    pub arity: usize,
+    pub variadic: bool,
+    pub slots: usize,
+    pub boxed: Vec<bool>,
+    pub captures: Vec<Capture>,
+    pub code: Vec<Op>,
+    pub constants: Vec<MalType>,
+    pub protos: Vec<Rc<Proto>>,
+    pub globals: Env,
+    /// The parameters and body of the `fn*` form it was compiled from, and
+    /// the names of its upvalues, for sending the function to another thread.
+    /// None for a top-level form.
+    pub source: Option<(MalType, MalType)>,
+    pub upvalue_names: Vec<String>,
+}
+
+struct Local {
+    name: String,
+    slot: usize,
+    bound: bool,
+}
+
+#[derive(Default)]
+struct FnState {
+    arity: usize,
+    variadic: bool,
+    scope: Vec<Local>,
+    slots: usize,
+    boxed: Vec<bool>,
+    upvalues: Vec<(String, Capture)>,
+    code: Vec<Op>,
+    constants: Vec<MalType>,
+    protos: Vec<Rc<Proto>>,
+}
+
+impl FnState {
+    /// Looks a name up in the function's own scope. `let*` names that are not
+    /// bound yet are only visible from nested functions, which run later.
+    fn local(&self, name: &str, include_unbound: bool) -> Option<usize> {
+        self.scope
+            .iter()
+            .rev()
+            .find(|l| l.name.eq(name) && (l.bound || include_unbound))
+            .map(|l| l.slot)
+    }
+
+    fn declare(&mut self, name: &str, bound: bool) -> usize {
+        let slot = self.slots;
+        self.slots += 1;
+        self.boxed.push(false);
+        self.scope.push(Local {
+            name: name.to_owned(),
+            slot,
+            bound,
+        });
+        slot
+    }
+
+    fn constant(&mut self, value: MalType) -> usize {
+        self.constants.push(value);
+        self.constants.len() - 1
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        self.code.push(op);
+        self.code.len() - 1
+    }
+
+    fn finish(self, globals: &Env) -> Proto {
+        Proto {
+            arity: self.arity,
+            variadic: self.variadic,
+            slots: self.slots,
+            boxed: self.boxed,
+            captures: self.upvalues.iter().map(|(_, c)| *c).collect(),
+            code: self.code,
+            constants: self.constants,
+            protos: self.protos,
+            globals: globals.clone(),
+            source: None,
+            upvalue_names: self.upvalues.into_iter().map(|(name, _)| name).collect(),
+        }
+    }
+}
+
+struct Compiler {
+    globals: Env,
+    fns: Vec<FnState>,
+}
+
+/// Compiles a top-level form into a proto taking no arguments.
+pub fn compile(ast: &MalType, globals: &Env) -> Rc<Proto> {
+    let mut compiler = Compiler {
+        globals: globals.clone(),
+        fns: vec![FnState::default()],
+    };
+
+    compiler.expr(ast, true);
+    compiler.emit(Op::Return);
+
+    let state = compiler.fns.pop().unwrap();
+    Rc::new(state.finish(globals))
+}
+
+impl Compiler {
+    fn current(&mut self) -> &mut FnState {
+        self.fns.last_mut().unwrap()
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        self.current().emit(op)
+    }
+
+    fn constant(&mut self, value: MalType) {
+        let index = self.current().constant(value);
+        self.emit(Op::Const(index));
+    }
+
+    fn patch(&mut self, at: usize) {
+        let target = self.current().code.len();
+        match &mut self.current().code[at] {
+            Op::Jump(t) | Op::JumpIfFalse(t) | Op::Try(t) => *t = target,
+            op => panic!("cannot patch {op:?}"),
+        }
+    }
+
+    fn is_lexical(&self, name: &str) -> bool {
+        self.fns.iter().any(|f| f.local(name, true).is_some())
+    }
+
+    fn upvalue(&mut self, level: usize, name: &str) -> Option<usize> {
+        if let Some(index) = self.fns[level]
+            .upvalues
+            .iter()
+            .position(|(n, _)| n.eq(name))
+        {
+            return Some(index);
+        }
+
+        if level == 0 {
+            return None;
+        }
+
+        let capture = if let Some(slot) = self.fns[level - 1].local(name, true) {
+            self.fns[level - 1].boxed[slot] = true;
+            Capture::Local(slot)
+        } else {
+            Capture::Upvalue(self.upvalue(level - 1, name)?)
+        };
+
+        self.fns[level].upvalues.push((name.to_owned(), capture));
+        Some(self.fns[level].upvalues.len() - 1)
+    }
+
+    fn symbol(&mut self, name: &str) {
+        let level = self.fns.len() - 1;
+
+        if let Some(slot) = self.current().local(name, false) {
+            self.emit(Op::Local(slot));
+        } else if let Some(index) = self.upvalue(level, name) {
+            self.emit(Op::Upvalue(index));
+        } else {
+            let index = self.current().constant(MalType::Symbol(name.to_owned()));
+            self.emit(Op::Global(index));
+        }
+    }
+
+    fn expr(&mut self, ast: &MalType, tail: bool) {
+        match ast {
+            MalType::Symbol(s) => self.symbol(s),
+            MalType::Vector(items) => {
+                for item in items {
+                    self.expr(item, false);
+                }
+                self.emit(Op::Vector(items.len()));
+            }
+            MalType::Dictionary(items) => {
+                for item in items {
+                    self.expr(item, false);
+                }
+                self.emit(Op::Dictionary(items.len()));
+            }
+            MalType::Set(items) => {
+                for item in items {
+                    self.expr(item, false);
+                }
+                self.emit(Op::Set(items.len()));
+            }
+            MalType::List(list) if list.is_empty() => self.constant(ast.clone()),
+            MalType::List(list) => self.list(ast, list, tail),
+            _ => self.constant(ast.clone()),
+        }
+    }
+
+    fn list(&mut self, ast: &MalType, list: &[MalType], tail: bool) {
+        match &list[0] {
+            MalType::Symbol(s) if s.eq("quote") => self.constant(list[1].clone()),
+            MalType::Symbol(s) if s.eq("quasiquoteexpand") => self.constant(quasiquote(&list[1]).0),
+            MalType::Symbol(s) if s.eq("quasiquote") => match quasiquote(&list[1]) {
+                (expanded, made) if made.is_empty() => self.expr(&expanded, tail),
+                (expanded, made) => {
+                    self.expr(&expanded, false);
+                    let index = self.current().constant(MalType::List(made));
+                    self.emit(Op::Freshen(index));
+                }
+            },
+            MalType::Symbol(s) if s.eq("macroexpand") => {
+                let index = self.current().constant(list[1].clone());
+                self.emit(Op::Macroexpand(index));
+            }
+            MalType::Symbol(s) if s.eq("eval") => {
+                self.expr(&list[1], false);
+                self.emit(Op::Eval);
+            }
+            MalType::Symbol(s) if s.eq("def!") => self.def(&list[1], &list[2]),
+            MalType::Symbol(s) if s.eq("defmacro!") => {
+                self.expr(&list[2], false);
+                let index = self.current().constant(list[1].clone());
+                self.emit(Op::DefMacro(index));
+            }
+            MalType::Symbol(s) if s.eq("let*") => self.let_star(&list[1], &list[2], tail),
+            MalType::Symbol(s) if s.eq("do") => {
+                let Some((last, init)) = list[1..].split_last() else {
+                    self.constant(MalType::Nil);
+                    return;
+                };
+
+                for item in init {
+                    self.expr(item, false);
+                    self.emit(Op::Pop);
+                }
+                self.expr(last, tail);
+            }
+            MalType::Symbol(s) if s.eq("if") => {
+                self.expr(&list[1], false);
+                let otherwise = self.emit(Op::JumpIfFalse(0));
+                self.expr(&list[2], tail);
+                let end = self.emit(Op::Jump(0));
+                self.patch(otherwise);
+                match list.get(3) {
+                    Some(e) => self.expr(e, tail),
+                    None => self.constant(MalType::Nil),
+                }
+                self.patch(end);
+            }
+            MalType::Symbol(s) if s.eq("try*") => self.try_star(&list[1], list.get(2), tail),
+            MalType::Symbol(s) if s.eq("fn*") => self.function(&list[1], &list[fn_body(list)]),
+            MalType::Symbol(s) if !self.is_lexical(s) && is_macro_call(ast, &self.globals) => {
+                let expanded = macroexpand(ast.clone(), &self.globals);
+                self.expr(&expanded, tail);
+            }
+            _ => {
+                for item in list {
+                    self.expr(item, false);
+                }
+
+                if tail {
+                    self.emit(Op::TailCall(list.len() - 1));
+                } else {
+                    self.emit(Op::Call(list.len() - 1));
+                }
+            }
+        }
+    }
+
+    fn let_star(&mut self, bindings: &MalType, body: &MalType, tail: bool) {
+        let bindings = match bindings {
+            MalType::List(l) | MalType::Vector(l) => l,
+            _ => {
+                println!("ERROR: first element `{bindings}` in let* binding is not a List/Vector");
+                self.constant(MalType::Nil);
+                return;
+            }
+        };
+
+        // every name is declared up front so that functions defined in the
+        // bindings can refer to each other, like they do with an `Env`
+        let scope_len = self.current().scope.len();
+        let mut slots = Vec::new();
+        for pair in bindings.chunks_exact(2) {
+            let MalType::Symbol(name) = &pair[0] else {
+                println!("env_get called with a non-Symbol {}", pair[0]);
+                continue;
+            };
+
+            let slot = match self.current().scope[scope_len..]
+                .iter()
+                .find(|l| l.name.eq(name))
+            {
+                Some(local) => local.slot,
+                None => {
+                    let slot = self.current().declare(name, false);
+                    self.emit(Op::Declare(slot));
+                    slot
+                }
+            };
+            slots.push((slot, &pair[1]));
+        }
+
+        for (slot, value) in slots {
+            self.expr(value, false);
+            self.emit(Op::SetLocal(slot));
+            if let Some(local) = self.current().scope.iter_mut().find(|l| l.slot == slot) {
+                local.bound = true;
+            }
+        }
+
+        self.expr(body, tail);
+        self.current().scope.truncate(scope_len);
+    }
+
+    /// A `def!` at the top level binds a global. Inside a function or a local
+    /// scope it binds in the innermost `Env` under the other evaluators, so it
+    /// gets a local slot here, visible until the end of that scope.
+    fn def(&mut self, name: &MalType, value: &MalType) {
+        let top_level = self.fns.len() == 1 && self.current().scope.is_empty();
+        let local = match name {
+            MalType::Symbol(local) if !top_level => local,
+            _ => {
+                self.expr(value, false);
+                let index = self.current().constant(name.clone());
+                self.emit(Op::Def(index));
+                return;
+            }
+        };
+
+        // declared before the value is compiled, like a `let*` name, so that
+        // a function defined by it can call itself
+        let slot = self.current().declare(local, false);
+        self.emit(Op::Declare(slot));
+        self.expr(value, false);
+        self.emit(Op::SetLocal(slot));
+        if let Some(local) = self.current().scope.iter_mut().find(|l| l.slot == slot) {
+            local.bound = true;
+        }
+        self.emit(Op::Local(slot));
+    }
+
+    fn try_star(&mut self, body: &MalType, catch: Option<&MalType>, tail: bool) {
+        let catch = match catch {
+            Some(MalType::List(catch))
+                if catch.first() == Some(&MalType::Symbol("catch*".to_owned())) =>
+            {
+                catch
+            }
+            _ => {
+                self.expr(body, tail);
+                return;
+            }
+        };
+
+        // the body is never in tail position, its frame has to stay around
+        // for the handler to run in
+        let handler = self.emit(Op::Try(0));
+        self.expr(body, false);
+        self.emit(Op::EndTry);
+        let end = self.emit(Op::Jump(0));
+        self.patch(handler);
+
+        // the VM jumps here with the thrown value on the stack
+        let scope_len = self.current().scope.len();
+        let MalType::Symbol(name) = &catch[1] else {
+            println!("env_get called with a non-Symbol {}", catch[1]);
+            self.emit(Op::Pop);
+            self.constant(MalType::Nil);
+            self.patch(end);
+            return;
+        };
+        let slot = self.current().declare(name, true);
+        self.emit(Op::Declare(slot));
+        self.emit(Op::SetLocal(slot));
+        self.expr(&catch[2], tail);
+        self.current().scope.truncate(scope_len);
+        self.patch(end);
+    }
+
+    fn function(&mut self, params: &MalType, body: &MalType) {
+        let source = Some((params.clone(), body.clone()));
+        let (MalType::List(params) | MalType::Vector(params)) = params else {
+            println!("env_bind binds is not a List/Vector");
+            self.constant(MalType::Nil);
+            return;
+        };
+
+        let mut state = FnState::default();
+        let mut params = params.iter();
+        while let Some(MalType::Symbol(name)) = params.next() {
+            if name.eq("&") {
+                if let Some(MalType::Symbol(rest)) = params.next() {
+                    state.variadic = true;
+                    state.declare(rest, true);
+                }
+                break;
+            }
+
+            state.arity += 1;
+            state.declare(name, true);
+        }
+
+        self.fns.push(state);
+        self.expr(body, true);
+        self.emit(Op::Return);
+
+        let state = self.fns.pop().unwrap();
+        let proto = Rc::new(Proto {
+            source,
+            ..state.finish(&self.globals)
+        });
+        self.current().protos.push(proto);
+        let index = self.current().protos.len() - 1;
+        self.emit(Op::Closure(index));
+    }
+}
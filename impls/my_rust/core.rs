@@ -1,52 +1,208 @@
 #![allow(clippy::not_unsafe_ptr_arg_deref)]
 //! TODO(mhs): Handle exceptions like divide by zero
 
-use std::{cell::RefCell, ops::Deref, rc::Rc};
+use std::{cmp::Ordering, ops::Deref, rc::Rc, time::Duration};
+
+use indexmap::IndexSet;
+use unicode_segmentation::UnicodeSegmentation;
 
 use super::*;
 
-type FuncTuple = (
+/// The fewest and most arguments a builtin takes, for `mal-lint`. Some of
+/// them ignore the arguments past the most rather than failing.
+pub type Arity = (usize, Option<usize>);
+
+pub type FuncTuple = (
     &'static str,
     fn(std::vec::Vec<types::MalType>) -> types::MalType,
+    Arity,
 );
 
-const NS: [FuncTuple; 30] = [
-    ("+", core::add),
-    ("-", core::sub),
-    ("*", core::mul),
-    ("/", core::div),
-    ("prn", core::prn),
-    ("pr-str", core::pr_str),
-    ("str", core::str),
-    ("println", core::println),
-    ("read-string", core::read_string),
-    ("slurp", core::slurp),
-    ("list", core::list),
-    ("list?", core::is_list),
-    ("empty?", core::is_empty),
-    ("count", core::count),
-    ("=", core::eq),
-    ("<", core::lt),
-    ("<=", core::lteq),
-    (">", core::gt),
-    (">=", core::gteq),
-    ("atom", core::atom),
-    ("atom?", core::is_atom),
-    ("deref", core::deref),
-    ("reset!", core::reset),
-    ("swap!", core::swap),
-    ("cons", core::cons),
-    ("concat", core::concat),
-    ("vec", core::vec),
-    ("nth", core::nth),
-    ("first", core::first),
-    ("rest", core::rest),
+const NS: [FuncTuple; 118] = [
+    ("+", core::add, (0, None)),
+    ("-", core::sub, (0, None)),
+    ("*", core::mul, (0, None)),
+    ("/", core::div, (0, None)),
+    ("prn", core::prn, (0, None)),
+    ("pr-str", core::pr_str, (0, None)),
+    ("str", core::str, (0, None)),
+    ("println", core::println, (0, None)),
+    ("read-string", core::read_string, (1, Some(1))),
+    ("slurp", core::slurp, (1, Some(1))),
+    ("read-file", core::read_file, (1, Some(1))),
+    ("list", core::list, (0, None)),
+    ("list?", core::is_list, (1, Some(1))),
+    ("empty?", core::is_empty, (1, Some(1))),
+    ("count", core::count, (1, Some(1))),
+    ("=", core::eq, (2, None)),
+    ("<", core::lt, (2, None)),
+    ("<=", core::lteq, (2, None)),
+    (">", core::gt, (2, None)),
+    (">=", core::gteq, (2, None)),
+    ("atom", core::atom, (1, Some(1))),
+    ("atom?", core::is_atom, (1, Some(1))),
+    ("deref", core::deref, (1, Some(1))),
+    ("reset!", core::reset, (2, Some(2))),
+    ("swap!", core::swap, (2, None)),
+    ("cons", core::cons, (2, Some(2))),
+    ("concat", core::concat, (0, None)),
+    ("vec", core::vec, (1, Some(1))),
+    ("nth", core::nth, (2, Some(2))),
+    ("first", core::first, (1, Some(1))),
+    ("rest", core::rest, (1, Some(1))),
+    ("time-ms", core::time_ms, (0, Some(0))),
+    ("gc", core::gc, (0, Some(0))),
+    ("gc-stats", core::gc_stats, (0, Some(0))),
+    ("future*", core::future, (1, Some(1))),
+    ("future-done?", core::is_future_done, (1, Some(1))),
+    ("spawn-isolate", core::spawn_isolate, (1, Some(1))),
+    ("send", core::send, (2, Some(2))),
+    ("receive", core::receive, (1, Some(1))),
+    ("isolate-done?", core::is_isolate_done, (1, Some(1))),
+    ("throw", core::throw, (1, Some(1))),
+    ("gensym", core::gensym, (0, Some(1))),
+    ("ex-stack", stack::ex_stack, (1, Some(1))),
+    ("trace*", profile::trace, (2, Some(2))),
+    ("untrace*", profile::untrace, (2, Some(2))),
+    ("with-timeout", core::with_timeout, (2, Some(2))),
+    ("ns*", core::ns, (1, Some(1))),
+    ("require", core::require, (1, None)),
+    ("map", core::map, (1, None)),
+    ("filter", core::filter, (1, Some(2))),
+    ("reduce", core::reduce, (2, Some(3))),
+    ("apply", core::apply, (1, None)),
+    ("range", core::range, (0, Some(3))),
+    ("take", core::take, (1, Some(2))),
+    ("drop", core::drop, (2, Some(2))),
+    ("partition", core::partition, (2, Some(3))),
+    ("group-by", core::group_by, (2, Some(2))),
+    ("sort", core::sort, (1, Some(2))),
+    ("lazy-seq*", core::lazy_seq, (1, Some(1))),
+    ("seq", core::seq, (1, Some(1))),
+    ("iterate", core::iterate, (2, Some(2))),
+    ("repeat", core::repeat, (1, Some(2))),
+    ("reduced", core::reduced, (1, Some(1))),
+    ("reduced?", core::is_reduced, (1, Some(1))),
+    ("unreduced", core::unreduced, (1, Some(1))),
+    ("comp", core::comp, (0, None)),
+    ("transduce", core::transduce, (3, Some(4))),
+    ("into", core::into, (2, Some(3))),
+    ("sequence", core::sequence, (1, Some(2))),
+    ("hash-set", core::hash_set, (0, None)),
+    ("set", core::set, (1, Some(1))),
+    ("conj", core::conj, (0, None)),
+    ("disj", core::disj, (1, None)),
+    ("contains?", core::contains, (2, Some(2))),
+    ("nil?", core::is_nil, (1, Some(1))),
+    ("true?", core::is_true, (1, Some(1))),
+    ("false?", core::is_false, (1, Some(1))),
+    ("symbol?", core::is_symbol, (1, Some(1))),
+    ("keyword?", core::is_keyword, (1, Some(1))),
+    ("string?", core::is_string, (1, Some(1))),
+    ("number?", core::is_number, (1, Some(1))),
+    ("fn?", core::is_fn, (1, Some(1))),
+    ("macro?", core::is_macro, (1, Some(1))),
+    ("vector?", core::is_vector, (1, Some(1))),
+    ("sequential?", core::is_sequential, (1, Some(1))),
+    ("map?", core::is_map, (1, Some(1))),
+    ("symbol", core::symbol, (1, Some(1))),
+    ("keyword", core::keyword, (1, Some(1))),
+    ("vector", core::vector, (0, None)),
+    ("hash-map", core::hash_map, (0, None)),
+    ("assoc", core::assoc, (1, None)),
+    ("dissoc", core::dissoc, (1, None)),
+    ("get", core::get, (2, Some(3))),
+    ("keys", core::keys, (1, Some(1))),
+    ("vals", core::vals, (1, Some(1))),
+    ("meta", core::meta, (1, Some(1))),
+    ("with-meta", core::with_meta, (2, Some(2))),
+    ("char?", core::is_char, (1, Some(1))),
+    ("char", core::char, (1, Some(1))),
+    ("int", core::int, (1, Some(1))),
+    ("subs", core::subs, (2, Some(3))),
+    ("char-at", core::char_at, (2, Some(2))),
+    ("split", core::split, (2, Some(2))),
+    ("join", core::join, (1, Some(2))),
+    ("upper-case", core::upper_case, (1, Some(1))),
+    ("lower-case", core::lower_case, (1, Some(1))),
+    ("trim", core::trim, (1, Some(1))),
+    ("index-of", core::index_of, (2, Some(3))),
+    ("replace", core::replace, (3, Some(3))),
+    ("regex?", core::is_regex, (1, Some(1))),
+    ("re-pattern", core::re_pattern, (1, Some(1))),
+    ("re-find", core::re_find, (2, Some(2))),
+    ("re-matches", core::re_matches, (2, Some(2))),
+    ("re-seq", core::re_seq, (2, Some(2))),
+    ("re-split", core::re_split, (2, Some(2))),
+    ("re-replace", core::re_replace, (3, Some(3))),
+    ("pprint", core::pprint, (1, Some(2))),
+    ("pprint-str", core::pprint_str, (1, Some(2))),
+];
+
+/// The builtins of the `set` namespace, like `set/union`.
+pub const SET_NS: [FuncTuple; 4] = [
+    ("union", core::set_union, (0, None)),
+    ("intersection", core::set_intersection, (1, None)),
+    ("difference", core::set_difference, (1, None)),
+    ("subset?", core::is_subset, (2, Some(2))),
+];
+
+/// The `pprint` namespace, standing in for the `impls/lib/pprint.mal` library.
+pub const PPRINT_NS: [FuncTuple; 2] = [
+    ("pprint", core::pprint, (1, Some(2))),
+    ("pprint-str", core::pprint_str, (1, Some(2))),
+];
+
+/// The arity of the builtin `name`, for `mal-lint`.
+pub fn arity(name: &str) -> Option<Arity> {
+    NS.iter()
+        .find(|(symbol, _, _)| *symbol == name)
+        .map(|(_, _, arity)| *arity)
+}
+
+/// The name a builtin is defined by, like `set/union` for the builtins of a
+/// namespace.
+pub fn builtin_name(func: fn(Vec<MalType>) -> MalType) -> Option<String> {
+    let find = |prefix: &str, ns: &[FuncTuple]| {
+        ns.iter()
+            .find(|(_, f, _)| std::ptr::fn_addr_eq(*f, func))
+            .map(|(name, _, _)| format!("{prefix}{name}"))
+    };
+    find("", &NS)
+        .or_else(|| find("set/", &SET_NS))
+        .or_else(|| find("pprint/", &PPRINT_NS))
+}
+
+/// Whether `func` is passed values carrying metadata as they are, rather than
+/// the values they carry (see `call_builtin`).
+pub fn reads_meta(func: fn(Vec<MalType>) -> MalType) -> bool {
+    std::ptr::fn_addr_eq(func, meta as fn(Vec<MalType>) -> MalType)
+}
+
+/// Builtins only installed when their capability is granted.
+const CAPABILITIES: [(&str, Capability); 4] = [
+    ("slurp", Capability::Io),
+    ("read-file", Capability::Io),
+    ("future*", Capability::Threads),
+    ("spawn-isolate", Capability::Threads),
 ];
 
 pub fn core_env() -> Env {
+    core_env_with(&ALL_CAPABILITIES)
+}
+
+pub fn core_env_with(capabilities: &[Capability]) -> Env {
     let env = env_new(None);
 
-    for (symbol, func) in NS {
+    for (symbol, func, _) in NS {
+        let allowed = CAPABILITIES
+            .iter()
+            .find(|(s, _)| s.eq(&symbol))
+            .is_none_or(|(_, c)| capabilities.contains(c));
+        if !allowed {
+            continue;
+        }
+
         env_set(
             &env,
             &MalType::Symbol(symbol.to_owned()),
@@ -107,7 +263,7 @@ fn sub(args: Vec<MalType>) -> MalType {
 
 fn mul(args: Vec<MalType>) -> MalType {
     if args.is_empty() {
-        return MalType::Nil;
+        return MalType::Number(1);
     }
 
     let mut res = {
@@ -159,7 +315,14 @@ fn pr_str(args: Vec<MalType>) -> MalType {
 }
 
 fn str(args: Vec<MalType>) -> MalType {
-    let s = print_seq(&args, false, "", "", "");
+    let mut s = String::new();
+    for arg in &args {
+        let piece = print_string(arg, false);
+        if let Err(err) = interpreter::check_size(s.len() + piece.len()) {
+            return err;
+        }
+        s.push_str(&piece);
+    }
     MalType::String(s)
 }
 
@@ -203,6 +366,26 @@ fn slurp(args: Vec<MalType>) -> MalType {
     }
 }
 
+/// `(read-file f)` reads the forms of the file `f` as one `(do forms... nil)`
+/// form for `load-file` to evaluate, telling the evaluator where each of them
+/// was read.
+fn read_file(args: Vec<MalType>) -> MalType {
+    match args.as_slice() {
+        [MalType::String(path)] => match std::fs::read_to_string(path) {
+            Ok(text) => match reader::read_file(&text, path) {
+                Ok((form, pos)) => {
+                    reader::locate(&form, pos);
+                    form
+                }
+                Err(err) => err,
+            },
+            Err(err) => string_error(format!("can't read {path}: {err}")),
+        },
+        [other] => type_error("a string", other),
+        _ => arity_error("1", args.len()),
+    }
+}
+
 fn list(args: Vec<MalType>) -> MalType {
     MalType::List(args)
 }
@@ -232,6 +415,12 @@ fn is_empty(args: Vec<MalType>) -> MalType {
                 MalType::False
             }
         }
+        MalType::Set(ref s) => MalType::boolean(s.is_empty()),
+        ref arg if seq::is_seqable(arg) => match seq::step(arg) {
+            Ok(seq::Step::Empty) => MalType::True,
+            Ok(seq::Step::Cons(..)) => MalType::False,
+            Err(err) => err,
+        },
         _ => MalType::False,
     }
 }
@@ -243,6 +432,11 @@ fn count(args: Vec<MalType>) -> MalType {
 
     match args[0] {
         MalType::List(ref c) | MalType::Vector(ref c) => MalType::Number(c.len() as i64),
+        MalType::Set(ref s) => MalType::Number(s.len() as i64),
+        ref arg if seq::is_seqable(arg) => match seq::to_vec(arg) {
+            Ok(items) => MalType::Number(items.len() as i64),
+            Err(err) => err,
+        },
         _ => MalType::Number(0),
     }
 }
@@ -256,8 +450,7 @@ fn eq(args: Vec<MalType>) -> MalType {
         (MalType::List(c0), MalType::List(c1))
         | (MalType::Vector(c0), MalType::Vector(c1))
         | (MalType::List(c0), MalType::Vector(c1))
-        | (MalType::Vector(c0), MalType::List(c1))
-        | (MalType::Dictionary(c0), MalType::Dictionary(c1)) => {
+        | (MalType::Vector(c0), MalType::List(c1)) => {
             if c0.len() != c1.len() {
                 return MalType::False;
             }
@@ -270,6 +463,19 @@ fn eq(args: Vec<MalType>) -> MalType {
 
             MalType::True
         }
+        (MalType::Dictionary(c0), MalType::Dictionary(c1)) => {
+            // whatever the order of the entries
+            let same = |entry: &[MalType]| {
+                c1.chunks_exact(2).any(|other| {
+                    entry[0] == other[0]
+                        && matches!(eq([entry[1].clone(), other[1].clone()].to_vec()), MalType::True)
+                })
+            };
+            MalType::boolean(c0.len() == c1.len() && c0.chunks_exact(2).all(same))
+        }
+        (MalType::Set(s0), MalType::Set(s1)) => MalType::boolean(s0 == s1),
+        (MalType::Char(c0), MalType::Char(c1)) => MalType::boolean(c0 == c1),
+        (MalType::Regex(r0), MalType::Regex(r1)) => MalType::boolean(r0 == r1),
         (MalType::Number(i0), MalType::Number(i1)) => {
             if i0 == i1 {
                 MalType::True
@@ -291,6 +497,17 @@ fn eq(args: Vec<MalType>) -> MalType {
                 MalType::False
             }
         }
+        (MalType::LazySeq(_), other) | (other, MalType::LazySeq(_))
+            if matches!(
+                other,
+                MalType::List(_) | MalType::Vector(_) | MalType::LazySeq(_)
+            ) =>
+        {
+            match (seq::to_vec(&args[0]), seq::to_vec(&args[1])) {
+                (Ok(c0), Ok(c1)) => eq([MalType::List(c0), MalType::List(c1)].to_vec()),
+                (Err(err), _) | (_, Err(err)) => err,
+            }
+        }
         (MalType::True, MalType::True) => MalType::True,
         (MalType::False, MalType::False) => MalType::True,
         (MalType::Nil, MalType::Nil) => MalType::True,
@@ -364,23 +581,15 @@ fn gt(args: Vec<MalType>) -> MalType {
 
 fn atom(args: Vec<MalType>) -> MalType {
     if args.len() != 1 {
-        println!(
-            "Error: wrong number of arguments provided. Expected 1, got {}",
-            args.len()
-        );
-        return MalType::Nil;
+        return arity_error("1", args.len());
     }
 
-    MalType::Atom(Rc::new(RefCell::new(args[0].clone())))
+    MalType::Atom(gc::atom(args[0].clone()))
 }
 
 fn is_atom(args: Vec<MalType>) -> MalType {
     if args.len() != 1 {
-        println!(
-            "Error: wrong number of arguments provided. Expected 1, got {}",
-            args.len()
-        );
-        return MalType::Nil;
+        return arity_error("1", args.len());
     }
 
     MalType::boolean(matches!(args[0], MalType::Atom(_)))
@@ -388,32 +597,19 @@ fn is_atom(args: Vec<MalType>) -> MalType {
 
 fn deref(args: Vec<MalType>) -> MalType {
     if args.len() != 1 {
-        println!(
-            "Error: wrong number of arguments provided. Expected 1, got {}",
-            args.len()
-        );
-        return MalType::Nil;
+        return arity_error("1", args.len());
     }
 
     match &args[0] {
         MalType::Atom(a) => a.deref().borrow().clone(),
-        _ => {
-            println!(
-                "Error: wrong argument type provided. Expected an Atom, got {}",
-                MalType::discriminant_name(&args[0])
-            );
-            MalType::Nil
-        }
+        MalType::Future(f) => f.wait(),
+        _ => type_error("an Atom or a Future", &args[0]),
     }
 }
 
 fn reset(args: Vec<MalType>) -> MalType {
     if args.len() != 2 {
-        println!(
-            "Error: wrong number of arguments provided. Expected 2, got {}",
-            args.len()
-        );
-        return MalType::Nil;
+        return arity_error("2", args.len());
     }
 
     match &args[0] {
@@ -421,29 +617,21 @@ fn reset(args: Vec<MalType>) -> MalType {
             a.deref().replace(args[1].clone());
             args[1].clone()
         }
-        _ => {
-            println!(
-                "Error: wrong argument type provided. Expected an Atom, got {}",
-                MalType::discriminant_name(&args[0])
-            );
-            MalType::Nil
-        }
+        _ => type_error("an Atom", &args[0]),
     }
 }
 
 // mal is single threaded, but in multithreaded Clojure swap promises atomic changes
 fn swap(args: Vec<MalType>) -> MalType {
     if args.len() < 2 {
-        println!(
-            "Error: wrong number of arguments provided. Expected 2 or more, got {}",
-            args.len()
-        );
-        return MalType::Nil;
+        return arity_error("2 or more", args.len());
     }
 
     match (&args[0], &args[1]) {
         (MalType::Atom(a), f @ MalType::MalFunc { .. })
-        | (MalType::Atom(a), f @ MalType::Func(_)) => {
+        | (MalType::Atom(a), f @ MalType::Func(_))
+        | (MalType::Atom(a), f @ MalType::Compiled(_))
+        | (MalType::Atom(a), f @ MalType::Native(_)) => {
             let mut func_args = [deref([args[0].clone()].to_vec())].to_vec();
             args.iter()
                 .skip(2)
@@ -454,53 +642,67 @@ fn swap(args: Vec<MalType>) -> MalType {
 
             new_value
         }
-        _ => {
-            println!(
-                "Error: wrong argument type provided. Expected an Atom and a Function, got {} and {}",
-                MalType::discriminant_name(&args[0]),
-                MalType::discriminant_name(&args[1])
-            );
-            MalType::Nil
-        }
+        _ => string_error(format!(
+            "wrong argument type provided. Expected an Atom and a Function, got {} and {}",
+            MalType::discriminant_name(&args[0]),
+            MalType::discriminant_name(&args[1])
+        )),
     }
 }
 
 fn cons(args: Vec<MalType>) -> MalType {
     if args.len() < 2 {
-        println!(
-            "Error: wrong number of arguments provided. Expected 2 or more, got {}",
-            args.len()
-        );
-        return MalType::Nil;
+        return arity_error("2 or more", args.len());
     }
 
     match &args[1] {
         MalType::List(end) | MalType::Vector(end) => {
             MalType::List([[args[0].clone()].to_vec(), end.clone()].concat())
         }
-        _ => {
-            println!(
-                "Error: wrong argument type provided. Expected second argument to be List, got {}",
-                MalType::discriminant_name(&args[1])
-            );
-            MalType::Nil
-        }
+        MalType::Nil => MalType::List([args[0].clone()].to_vec()),
+        MalType::LazySeq(_) => seq::cons(args[0].clone(), args[1].clone()),
+        end if seq::is_seqable(end) => match seq::to_vec(end) {
+            Ok(end) => MalType::List([[args[0].clone()].to_vec(), end].concat()),
+            Err(err) => err,
+        },
+        _ => type_error("second argument to be a sequence", &args[1]),
     }
 }
 
 fn concat(args: Vec<MalType>) -> MalType {
+    if args.iter().any(|arg| matches!(arg, MalType::LazySeq(_))) {
+        if let Some(arg) = args.iter().find(|arg| !seq::is_seqable(arg)) {
+            return type_error("a sequence", arg);
+        }
+        return seq::concat(args);
+    }
+
+    let size = args
+        .iter()
+        .map(|arg| match arg {
+            MalType::List(items) | MalType::Vector(items) => items.len(),
+            _ => 0,
+        })
+        .sum();
+    if let Err(err) = interpreter::check_size(size) {
+        return err;
+    }
+
     let mut res = Vec::new();
 
     for (i, arg) in args.into_iter().enumerate() {
         match arg {
             MalType::List(end) | MalType::Vector(end) => res = [res, end].concat(),
+            ref end if seq::is_seqable(end) => match seq::to_vec(end) {
+                Ok(end) => res = [res, end].concat(),
+                Err(err) => return err,
+            },
             _ => {
-                println!(
-                    "Error: wrong argument type provided. Expected argument #{} to be List, got {}",
+                return string_error(format!(
+                    "wrong argument type provided. Expected argument #{} to be List, got {}",
                     i,
                     MalType::discriminant_name(&arg)
-                );
-                return MalType::Nil;
+                ));
             }
         };
     }
@@ -517,36 +719,39 @@ fn vec(args: Vec<MalType>) -> MalType {
 
     match &arg {
         MalType::List(v) | MalType::Vector(v) => MalType::Vector(v.to_owned()),
+        _ if seq::is_seqable(&arg) => match seq::to_vec(&arg) {
+            Ok(v) => MalType::Vector(v),
+            Err(err) => err,
+        },
         _ => MalType::Vector([arg].to_vec()),
     }
 }
 
 fn nth(args: Vec<MalType>) -> MalType {
     if args.len() != 2 {
-        println!(
-            "Error: wrong number of arguments provided. Expected 2 or more, got {}",
-            args.len()
-        );
-        return MalType::Nil;
+        return arity_error("2 or more", args.len());
     }
 
     match (&args[0], &args[1]) {
         (MalType::List(collection), MalType::Number(i))
         | (MalType::Vector(collection), MalType::Number(i)) => {
-            if *i as usize >= collection.len() {
-                MalType::Nil
-            } else {
-                collection[*i as usize].clone()
+            match usize::try_from(*i).ok().and_then(|i| collection.get(i)) {
+                Some(item) => item.clone(),
+                None => string_error(format!("nth: index {i} out of range")),
             }
         }
-        _ => {
-            println!(
-                "Error: wrong argument type provided. Expected a List/Vector and a Number, got {} and {}",
-                MalType::discriminant_name(&args[0]),
-                MalType::discriminant_name(&args[1])
-            );
-            MalType::Nil
+        (coll, MalType::Number(i)) if seq::is_seqable(coll) => {
+            match seq::drop((*i).max(0) as usize, coll).and_then(|rest| seq::step(&rest)) {
+                Ok(seq::Step::Cons(item, _)) if *i >= 0 => item,
+                Ok(_) => string_error(format!("nth: index {i} out of range")),
+                Err(err) => err,
+            }
         }
+        _ => string_error(format!(
+            "wrong argument type provided. Expected a sequence and a Number, got {} and {}",
+            MalType::discriminant_name(&args[0]),
+            MalType::discriminant_name(&args[1])
+        )),
     }
 }
 
@@ -565,13 +770,12 @@ fn first(args: Vec<MalType>) -> MalType {
                 v[0].clone()
             }
         }
-        _ => {
-            println!(
-                "Error: wrong argument type provided. Expected a List/Vector, got {}",
-                MalType::discriminant_name(&args[0]),
-            );
-            MalType::Nil
-        }
+        _ if seq::is_seqable(&arg) => match seq::step(&arg) {
+            Ok(seq::Step::Cons(first, _)) => first,
+            Ok(seq::Step::Empty) => MalType::Nil,
+            Err(err) => err,
+        },
+        _ => type_error("a sequence", &args[0]),
     }
 }
 
@@ -590,12 +794,1268 @@ fn rest(args: Vec<MalType>) -> MalType {
                 MalType::List(v.iter().skip(1).cloned().collect())
             }
         }
-        _ => {
-            println!(
-                "Error: wrong argument type provided. Expected a List/Vector, got {}",
-                MalType::discriminant_name(&args[0]),
+        MalType::LazySeq(_) => match seq::step(&arg) {
+            Ok(seq::Step::Cons(_, rest @ MalType::LazySeq(_))) => rest,
+            Ok(seq::Step::Cons(_, rest)) => {
+                seq::to_vec(&rest).map_or_else(|err| err, MalType::List)
+            }
+            Ok(seq::Step::Empty) => MalType::List(Vec::new()),
+            Err(err) => err,
+        },
+        _ if seq::is_seqable(&arg) => match seq::to_vec(&arg) {
+            Ok(v) => MalType::List(v.into_iter().skip(1).collect()),
+            Err(err) => err,
+        },
+        _ => type_error("a sequence", &args[0]),
+    }
+}
+
+fn time_ms(_args: Vec<MalType>) -> MalType {
+    let elapsed = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+
+    MalType::Number(elapsed.as_millis() as i64)
+}
+
+fn gc(_args: Vec<MalType>) -> MalType {
+    MalType::Number(gc::collect() as i64)
+}
+
+fn gc_stats(_args: Vec<MalType>) -> MalType {
+    gc::stats()
+}
+
+/// `(future* f)`, what the `future` macro expands to: calls `f`, a function
+/// taking no arguments, on another thread.
+fn future(args: Vec<MalType>) -> MalType {
+    match args.as_slice() {
+        [f @ (MalType::MalFunc { .. } | MalType::Compiled(_))] => match isolate::spawn(f) {
+            Ok(future) => MalType::Future(Rc::new(future)),
+            Err(err) => string_error(err),
+        },
+        [arg] => type_error("a function", arg),
+        _ => arity_error("1", args.len()),
+    }
+}
+
+fn is_future_done(args: Vec<MalType>) -> MalType {
+    if args.len() != 1 {
+        return arity_error("1", args.len());
+    }
+
+    match &args[0] {
+        MalType::Future(f) => MalType::boolean(f.is_done()),
+        _ => type_error("a Future", &args[0]),
+    }
+}
+
+fn spawn_isolate(args: Vec<MalType>) -> MalType {
+    if args.len() != 1 {
+        return arity_error("1", args.len());
+    }
+
+    match &args[0] {
+        MalType::String(path) => MalType::Isolate(Rc::new(isolate::spawn_isolate(path))),
+        _ => type_error("a String", &args[0]),
+    }
+}
+
+fn send(args: Vec<MalType>) -> MalType {
+    if args.len() != 2 {
+        return arity_error("2", args.len());
+    }
+
+    match &args[0] {
+        MalType::Isolate(i) => match i.send(&args[1]) {
+            Ok(()) => args[1].clone(),
+            Err(err) => string_error(err.to_string()),
+        },
+        _ => type_error("an Isolate", &args[0]),
+    }
+}
+
+fn receive(args: Vec<MalType>) -> MalType {
+    if args.len() != 1 {
+        return arity_error("1", args.len());
+    }
+
+    match &args[0] {
+        MalType::Isolate(i) => i.receive().unwrap_or(MalType::Nil),
+        _ => type_error("an Isolate", &args[0]),
+    }
+}
+
+fn is_isolate_done(args: Vec<MalType>) -> MalType {
+    if args.len() != 1 {
+        return arity_error("1", args.len());
+    }
+
+    match &args[0] {
+        MalType::Isolate(i) => MalType::boolean(i.is_done()),
+        _ => type_error("an Isolate", &args[0]),
+    }
+}
+
+fn throw(args: Vec<MalType>) -> MalType {
+    MalType::Error(Box::new(args.first().cloned().unwrap_or(MalType::Nil)))
+}
+
+/// Numbers the symbols `gensym` makes, across all threads, so no two are the
+/// same.
+static GENSYM_COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+/// The next number for a symbol nobody else has, which the auto-gensyms of a
+/// quasiquote take too.
+pub fn gensym_number() -> usize {
+    GENSYM_COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+}
+
+/// `(gensym)` gives a symbol like `G__12` nobody else has, and `(gensym
+/// "foo__")` one like `foo__12`.
+fn gensym(args: Vec<MalType>) -> MalType {
+    let prefix = match args.first() {
+        None => "G__",
+        Some(MalType::String(prefix)) => prefix,
+        Some(other) => return type_error("string", other),
+    };
+    MalType::Symbol(format!("{prefix}{}", gensym_number()))
+}
+
+fn with_timeout(args: Vec<MalType>) -> MalType {
+    if args.len() != 2 {
+        return arity_error("2", args.len());
+    }
+
+    match (&args[0], &args[1]) {
+        (
+            MalType::Number(ms),
+            f @ (MalType::Func(_)
+            | MalType::MalFunc { .. }
+            | MalType::Compiled(_)
+            | MalType::Native(_)),
+        ) => interpreter::with_timeout(Duration::from_millis((*ms).max(0) as u64), || {
+            f.apply(Vec::new())
+        }),
+        _ => string_error(format!(
+            "wrong argument types provided. Expected a Number and a function, got {} and {}",
+            MalType::discriminant_name(&args[0]),
+            MalType::discriminant_name(&args[1])
+        )),
+    }
+}
+
+fn ns(args: Vec<MalType>) -> MalType {
+    if args.len() != 1 {
+        return arity_error("1", args.len());
+    }
+
+    namespace::ns(&args[0]).unwrap_or_else(|err| err)
+}
+
+fn require(args: Vec<MalType>) -> MalType {
+    let reload = args.contains(&MalType::Symbol(":reload".to_owned()));
+
+    for spec in args
+        .iter()
+        .filter(|a| **a != MalType::Symbol(":reload".to_owned()))
+    {
+        if let Err(err) = namespace::require(spec, reload) {
+            return err;
+        }
+    }
+
+    MalType::Nil
+}
+
+fn arity_error(expected: &str, got: usize) -> MalType {
+    string_error(format!(
+        "wrong number of arguments provided. Expected {expected}, got {got}"
+    ))
+}
+
+fn type_error(expected: &str, got: &MalType) -> MalType {
+    string_error(format!(
+        "wrong argument type provided. Expected {expected}, got {}",
+        MalType::discriminant_name(got)
+    ))
+}
+
+/// Realizes the elements of any sequence, with nil standing for the empty one.
+/// The error is what the builtin should return.
+fn items(value: &MalType) -> Result<Vec<MalType>, MalType> {
+    if seq::is_seqable(value) {
+        seq::to_vec(value)
+    } else {
+        Err(type_error("a sequence", value))
+    }
+}
+
+fn is_truthy(value: &MalType) -> bool {
+    !matches!(value, MalType::Nil | MalType::False)
+}
+
+fn map(args: Vec<MalType>) -> MalType {
+    match args.len() {
+        0 => return arity_error("1 or more", 0),
+        1 => return transducers::map(args[0].clone()),
+        _ => {}
+    }
+
+    if args[1..]
+        .iter()
+        .any(|arg| matches!(arg, MalType::LazySeq(_)))
+    {
+        if let Some(arg) = args[1..].iter().find(|arg| !seq::is_seqable(arg)) {
+            return type_error("a sequence", arg);
+        }
+        return seq::map(args[0].clone(), args[1..].to_vec());
+    }
+
+    let mut colls = Vec::new();
+    for arg in &args[1..] {
+        match items(arg) {
+            Ok(items) => colls.push(items),
+            Err(err) => return err,
+        }
+    }
+
+    let len = colls.iter().map(|c| c.len()).min().unwrap_or(0);
+    let mut res = Vec::with_capacity(len);
+    for i in 0..len {
+        let value = args[0].apply(colls.iter().map(|c| c[i].clone()).collect());
+        if value.is_error() {
+            return value;
+        }
+        res.push(value);
+    }
+
+    MalType::List(res)
+}
+
+fn filter(args: Vec<MalType>) -> MalType {
+    match args.len() {
+        1 => return transducers::filter(args[0].clone()),
+        2 => {}
+        n => return arity_error("1 or 2", n),
+    }
+
+    if let MalType::LazySeq(_) = args[1] {
+        return seq::filter(args[0].clone(), args[1].clone());
+    }
+
+    let items = match items(&args[1]) {
+        Ok(items) => items,
+        Err(err) => return err,
+    };
+
+    let mut res = Vec::new();
+    for item in items {
+        let keep = args[0].apply([item.clone()].to_vec());
+        if keep.is_error() {
+            return keep;
+        }
+        if is_truthy(&keep) {
+            res.push(item);
+        }
+    }
+
+    MalType::List(res)
+}
+
+fn reduce(args: Vec<MalType>) -> MalType {
+    match args.as_slice() {
+        [_, coll] | [_, _, coll] if !seq::is_seqable(coll) => type_error("a sequence", coll),
+        [f, coll] => {
+            let mut items = seq::iter(coll);
+            match items.next() {
+                None => f.apply(Vec::new()),
+                Some(Ok(init)) => transducers::fold(f, init, items),
+                Some(Err(err)) => err,
+            }
+        }
+        [f, init, coll] => transducers::fold(f, init.clone(), seq::iter(coll)),
+        _ => arity_error("2 or 3", args.len()),
+    }
+}
+
+fn apply(args: Vec<MalType>) -> MalType {
+    let Some((last, init)) = args.split_last() else {
+        return arity_error("1 or more", 0);
+    };
+    if init.is_empty() {
+        return last.apply(Vec::new());
+    }
+
+    let items = match items(last) {
+        Ok(items) => items,
+        Err(err) => return err,
+    };
+
+    let call_args = [&init[1..], &items].concat();
+    init[0].apply(call_args)
+}
+
+fn range(args: Vec<MalType>) -> MalType {
+    let (start, end, step) = match args.as_slice() {
+        [] => return seq::range_from(0, 1),
+        [MalType::Number(end)] => (0, *end, 1),
+        [MalType::Number(start), MalType::Number(end)] => (*start, *end, 1),
+        [MalType::Number(start), MalType::Number(end), MalType::Number(step)] => {
+            (*start, *end, *step)
+        }
+        [_] | [_, _] | [_, _, _] => {
+            return string_error("wrong argument type provided. Expected Numbers".to_owned());
+        }
+        _ => return arity_error("0 to 3", args.len()),
+    };
+
+    if step == 0 {
+        return string_error("range step must not be 0".to_owned());
+    }
+
+    let mut res = Vec::new();
+    let mut n = start;
+    while (step > 0 && n < end) || (step < 0 && n > end) {
+        res.push(MalType::Number(n));
+        n += step;
+    }
+
+    MalType::List(res)
+}
+
+fn take(args: Vec<MalType>) -> MalType {
+    match args.as_slice() {
+        [MalType::Number(n)] => transducers::take(*n),
+        [n] => type_error("a Number", n),
+        [MalType::Number(n), coll] if seq::is_seqable(coll) => {
+            seq::take((*n).max(0) as usize, coll).map_or_else(|err| err, MalType::List)
+        }
+        [MalType::Number(_), coll] => type_error("a sequence", coll),
+        [n, _] => type_error("a Number", n),
+        _ => arity_error("1 or 2", args.len()),
+    }
+}
+
+fn drop(args: Vec<MalType>) -> MalType {
+    match args.as_slice() {
+        [MalType::Number(n), coll @ MalType::LazySeq(_)] => {
+            match seq::drop((*n).max(0) as usize, coll) {
+                Ok(rest @ MalType::LazySeq(_)) => rest,
+                Ok(rest) => items(&rest).map_or_else(|err| err, MalType::List),
+                Err(err) => err,
+            }
+        }
+        [MalType::Number(n), coll] => match items(coll) {
+            Ok(items) => MalType::List(items.into_iter().skip((*n).max(0) as usize).collect()),
+            Err(err) => err,
+        },
+        [n, _] => type_error("a Number", n),
+        _ => arity_error("2", args.len()),
+    }
+}
+
+fn partition(args: Vec<MalType>) -> MalType {
+    let (n, step, coll) = match args.as_slice() {
+        [MalType::Number(n), coll] => (*n, *n, coll),
+        [MalType::Number(n), MalType::Number(step), coll] => (*n, *step, coll),
+        [_, _] | [_, _, _] => {
+            return string_error(
+                "wrong argument type provided. Expected Numbers and a sequence".to_owned(),
             );
-            MalType::List(Vec::new())
         }
+        _ => return arity_error("2 or 3", args.len()),
+    };
+
+    if n <= 0 || step <= 0 {
+        return string_error("partition size and step must be positive".to_owned());
+    }
+
+    let items = match items(coll) {
+        Ok(items) => items,
+        Err(err) => return err,
+    };
+
+    // like in Clojure, a last partition with less than n items is dropped
+    let (n, step) = (n as usize, step as usize);
+    let mut res = Vec::new();
+    let mut start = 0;
+    while start + n <= items.len() {
+        res.push(MalType::List(items[start..start + n].to_vec()));
+        start += step;
+    }
+
+    MalType::List(res)
+}
+
+fn group_by(args: Vec<MalType>) -> MalType {
+    if args.len() != 2 {
+        return arity_error("2", args.len());
+    }
+
+    let items = match items(&args[1]) {
+        Ok(items) => items,
+        Err(err) => return err,
+    };
+
+    // keys in the order they are first seen, each with the items mapping to it
+    let mut groups: Vec<(MalType, Vec<MalType>)> = Vec::new();
+    for item in items {
+        let key = args[0].apply([item.clone()].to_vec());
+        if key.is_error() {
+            return key;
+        }
+
+        let group = groups
+            .iter_mut()
+            .find(|(k, _)| matches!(eq([k.clone(), key.clone()].to_vec()), MalType::True));
+        match group {
+            Some((_, group)) => group.push(item),
+            None => groups.push((key, [item].to_vec())),
+        }
+    }
+
+    MalType::Dictionary(
+        groups
+            .into_iter()
+            .flat_map(|(key, group)| [key, MalType::Vector(group)])
+            .collect(),
+    )
+}
+
+fn sort(args: Vec<MalType>) -> MalType {
+    let (comparator, coll) = match args.as_slice() {
+        [coll] => (None, coll),
+        [comparator, coll] => (Some(comparator), coll),
+        _ => return arity_error("1 or 2", args.len()),
+    };
+
+    let items = match items(coll) {
+        Ok(items) => items,
+        Err(err) => return err,
+    };
+
+    let mut compare = |a: &MalType, b: &MalType| match comparator {
+        Some(comparator) => compare_with(comparator, a, b),
+        None => compare_values(a, b),
+    };
+
+    match merge_sort(items, &mut compare) {
+        Ok(sorted) => MalType::List(sorted),
+        Err(err) => err,
+    }
+}
+
+/// `(lazy-seq* f)`, what the `lazy-seq` macro expands to: a sequence whose
+/// elements are the ones of what `f` returns, the first time they are needed.
+fn lazy_seq(args: Vec<MalType>) -> MalType {
+    match args.as_slice() {
+        [f @ (MalType::Func(_)
+        | MalType::MalFunc { .. }
+        | MalType::Compiled(_)
+        | MalType::Native(_))] => MalType::LazySeq(gc::lazy_seq(seq::LazySeq::new(f.clone()))),
+        [arg] => type_error("a function", arg),
+        _ => arity_error("1", args.len()),
+    }
+}
+
+/// `(seq coll)` is nil for an empty sequence, and a sequence of the elements
+/// of `coll` otherwise.
+fn seq(args: Vec<MalType>) -> MalType {
+    let [coll] = args.as_slice() else {
+        return arity_error("1", args.len());
+    };
+
+    match coll {
+        MalType::LazySeq(_) => match seq::step(coll) {
+            Ok(seq::Step::Empty) => MalType::Nil,
+            Ok(seq::Step::Cons(..)) => coll.clone(),
+            Err(err) => err,
+        },
+        _ => match items(coll) {
+            Ok(items) if items.is_empty() => MalType::Nil,
+            Ok(items) => MalType::List(items),
+            Err(err) => err,
+        },
+    }
+}
+
+fn iterate(args: Vec<MalType>) -> MalType {
+    match args.as_slice() {
+        [f, x] => seq::iterate(f.clone(), x.clone()),
+        _ => arity_error("2", args.len()),
+    }
+}
+
+/// `(repeat x)` is an infinite sequence of `x`, `(repeat n x)` a list of `n`.
+fn repeat(args: Vec<MalType>) -> MalType {
+    match args.as_slice() {
+        [x] => seq::repeat(x.clone()),
+        [MalType::Number(n), x] => match interpreter::check_size((*n).max(0) as usize) {
+            Ok(()) => MalType::List(vec![x.clone(); (*n).max(0) as usize]),
+            Err(err) => err,
+        },
+        [n, _] => type_error("a Number", n),
+        _ => arity_error("1 or 2", args.len()),
+    }
+}
+
+fn compare_values(a: &MalType, b: &MalType) -> Result<Ordering, MalType> {
+    match (a, b) {
+        (MalType::Number(a), MalType::Number(b)) => Ok(a.cmp(b)),
+        (MalType::Char(a), MalType::Char(b)) => Ok(a.cmp(b)),
+        (MalType::String(a), MalType::String(b)) | (MalType::Symbol(a), MalType::Symbol(b)) => {
+            Ok(a.cmp(b))
+        }
+        _ => Err(MalType::Error(Box::new(MalType::String(format!(
+            "cannot compare {} with {}",
+            MalType::discriminant_name(a),
+            MalType::discriminant_name(b)
+        ))))),
+    }
+}
+
+/// Like in Clojure, a comparator either returns a Number, or is a predicate
+/// telling whether its first argument goes before the second.
+fn compare_with(comparator: &MalType, a: &MalType, b: &MalType) -> Result<Ordering, MalType> {
+    match comparator.apply([a.clone(), b.clone()].to_vec()) {
+        err @ MalType::Error(_) => Err(err),
+        MalType::Number(n) => Ok(n.cmp(&0)),
+        before if is_truthy(&before) => Ok(Ordering::Less),
+        _ => match comparator.apply([b.clone(), a.clone()].to_vec()) {
+            err @ MalType::Error(_) => Err(err),
+            after if is_truthy(&after) => Ok(Ordering::Greater),
+            _ => Ok(Ordering::Equal),
+        },
+    }
+}
+
+/// A stable sort that stops at the first error from `compare`. Unlike
+/// `slice::sort_by`, it cannot panic on a comparator that is not a total order.
+fn merge_sort(
+    mut items: Vec<MalType>,
+    compare: &mut dyn FnMut(&MalType, &MalType) -> Result<Ordering, MalType>,
+) -> Result<Vec<MalType>, MalType> {
+    if items.len() <= 1 {
+        return Ok(items);
+    }
+
+    let right = items.split_off(items.len() / 2);
+    let left = merge_sort(items, compare)?;
+    let right = merge_sort(right, compare)?;
+
+    let mut res = Vec::with_capacity(left.len() + right.len());
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    while let (Some(l), Some(r)) = (left.peek(), right.peek()) {
+        if compare(l, r)? == Ordering::Greater {
+            res.extend(right.next());
+        } else {
+            res.extend(left.next());
+        }
+    }
+    res.extend(left);
+    res.extend(right);
+
+    Ok(res)
+}
+
+fn reduced(args: Vec<MalType>) -> MalType {
+    match <[MalType; 1]>::try_from(args) {
+        Ok([value]) => transducers::reduced(value),
+        Err(args) => arity_error("1", args.len()),
+    }
+}
+
+fn is_reduced(args: Vec<MalType>) -> MalType {
+    match args.as_slice() {
+        [value] => MalType::boolean(matches!(value, MalType::Reduced(_))),
+        _ => arity_error("1", args.len()),
+    }
+}
+
+fn unreduced(args: Vec<MalType>) -> MalType {
+    match <[MalType; 1]>::try_from(args) {
+        Ok([value]) => transducers::unreduced(value),
+        Err(args) => arity_error("1", args.len()),
+    }
+}
+
+fn comp(args: Vec<MalType>) -> MalType {
+    transducers::comp(args)
+}
+
+/// `(transduce xform f coll)` or `(transduce xform f init coll)`.
+fn transduce(args: Vec<MalType>) -> MalType {
+    match args.as_slice() {
+        [.., coll] if args.len() >= 3 && !seq::is_seqable(coll) => type_error("a sequence", coll),
+        [xform, f, coll] => transducers::transduce(xform, f, None, coll),
+        [xform, f, init, coll] => transducers::transduce(xform, f, Some(init.clone()), coll),
+        _ => arity_error("3 or 4", args.len()),
+    }
+}
+
+/// `(into to from)` or `(into to xform from)`.
+fn into(args: Vec<MalType>) -> MalType {
+    let mut args = args.into_iter();
+    match (args.next(), args.next(), args.next(), args.next()) {
+        (Some(_), Some(from), None, None) | (Some(_), Some(_), Some(from), None)
+            if !seq::is_seqable(&from) =>
+        {
+            type_error("a sequence", &from)
+        }
+        (Some(to), Some(from), None, None) => transducers::into(to, None, &from),
+        (Some(to), Some(xform), Some(from), None) => transducers::into(to, Some(&xform), &from),
+        _ => arity_error("2 or 3", args.len()),
+    }
+}
+
+/// `(sequence coll)` is `coll` as a list, `(sequence xform coll)` a lazy
+/// sequence of its elements through `xform`.
+fn sequence(args: Vec<MalType>) -> MalType {
+    match args.as_slice() {
+        [coll] => items(coll).map_or_else(|err| err, MalType::List),
+        [_, coll] if !seq::is_seqable(coll) => type_error("a sequence", coll),
+        [xform, coll] => transducers::sequence(xform, coll.clone()),
+        _ => arity_error("1 or 2", args.len()),
+    }
+}
+
+fn hash_set(args: Vec<MalType>) -> MalType {
+    MalType::Set(args.into_iter().collect())
+}
+
+fn set(args: Vec<MalType>) -> MalType {
+    match args.as_slice() {
+        [coll] => {
+            items(coll).map_or_else(|err| err, |items| MalType::Set(items.into_iter().collect()))
+        }
+        _ => arity_error("1", args.len()),
+    }
+}
+
+/// `(conj coll x...)`
+fn conj(args: Vec<MalType>) -> MalType {
+    let mut args = args.into_iter();
+    let Some(coll) = args.next() else {
+        return MalType::Vector(Vec::new());
+    };
+
+    let mut res = coll;
+    for item in args {
+        res = transducers::conj(res, item);
+        if res.is_error() {
+            break;
+        }
+    }
+    res
+}
+
+/// `(disj set x...)`
+fn disj(args: Vec<MalType>) -> MalType {
+    let mut args = args.into_iter();
+    match args.next() {
+        Some(MalType::Set(mut set)) => {
+            for item in args {
+                set.shift_remove(&item);
+            }
+            MalType::Set(set)
+        }
+        Some(MalType::Nil) => MalType::Nil,
+        Some(other) => type_error("a Set", &other),
+        None => arity_error("1 or more", 0),
+    }
+}
+
+/// Whether a set has an element, a dictionary a key, or a vector an index.
+fn contains(args: Vec<MalType>) -> MalType {
+    match args.as_slice() {
+        [MalType::Set(set), item] => MalType::boolean(set.contains(item)),
+        [MalType::Dictionary(items), key] => {
+            MalType::boolean(items.iter().step_by(2).any(|k| k == key))
+        }
+        [MalType::Vector(items), MalType::Number(i)] => {
+            MalType::boolean(*i >= 0 && (*i as usize) < items.len())
+        }
+        [MalType::Nil, _] => MalType::False,
+        [coll, _] => type_error("a Set, Dictionary or Vector", coll),
+        _ => arity_error("2", args.len()),
+    }
+}
+
+/// The builtins testing the type of their argument, like `nil?`.
+fn is_a(args: &[MalType], test: fn(&MalType) -> bool) -> MalType {
+    match args {
+        [value] => MalType::boolean(test(value)),
+        _ => arity_error("1", args.len()),
+    }
+}
+
+fn is_nil(args: Vec<MalType>) -> MalType {
+    is_a(&args, |value| matches!(value, MalType::Nil))
+}
+
+fn is_true(args: Vec<MalType>) -> MalType {
+    is_a(&args, |value| matches!(value, MalType::True))
+}
+
+fn is_false(args: Vec<MalType>) -> MalType {
+    is_a(&args, |value| matches!(value, MalType::False))
+}
+
+fn is_symbol(args: Vec<MalType>) -> MalType {
+    is_a(
+        &args,
+        |value| matches!(value, MalType::Symbol(s) if !s.starts_with(':')),
+    )
+}
+
+fn is_keyword(args: Vec<MalType>) -> MalType {
+    is_a(
+        &args,
+        |value| matches!(value, MalType::Symbol(s) if s.starts_with(':')),
+    )
+}
+
+fn is_string(args: Vec<MalType>) -> MalType {
+    is_a(&args, |value| matches!(value, MalType::String(_)))
+}
+
+fn is_number(args: Vec<MalType>) -> MalType {
+    is_a(&args, |value| matches!(value, MalType::Number(_)))
+}
+
+fn is_fn(args: Vec<MalType>) -> MalType {
+    is_a(&args, |value| {
+        matches!(
+            value,
+            MalType::Func(_) | MalType::MalFunc { .. } | MalType::Compiled(_) | MalType::Native(_)
+        ) && !value.is_macro()
+    })
+}
+
+fn is_macro(args: Vec<MalType>) -> MalType {
+    is_a(&args, MalType::is_macro)
+}
+
+fn is_vector(args: Vec<MalType>) -> MalType {
+    is_a(&args, |value| matches!(value, MalType::Vector(_)))
+}
+
+fn is_sequential(args: Vec<MalType>) -> MalType {
+    is_a(&args, |value| {
+        matches!(value, MalType::List(_) | MalType::Vector(_))
+    })
+}
+
+fn is_map(args: Vec<MalType>) -> MalType {
+    is_a(&args, |value| matches!(value, MalType::Dictionary(_)))
+}
+
+/// `(symbol "name")`
+fn symbol(args: Vec<MalType>) -> MalType {
+    match args.as_slice() {
+        [MalType::String(name)] => MalType::Symbol(name.clone()),
+        [symbol @ MalType::Symbol(_)] => symbol.clone(),
+        [arg] => type_error("a String", arg),
+        _ => arity_error("1", args.len()),
+    }
+}
+
+/// `(keyword "name")`, which keywords are given back by.
+fn keyword(args: Vec<MalType>) -> MalType {
+    match args.as_slice() {
+        [MalType::Symbol(name)] if name.starts_with(':') => args[0].clone(),
+        [MalType::String(name)] => MalType::Symbol(format!(":{name}")),
+        [arg] => type_error("a String", arg),
+        _ => arity_error("1", args.len()),
+    }
+}
+
+fn vector(args: Vec<MalType>) -> MalType {
+    MalType::Vector(args)
+}
+
+/// `(hash-map key value...)`, later keys replacing earlier equal ones.
+fn hash_map(args: Vec<MalType>) -> MalType {
+    if !args.len().is_multiple_of(2) {
+        return string_error("hash-map takes an even number of arguments".to_owned());
+    }
+    assoc([[MalType::Dictionary(Vec::new())].to_vec(), args].concat())
+}
+
+/// The keys and values of a dictionary argument, with nil standing for the
+/// empty one.
+fn entries(value: &MalType) -> Result<Vec<MalType>, MalType> {
+    match value {
+        MalType::Dictionary(items) => Ok(items.clone()),
+        MalType::Nil => Ok(Vec::new()),
+        other => Err(type_error("a Dictionary", other)),
+    }
+}
+
+/// `(assoc map key value...)`
+fn assoc(args: Vec<MalType>) -> MalType {
+    let Some((map, pairs)) = args.split_first() else {
+        return arity_error("1 or more", 0);
+    };
+    if !pairs.len().is_multiple_of(2) {
+        return string_error("assoc takes a value for each key".to_owned());
+    }
+
+    let mut items = match entries(map) {
+        Ok(items) => items,
+        Err(err) => return err,
+    };
+    for pair in pairs.chunks(2) {
+        match items.iter().step_by(2).position(|k| *k == pair[0]) {
+            Some(i) => items[2 * i + 1] = pair[1].clone(),
+            None => items.extend_from_slice(pair),
+        }
+    }
+    MalType::Dictionary(items)
+}
+
+/// `(dissoc map key...)`
+fn dissoc(args: Vec<MalType>) -> MalType {
+    let Some((map, keys)) = args.split_first() else {
+        return arity_error("1 or more", 0);
+    };
+
+    match entries(map) {
+        Ok(items) => MalType::Dictionary(
+            items
+                .chunks(2)
+                .filter(|pair| !keys.contains(&pair[0]))
+                .flatten()
+                .cloned()
+                .collect(),
+        ),
+        Err(err) => err,
+    }
+}
+
+/// `(get map key)` or `(get map key not-found)`
+fn get(args: Vec<MalType>) -> MalType {
+    let (map, key, not_found) = match args.as_slice() {
+        [map, key] => (map, key, MalType::Nil),
+        [map, key, not_found] => (map, key, not_found.clone()),
+        _ => return arity_error("2 or 3", args.len()),
+    };
+
+    match map {
+        MalType::Dictionary(items) => items
+            .chunks(2)
+            .find(|pair| pair[0] == *key)
+            .map_or(not_found, |pair| pair[1].clone()),
+        MalType::Set(set) if set.contains(key) => key.clone(),
+        MalType::Vector(items) if let MalType::Number(i) = key => usize::try_from(*i)
+            .ok()
+            .and_then(|i| items.get(i).cloned())
+            .unwrap_or(not_found),
+        MalType::Nil | MalType::Set(_) | MalType::Vector(_) => not_found,
+        other => type_error("a Dictionary", other),
+    }
+}
+
+fn keys(args: Vec<MalType>) -> MalType {
+    match args.as_slice() {
+        [map] => entries(map).map_or_else(
+            |err| err,
+            |items| MalType::List(items.into_iter().step_by(2).collect()),
+        ),
+        _ => arity_error("1", args.len()),
+    }
+}
+
+fn vals(args: Vec<MalType>) -> MalType {
+    match args.as_slice() {
+        [map] => entries(map).map_or_else(
+            |err| err,
+            |items| MalType::List(items.into_iter().skip(1).step_by(2).collect()),
+        ),
+        _ => arity_error("1", args.len()),
+    }
+}
+
+fn meta(args: Vec<MalType>) -> MalType {
+    match args.as_slice() {
+        [MalType::WithMeta(meta, _)] => meta.deref().clone(),
+        [_] => MalType::Nil,
+        _ => arity_error("1", args.len()),
+    }
+}
+
+/// `(with-meta value meta)`, which replaces the metadata `value` had.
+fn with_meta(args: Vec<MalType>) -> MalType {
+    match args.as_slice() {
+        [value, meta] => MalType::WithMeta(Box::new(meta.clone()), Box::new(value.clone())),
+        _ => arity_error("2", args.len()),
+    }
+}
+
+/// The arguments of the `set` namespace builtins, which all take sets.
+fn sets(args: Vec<MalType>, at_least: usize) -> Result<Vec<IndexSet<MalType>>, MalType> {
+    if args.len() < at_least {
+        return Err(arity_error(&format!("{at_least} or more"), args.len()));
+    }
+
+    args.into_iter()
+        .map(|arg| match arg {
+            MalType::Set(set) => Ok(set),
+            MalType::Nil => Ok(IndexSet::new()),
+            other => Err(type_error("a Set", &other)),
+        })
+        .collect()
+}
+
+fn set_union(args: Vec<MalType>) -> MalType {
+    match sets(args, 0) {
+        Ok(sets) => MalType::Set(sets.into_iter().flatten().collect()),
+        Err(err) => err,
+    }
+}
+
+fn set_intersection(args: Vec<MalType>) -> MalType {
+    match sets(args, 1) {
+        Ok(mut sets) => {
+            let mut res = sets.remove(0);
+            res.retain(|item| sets.iter().all(|set| set.contains(item)));
+            MalType::Set(res)
+        }
+        Err(err) => err,
+    }
+}
+
+fn set_difference(args: Vec<MalType>) -> MalType {
+    match sets(args, 1) {
+        Ok(mut sets) => {
+            let mut res = sets.remove(0);
+            res.retain(|item| !sets.iter().any(|set| set.contains(item)));
+            MalType::Set(res)
+        }
+        Err(err) => err,
+    }
+}
+
+fn is_subset(args: Vec<MalType>) -> MalType {
+    if args.len() != 2 {
+        return arity_error("2", args.len());
+    }
+
+    match sets(args, 2) {
+        Ok(sets) => MalType::boolean(sets[0].is_subset(&sets[1])),
+        Err(err) => err,
+    }
+}
+
+fn is_char(args: Vec<MalType>) -> MalType {
+    match args.as_slice() {
+        [value] => MalType::boolean(matches!(value, MalType::Char(_))),
+        _ => arity_error("1", args.len()),
+    }
+}
+
+/// The character with a code point, or the only character of a string.
+fn char(args: Vec<MalType>) -> MalType {
+    match args.as_slice() {
+        [MalType::Char(c)] => MalType::Char(*c),
+        [MalType::Number(n)] => match u32::try_from(*n).ok().and_then(char::from_u32) {
+            Some(c) => MalType::Char(c),
+            None => string_error(format!("{n} is not a valid code point")),
+        },
+        [MalType::String(s)] if s.chars().count() == 1 => MalType::Char(s.chars().next().unwrap()),
+        [arg] => type_error("a Number or a one character String", arg),
+        _ => arity_error("1", args.len()),
+    }
+}
+
+/// The code point of a character.
+fn int(args: Vec<MalType>) -> MalType {
+    match args.as_slice() {
+        [MalType::Char(c)] => MalType::Number(*c as i64),
+        [MalType::Number(n)] => MalType::Number(*n),
+        [arg] => type_error("a Char or a Number", arg),
+        _ => arity_error("1", args.len()),
+    }
+}
+
+fn string_error(message: String) -> MalType {
+    MalType::Error(Box::new(MalType::String(message)))
+}
+
+/// The byte offset of the code point at `index` in `s`, which may be the end.
+fn byte_offset(s: &str, index: i64) -> Option<usize> {
+    let index = usize::try_from(index).ok()?;
+    s.char_indices()
+        .map(|(offset, _)| offset)
+        .chain([s.len()])
+        .nth(index)
+}
+
+/// `(subs s start end)`, where `start` and `end` count code points.
+fn subs(args: Vec<MalType>) -> MalType {
+    let (s, start, end) = match args.as_slice() {
+        [MalType::String(s), MalType::Number(start)] => (s, *start, None),
+        [MalType::String(s), MalType::Number(start), MalType::Number(end)] => {
+            (s, *start, Some(*end))
+        }
+        [_, _] | [_, _, _] => {
+            return string_error(
+                "wrong argument type provided. Expected a String and Numbers".to_owned(),
+            );
+        }
+        _ => return arity_error("2 or 3", args.len()),
+    };
+
+    let start_offset = byte_offset(s, start);
+    let end_offset = match end {
+        Some(end) => byte_offset(s, end),
+        None => Some(s.len()),
+    };
+    match (start_offset, end_offset) {
+        (Some(start), Some(end)) if start <= end => MalType::String(s[start..end].to_owned()),
+        _ => string_error(format!(
+            "subs: range {start}..{} is out of bounds for a string of length {}",
+            end.map_or_else(String::new, |end| end.to_string()),
+            s.chars().count()
+        )),
+    }
+}
+
+/// `(char-at s i)`: the character at code point `i`, or nil past the end.
+fn char_at(args: Vec<MalType>) -> MalType {
+    match args.as_slice() {
+        [MalType::String(s), MalType::Number(i)] => usize::try_from(*i)
+            .ok()
+            .and_then(|i| s.chars().nth(i))
+            .map_or(MalType::Nil, MalType::Char),
+        [_, _] => {
+            string_error("wrong argument type provided. Expected a String and a Number".to_owned())
+        }
+        _ => arity_error("2", args.len()),
+    }
+}
+
+/// A String or a Char argument used as a pattern.
+fn pattern(value: &MalType) -> Option<String> {
+    match value {
+        MalType::String(s) => Some(s.clone()),
+        MalType::Char(c) => Some(c.to_string()),
+        _ => None,
+    }
+}
+
+/// `(split s separator)`, where an empty separator splits `s` into its
+/// grapheme clusters, the characters as a reader sees them.
+fn split(args: Vec<MalType>) -> MalType {
+    match args.as_slice() {
+        [MalType::String(s), separator] => match pattern(separator) {
+            Some(separator) if separator.is_empty() => MalType::Vector(
+                s.graphemes(true)
+                    .map(|g| MalType::String(g.to_owned()))
+                    .collect(),
+            ),
+            Some(separator) => MalType::Vector(
+                s.split(separator.as_str())
+                    .map(|part| MalType::String(part.to_owned()))
+                    .collect(),
+            ),
+            None => type_error("a String or a Char separator", separator),
+        },
+        [s, _] => type_error("a String", s),
+        _ => arity_error("2", args.len()),
+    }
+}
+
+/// `(join coll)` or `(join separator coll)`, with the elements printed as by
+/// `str`.
+fn join(args: Vec<MalType>) -> MalType {
+    let (separator, coll) = match args.as_slice() {
+        [coll] => (String::new(), coll),
+        [separator, coll] => match pattern(separator) {
+            Some(separator) => (separator, coll),
+            None => return type_error("a String or a Char separator", separator),
+        },
+        _ => return arity_error("1 or 2", args.len()),
+    };
+
+    match items(coll) {
+        Ok(items) => MalType::String(print_seq(&items, false, "", "", &separator)),
+        Err(err) => err,
+    }
+}
+
+fn upper_case(args: Vec<MalType>) -> MalType {
+    match args.as_slice() {
+        [MalType::String(s)] => MalType::String(s.to_uppercase()),
+        [arg] => type_error("a String", arg),
+        _ => arity_error("1", args.len()),
+    }
+}
+
+fn lower_case(args: Vec<MalType>) -> MalType {
+    match args.as_slice() {
+        [MalType::String(s)] => MalType::String(s.to_lowercase()),
+        [arg] => type_error("a String", arg),
+        _ => arity_error("1", args.len()),
+    }
+}
+
+fn trim(args: Vec<MalType>) -> MalType {
+    match args.as_slice() {
+        [MalType::String(s)] => MalType::String(s.trim().to_owned()),
+        [arg] => type_error("a String", arg),
+        _ => arity_error("1", args.len()),
+    }
+}
+
+/// `(index-of s value from)`: the code point index of the first `value` in
+/// `s`, a String or a Char, at or after `from`. Nil if there is none.
+fn index_of(args: Vec<MalType>) -> MalType {
+    let (s, value, from) = match args.as_slice() {
+        [MalType::String(s), value] => (s, value, 0),
+        [MalType::String(s), value, MalType::Number(from)] => (s, value, (*from).max(0)),
+        [_, _] | [_, _, _] => {
+            return string_error(
+                "wrong argument type provided. Expected a String, a pattern and a Number"
+                    .to_owned(),
+            );
+        }
+        _ => return arity_error("2 or 3", args.len()),
+    };
+
+    let Some(value) = pattern(value) else {
+        return type_error("a String or a Char", value);
+    };
+    let Some(start) = byte_offset(s, from) else {
+        return MalType::Nil;
+    };
+
+    match s[start..].find(&value) {
+        Some(offset) => MalType::Number(s[..start + offset].chars().count() as i64),
+        None => MalType::Nil,
+    }
+}
+
+/// `(replace s match replacement)` replaces every occurrence of `match`.
+fn replace(args: Vec<MalType>) -> MalType {
+    match args.as_slice() {
+        [MalType::String(s), from, to] => match (pattern(from), pattern(to)) {
+            (Some(from), Some(to)) if !from.is_empty() => {
+                MalType::String(s.replace(from.as_str(), &to))
+            }
+            (Some(_), Some(_)) => MalType::String(s.clone()),
+            _ => string_error(
+                "wrong argument type provided. Expected Strings or Chars to replace".to_owned(),
+            ),
+        },
+        [s, _, _] => type_error("a String", s),
+        _ => arity_error("3", args.len()),
+    }
+}
+
+fn is_regex(args: Vec<MalType>) -> MalType {
+    match args.as_slice() {
+        [value] => MalType::boolean(matches!(value, MalType::Regex(_))),
+        _ => arity_error("1", args.len()),
+    }
+}
+
+/// `(re-pattern s)` compiles a regex from a string, as `#"..."` does.
+fn re_pattern(args: Vec<MalType>) -> MalType {
+    match args.as_slice() {
+        [MalType::Regex(re)] => MalType::Regex(re.clone()),
+        [MalType::String(s)] => pattern::compile(s),
+        [arg] => type_error("a String", arg),
+        _ => arity_error("1", args.len()),
+    }
+}
+
+/// The arguments of the `re-` functions taking a regex and a string.
+fn regex_args(args: &[MalType]) -> Result<(&Rc<pattern::Pattern>, &String), MalType> {
+    match args {
+        [MalType::Regex(re), MalType::String(s)] => Ok((re, s)),
+        [MalType::Regex(_), s] => Err(type_error("a String", s)),
+        [re, _] => Err(type_error("a Regex", re)),
+        _ => Err(arity_error("2", args.len())),
+    }
+}
+
+fn re_find(args: Vec<MalType>) -> MalType {
+    match regex_args(&args) {
+        Ok((re, s)) => pattern::find(re, s),
+        Err(err) => err,
+    }
+}
+
+fn re_matches(args: Vec<MalType>) -> MalType {
+    match regex_args(&args) {
+        Ok((re, s)) => pattern::matches(re, s),
+        Err(err) => err,
+    }
+}
+
+fn re_seq(args: Vec<MalType>) -> MalType {
+    match regex_args(&args) {
+        Ok((re, s)) => pattern::find_all(re.clone(), Rc::from(s.as_str()), 0),
+        Err(err) => err,
+    }
+}
+
+fn re_split(args: Vec<MalType>) -> MalType {
+    match regex_args(&args) {
+        Ok((re, s)) => pattern::split(re, s),
+        Err(err) => err,
+    }
+}
+
+/// `(re-replace re s replacement)`, the replacement being a string or a
+/// function of the match. Like the other `re-` functions, it takes the regex
+/// first.
+fn re_replace(args: Vec<MalType>) -> MalType {
+    match args.as_slice() {
+        [MalType::Regex(re), MalType::String(s), replacement] => {
+            pattern::replace(s, re, replacement)
+        }
+        [MalType::Regex(_), s, _] => type_error("a String", s),
+        [re, _, _] => type_error("a Regex", re),
+        _ => arity_error("3", args.len()),
+    }
+}
+
+/// The value to lay out, and the settings from `*print-right-margin*`,
+/// `*print-length*` and `*print-level*`, with an optional width overriding the
+/// first.
+fn pprint_args(args: &[MalType]) -> Result<(&MalType, printer::Options), MalType> {
+    let mut options = printer::Options::current();
+    match args {
+        [value] => Ok((value, options)),
+        [value, MalType::Number(width)] if *width > 0 => {
+            options.width = *width as usize;
+            Ok((value, options))
+        }
+        [_, width] => Err(type_error("a positive Number for the width", width)),
+        _ => Err(arity_error("1 or 2", args.len())),
+    }
+}
+
+/// `(pprint value)` or `(pprint value width)` prints `value` laid out over
+/// several lines when it does not fit in the width.
+fn pprint(args: Vec<MalType>) -> MalType {
+    match pprint_args(&args) {
+        Ok((value, options)) => {
+            println!("{}", pprint::pprint(value, &options));
+            MalType::Nil
+        }
+        Err(err) => err,
+    }
+}
+
+/// `(pprint-str value)` or `(pprint-str value width)`: what `pprint` prints.
+fn pprint_str(args: Vec<MalType>) -> MalType {
+    match pprint_args(&args) {
+        Ok((value, options)) => MalType::String(pprint::pprint(value, &options)),
+        Err(err) => err,
     }
 }
@@ -0,0 +1,466 @@
+//! A concrete syntax tree of Mal source, for tools like `mal-fmt` that need
+//! the comments and the layout `reader::read_str` throws away.
+//!
+//! Every form keeps the whitespace, commas, newlines and comments before it as
+//! its leading trivia, and a collection keeps those before its closing
+//! delimiter. Atoms, like numbers, symbols, strings or regexes, are kept as
+//! they are written, with the same boundaries as the reader gives them.
+//!
+//! The tree is lossless: printing it with `Display` gives back the source byte
+//! for byte, even when it does not parse, as a stray closing delimiter or an
+//! unterminated string is kept as an error node, and a collection that is not
+//! closed as it is. Each node also has the span of its form in the source.
+
+use std::{fmt, ops::Range};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Trivia {
+    /// Spaces, tabs and commas.
+    Whitespace(String),
+    /// A line break, `\n` or `\r\n`.
+    Newline(String),
+    /// A comment, from its `;` up to the end of the line.
+    Comment(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    /// The trivia between the previous form, or delimiter, and this one.
+    pub leading: Vec<Trivia>,
+    /// The bytes of the form in the source, without its leading trivia.
+    pub span: Range<usize>,
+    pub kind: NodeKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeKind {
+    /// A number, symbol, keyword, string, character or regex, as written.
+    Atom(String),
+    /// A reader macro like `'`, `~@` or `@` followed by the form it applies
+    /// to, or `^` followed by the metadata and then the form.
+    Prefix(String, Vec<Node>),
+    /// A list, vector, dictionary or set: `open` is `(`, `[`, `{` or `#{`,
+    /// `trailing` the trivia before the closing delimiter, and `closed`
+    /// whether there is one before the end of the source.
+    Collection {
+        open: String,
+        items: Vec<Node>,
+        trailing: Vec<Trivia>,
+        closed: bool,
+    },
+    /// Text that is not a form: a stray closing delimiter, or an unterminated
+    /// string or regex up to the end of the source.
+    Error(String),
+}
+
+impl NodeKind {
+    /// The delimiter closing a collection opened with `open`.
+    pub fn close(open: &str) -> &'static str {
+        match open {
+            "(" => ")",
+            "[" => "]",
+            _ => "}",
+        }
+    }
+}
+
+/// A whole file: its forms, then the trivia after the last one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cst {
+    pub nodes: Vec<Node>,
+    pub trailing: Vec<Trivia>,
+}
+
+impl Trivia {
+    pub fn text(&self) -> &str {
+        match self {
+            Trivia::Whitespace(text) | Trivia::Newline(text) | Trivia::Comment(text) => text,
+        }
+    }
+}
+
+impl fmt::Display for Trivia {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.text())
+    }
+}
+
+fn write_trivia(f: &mut fmt::Formatter<'_>, trivia: &[Trivia]) -> fmt::Result {
+    trivia.iter().try_for_each(|t| f.write_str(t.text()))
+}
+
+/// A node as written, with its leading trivia.
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_trivia(f, &self.leading)?;
+        write!(f, "{}", self.kind)
+    }
+}
+
+/// A form as written, without its leading trivia.
+impl fmt::Display for NodeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NodeKind::Atom(text) | NodeKind::Error(text) => f.write_str(text),
+            NodeKind::Prefix(prefix, forms) => {
+                f.write_str(prefix)?;
+                forms.iter().try_for_each(|form| write!(f, "{form}"))
+            }
+            NodeKind::Collection {
+                open,
+                items,
+                trailing,
+                closed,
+            } => {
+                f.write_str(open)?;
+                items.iter().try_for_each(|item| write!(f, "{item}"))?;
+                write_trivia(f, trailing)?;
+                if *closed {
+                    f.write_str(NodeKind::close(open))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The source the tree was parsed from.
+impl fmt::Display for Cst {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.nodes.iter().try_for_each(|node| write!(f, "{node}"))?;
+        write_trivia(f, &self.trailing)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub message: String,
+    /// The byte offset in the source the error was found at.
+    pub offset: usize,
+}
+
+impl ParseError {
+    /// The 1-based line and column of the error in `source`.
+    pub fn line_col(&self, source: &str) -> (usize, usize) {
+        let before = &source[..self.offset.min(source.len())];
+        let line = before.matches('\n').count() + 1;
+        let col = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+        (line, col)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Trivia(Trivia),
+    Open(String),
+    Close(char),
+    Prefix(String),
+    Atom(String),
+    Error(String),
+}
+
+/// The characters ending a symbol or a number, as in the reader.
+fn is_separator(c: char) -> bool {
+    matches!(c, '(' | '[' | '{' | ')' | ']' | '}' | ' ' | '\n' | '\t')
+}
+
+struct Lexer<'a> {
+    source: &'a str,
+    offset: usize,
+    errors: Vec<ParseError>,
+}
+
+impl Lexer<'_> {
+    fn rest(&self) -> &str {
+        &self.source[self.offset..]
+    }
+
+    /// Takes the next `len` bytes of the source.
+    fn take(&mut self, len: usize) -> String {
+        let text = self.rest()[..len].to_owned();
+        self.offset += len;
+        text
+    }
+
+    /// The length of the text up to the first character matching `end`, or
+    /// the rest of the source.
+    fn len_until(&self, from: usize, end: impl Fn(char) -> bool) -> usize {
+        self.rest()[from..]
+            .find(end)
+            .map_or(self.rest().len(), |i| from + i)
+    }
+
+    /// A string or regex literal, starting with its quote at `from`, up to
+    /// and including its closing quote, or an error up to the end of the
+    /// source if it has none.
+    fn quoted(&mut self, from: usize) -> Token {
+        let mut escaped = false;
+        for (i, c) in self.rest()[from + 1..].char_indices() {
+            match c {
+                '"' if !escaped => return Token::Atom(self.take(from + 1 + i + 1)),
+                '\\' => escaped = !escaped,
+                _ => escaped = false,
+            }
+        }
+
+        self.errors.push(ParseError {
+            message: "unterminated string".to_owned(),
+            offset: self.offset,
+        });
+        Token::Error(self.take(self.rest().len()))
+    }
+
+    fn next(&mut self) -> Option<(usize, Token)> {
+        let offset = self.offset;
+        let rest = self.rest();
+        let c = rest.chars().next()?;
+
+        let token = match c {
+            ' ' | ',' | '\t' => {
+                let len = self.len_until(0, |c| !matches!(c, ' ' | ',' | '\t'));
+                Token::Trivia(Trivia::Whitespace(self.take(len)))
+            }
+            '\n' => Token::Trivia(Trivia::Newline(self.take(1))),
+            '\r' if rest.starts_with("\r\n") => Token::Trivia(Trivia::Newline(self.take(2))),
+            ';' => {
+                let len = self.len_until(0, |c| c == '\n');
+                let len = if self.rest()[..len].ends_with('\r') {
+                    len - 1
+                } else {
+                    len
+                };
+                Token::Trivia(Trivia::Comment(self.take(len)))
+            }
+            '(' | '[' | '{' => Token::Open(self.take(1)),
+            '#' if rest.starts_with("#{") => Token::Open(self.take(2)),
+            ')' | ']' | '}' => {
+                self.take(1);
+                Token::Close(c)
+            }
+            '\'' | '`' | '^' | '@' => Token::Prefix(self.take(1)),
+            '~' if rest.starts_with("~@") => Token::Prefix(self.take(2)),
+            '~' => Token::Prefix(self.take(1)),
+            '"' => self.quoted(0),
+            '#' if rest.starts_with("#\"") => self.quoted(1),
+            '\\' => {
+                // the first character after the backslash is always part of
+                // the literal, even a delimiter
+                let first = rest[1..].chars().next().map_or(0, char::len_utf8);
+                let len = self.len_until(1 + first, is_separator);
+                Token::Atom(self.take(len))
+            }
+            // a number ends at the first character that is not a digit
+            _ if c.is_ascii_digit() => {
+                let len = self.len_until(0, |c| !c.is_ascii_digit());
+                Token::Atom(self.take(len))
+            }
+            '-' if rest[1..].starts_with(|c: char| c.is_ascii_digit()) => {
+                let len = self.len_until(1, |c| !c.is_ascii_digit());
+                Token::Atom(self.take(len))
+            }
+            _ => {
+                let len = self.len_until(0, |c| is_separator(c) || c == '\r');
+                let len = len.max(c.len_utf8());
+                Token::Atom(self.take(len))
+            }
+        };
+
+        Some((offset, token))
+    }
+}
+
+struct Parser<'a> {
+    lexer: Lexer<'a>,
+    peeked: Option<(usize, Token)>,
+    /// The end of the last token taken.
+    end: usize,
+    /// Trivia read after a reader macro at the end of the source, which goes
+    /// at the end of the file.
+    pending: Vec<Trivia>,
+}
+
+impl Parser<'_> {
+    fn peek(&mut self) -> Option<&(usize, Token)> {
+        if self.peeked.is_none() {
+            self.peeked = self.lexer.next();
+        }
+        self.peeked.as_ref()
+    }
+
+    fn next(&mut self) -> Option<(usize, Token)> {
+        self.peek();
+        let next = self.peeked.take();
+        if next.is_some() {
+            self.end = self.lexer.offset;
+        }
+        next
+    }
+
+    fn error(&mut self, message: String, offset: usize) {
+        self.lexer.errors.push(ParseError { message, offset });
+    }
+
+    fn trivia(&mut self) -> Vec<Trivia> {
+        let mut trivia = Vec::new();
+        while let Some((_, Token::Trivia(_))) = self.peek() {
+            let Some((_, Token::Trivia(t))) = self.next() else {
+                unreachable!()
+            };
+            trivia.push(t);
+        }
+        trivia
+    }
+
+    /// The next form, after `leading` trivia that was already read. There
+    /// must be a token left in the source.
+    fn node(&mut self, leading: Vec<Trivia>) -> Node {
+        let (offset, token) = self.next().expect("a token");
+        let mut end = self.end;
+
+        let kind = match token {
+            Token::Atom(text) => NodeKind::Atom(text),
+            Token::Error(text) => NodeKind::Error(text),
+            Token::Prefix(prefix) => {
+                let count = if prefix == "^" { 2 } else { 1 };
+                let mut forms = Vec::new();
+                for _ in 0..count {
+                    let leading = self.trivia();
+                    if self.peek().is_none() {
+                        self.error(format!("expected a form after '{prefix}', got EOF"), offset);
+                        // the trivia is left for the end of the file
+                        self.pending = leading;
+                        break;
+                    }
+                    let form = self.node(leading);
+                    end = form.span.end;
+                    forms.push(form);
+                }
+                NodeKind::Prefix(prefix, forms)
+            }
+            Token::Open(open) => {
+                let close = NodeKind::close(&open);
+                let mut items = Vec::new();
+                loop {
+                    let leading = self.trivia();
+                    match self.peek() {
+                        Some((_, Token::Close(c))) if close.starts_with(*c) => {
+                            self.next();
+                            end = self.end;
+                            break NodeKind::Collection {
+                                open,
+                                items,
+                                trailing: leading,
+                                closed: true,
+                            };
+                        }
+                        None => {
+                            self.error(format!("expected '{close}', got EOF"), offset);
+                            end = self.end;
+                            break NodeKind::Collection {
+                                open,
+                                items,
+                                trailing: leading,
+                                closed: false,
+                            };
+                        }
+                        _ => items.push(self.node(leading)),
+                    }
+                }
+            }
+            Token::Close(c) => {
+                self.error(format!("unexpected '{c}'"), offset);
+                NodeKind::Error(c.to_string())
+            }
+            Token::Trivia(_) => unreachable!(),
+        };
+
+        Node {
+            leading,
+            span: offset..end,
+            kind,
+        }
+    }
+}
+
+/// Parses a whole file, keeping every byte of it even when it does not parse,
+/// along with the errors found on the way.
+pub fn parse_lossless(source: &str) -> (Cst, Vec<ParseError>) {
+    let mut parser = Parser {
+        lexer: Lexer {
+            source,
+            offset: 0,
+            errors: Vec::new(),
+        },
+        peeked: None,
+        end: 0,
+        pending: Vec::new(),
+    };
+
+    let mut nodes = Vec::new();
+    loop {
+        let mut leading = std::mem::take(&mut parser.pending);
+        leading.extend(parser.trivia());
+        if parser.peek().is_none() {
+            let cst = Cst {
+                nodes,
+                trailing: leading,
+            };
+            return (cst, parser.lexer.errors);
+        }
+        nodes.push(parser.node(leading));
+    }
+}
+
+/// Parses a whole file, or fails on the first unbalanced delimiter or
+/// unterminated string.
+pub fn parse(source: &str) -> Result<Cst, ParseError> {
+    let (cst, mut errors) = parse_lossless(source);
+    match errors.is_empty() {
+        true => Ok(cst),
+        false => Err(errors.swap_remove(0)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn round_trips(source: &str) -> bool {
+        parse_lossless(source).0.to_string() == source
+    }
+
+    #[test]
+    fn prints_the_tests_back_byte_for_byte() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../tests");
+        let mut checked = 0;
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|e| e == "mal") {
+                let source = std::fs::read_to_string(&path).unwrap();
+                assert!(round_trips(&source), "{}", path.display());
+                checked += 1;
+            }
+        }
+        assert!(checked > 0);
+    }
+
+    #[test]
+    fn prints_comments_strings_and_broken_source_back() {
+        for source in [
+            "; only a comment",
+            "(a ; inside\n b) ; after\n;",
+            "(f)\n;",
+            "\"esc \\\" \\\\ \\n\" \"\\\\\"",
+            "#\"a\\\\d\" \\a \\newline",
+            "{:a 1, :b [2 3]} #{1} ^{:m 1} x '(~@y)",
+            "(unclosed [a",
+            ") stray",
+            "\"unterminated \\\"",
+            "\r\n  (a)\r\n",
+        ] {
+            assert!(round_trips(source), "{source:?}");
+        }
+    }
+}
@@ -0,0 +1,408 @@
+//! A step debugger for the tree-walking evaluator, enabled with `--debug`.
+//!
+//! While it is `active`, `eval` calls `step` before it evaluates each list,
+//! which pauses when a breakpoint is hit or a step command asks for it, and
+//! reads commands from stdin until told to go on. `eval` only checks whether
+//! it is active once per call, so it costs nothing otherwise. A breakpoint is
+//! either the name of a function, hit wherever a list calling it by that name
+//! is evaluated, or a line of the source, hit by the first list evaluated on
+//! it, as the `reader::Pos` the evaluator carries along has it. The line is
+//! one of any file, or of the file whose path ends with the one given, as in
+//! `lib/foo.mal:4`. Stepping over or out, and the backtrace, follow the
+//! `stack` of `eval` calls and function applications.
+//!
+//! From the REPL, `:break <name|line>`, `:delete <n>` and `:breaks` manage the
+//! breakpoints, and `:step <form>` evaluates a form pausing before its first
+//! step. `--break=<name|line>` sets one before a script starts.
+
+use std::{
+    cell::{Cell, RefCell},
+    fmt,
+    io::{self, BufRead, Write},
+    rc::Rc,
+};
+
+use crate::{
+    env::*,
+    printer::print_string,
+    reader::{read_str, Pos},
+    stack::{self, describe, short},
+    types::MalType,
+};
+
+const HELP: &str = "\
+s, step            evaluate up to the next form
+n, next            evaluate up to the next form outside of this one
+o, out             evaluate up to the end of this function application
+c, continue        evaluate up to the next breakpoint
+bt, backtrace      show the function applications in progress
+env                show the bindings of each Env around this form
+where <symbol>     show which Env binds a symbol
+p <form>           evaluate a form in the Env of this one
+b <name|[file:]line>  add a breakpoint
+d <n>              delete breakpoint n
+breaks             list the breakpoints
+q, quit            abort the evaluation
+h, help            show this help";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Breakpoint {
+    Function(String),
+    /// A line of the file whose path ends with the one given, if any.
+    Line(Option<String>, usize),
+}
+
+impl Breakpoint {
+    pub fn parse(spec: &str) -> Breakpoint {
+        if let Ok(line) = spec.parse() {
+            return Breakpoint::Line(None, line);
+        }
+        match spec.rsplit_once(':') {
+            Some((file, line))
+                if !file.is_empty()
+                    && let Ok(line) = line.parse() =>
+            {
+                Breakpoint::Line(Some(file.to_owned()), line)
+            }
+            _ => Breakpoint::Function(spec.to_owned()),
+        }
+    }
+
+    /// Whether this is a breakpoint at the line `pos` starts on.
+    fn at(&self, pos: &Pos) -> bool {
+        let Breakpoint::Line(file, line) = self else {
+            return false;
+        };
+        let path = &pos.source.file;
+        let in_file = file.as_ref().is_none_or(|file| {
+            path == file
+                || path
+                    .strip_suffix(file.as_str())
+                    .is_some_and(|p| p.ends_with('/'))
+        });
+        in_file && pos.line_col().0 == *line
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Breakpoint::Function(name) => write!(f, "function {name}"),
+            Breakpoint::Line(None, line) => write!(f, "line {line}"),
+            Breakpoint::Line(Some(file), line) => write!(f, "line {file}:{line}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Continue,
+    Step,
+    /// Pause at a form no deeper than `depth`, in the application `frame`.
+    Next {
+        depth: usize,
+        frame: Option<usize>,
+    },
+    /// Pause once fewer than `frames` applications are in progress.
+    Out {
+        frames: usize,
+    },
+}
+
+struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    mode: Mode,
+    /// Where the form that last hit a line breakpoint was read, whose parts
+    /// do not hit it again.
+    hit: Option<Rc<Pos>>,
+}
+
+thread_local! {
+    static ENABLED: Cell<bool> = const { Cell::new(false) };
+    /// Set while the debugger evaluates a form for `p`, which does not pause.
+    static SUSPENDED: Cell<bool> = const { Cell::new(false) };
+    static DEBUGGER: RefCell<Debugger> = const {
+        RefCell::new(Debugger {
+            breakpoints: Vec::new(),
+            mode: Mode::Continue,
+            hit: None,
+        })
+    };
+}
+
+pub fn enable() {
+    ENABLED.with(|e| e.set(true));
+}
+
+/// Whether `step` should be called, which `eval` checks once per call.
+pub fn active() -> bool {
+    ENABLED.with(|e| e.get()) && !SUSPENDED.with(|s| s.get())
+}
+
+pub fn add_breakpoint(breakpoint: Breakpoint) {
+    DEBUGGER.with(|d| d.borrow_mut().breakpoints.push(breakpoint));
+}
+
+/// Pauses before `ast`, read at `pos`, is evaluated in `env` if a breakpoint
+/// or a step asks for it. Gives back an error to raise when the user quits.
+pub fn step(ast: &MalType, pos: Option<&Rc<Pos>>, env: &Env) -> Result<(), MalType> {
+    let MalType::List(list) = ast else {
+        return Ok(());
+    };
+
+    let reason = DEBUGGER.with(|d| {
+        let mut d = d.borrow_mut();
+        let new_line = pos.filter(|pos| !d.hit.as_ref().is_some_and(|hit| hit.contains(pos)));
+
+        let hit = d.breakpoints.iter().position(|b| match b {
+            Breakpoint::Function(name) => list.first() == Some(&MalType::Symbol(name.clone())),
+            Breakpoint::Line(..) => new_line.is_some_and(|pos| b.at(pos)),
+        });
+        if let Some(i) = hit {
+            if let Breakpoint::Line(..) = d.breakpoints[i] {
+                d.hit = pos.cloned();
+            }
+            return Some(format!("breakpoint {}, {}", i + 1, d.breakpoints[i]));
+        }
+
+        let (frames, top) = stack::top();
+        let pause = match d.mode {
+            Mode::Continue => false,
+            Mode::Step => true,
+            Mode::Next { depth, frame } => {
+                stack::depth() < depth || (stack::depth() == depth && top == frame)
+            }
+            Mode::Out { frames: outer } => frames < outer,
+        };
+        pause.then(String::new)
+    });
+
+    match reason {
+        Some(reason) => pause(ast, pos, env, &reason),
+        None => Ok(()),
+    }
+}
+
+fn pause(ast: &MalType, pos: Option<&Rc<Pos>>, env: &Env, reason: &str) -> Result<(), MalType> {
+    if !reason.is_empty() {
+        println!("{reason}");
+    }
+    println!("-> {}", describe(ast, pos));
+
+    let stdin = io::stdin();
+    loop {
+        print!("debug> ");
+        let _ = io::stdout().flush();
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            // nobody left to ask
+            set_mode(Mode::Continue);
+            return Ok(());
+        }
+
+        let line = line.trim();
+        let (command, arg) = line.split_once(' ').unwrap_or((line, ""));
+        let arg = arg.trim();
+        let mode = match command {
+            "s" | "step" => Mode::Step,
+            "n" | "next" => Mode::Next {
+                depth: stack::depth(),
+                frame: stack::top().1,
+            },
+            "o" | "out" => Mode::Out {
+                frames: stack::top().0,
+            },
+            "c" | "continue" => Mode::Continue,
+            "q" | "quit" => {
+                set_mode(Mode::Continue);
+                let message = "quit the debugger".to_owned();
+                return Err(MalType::Error(Box::new(MalType::String(message))));
+            }
+            _ => {
+                match command {
+                    "" => {}
+                    "bt" | "backtrace" => backtrace(),
+                    "env" => show_env(env),
+                    "where" => show_binding(env, arg),
+                    "p" | "print" => evaluate(arg, env),
+                    "b" | "break" => add(arg, "b <name|[file:]line>"),
+                    "d" | "delete" => delete(arg),
+                    "breaks" => list(),
+                    "h" | "help" => println!("{HELP}"),
+                    _ => println!("Unknown command {command}, try help"),
+                }
+                continue;
+            }
+        };
+
+        set_mode(mode);
+        return Ok(());
+    }
+}
+
+fn set_mode(mode: Mode) {
+    DEBUGGER.with(|d| d.borrow_mut().mode = mode);
+}
+
+fn backtrace() {
+    let calls = stack::calls();
+    if calls.is_empty() {
+        println!("at the top level");
+    }
+    for (i, call) in calls.iter().enumerate() {
+        println!("#{i} {call}");
+    }
+}
+
+fn show_env(env: &Env) {
+    let mut env = Some(env.clone());
+    let mut level = 0;
+    while let Some(e) = env {
+        let data = e.data.borrow();
+        let mut names: Vec<&String> = data.keys().collect();
+        names.sort();
+
+        match (&e.outer, e.outer.as_ref().and_then(|o| o.outer.as_ref())) {
+            (None, _) => println!("{level}: builtins, {} bindings", names.len()),
+            (Some(_), None) => {
+                let names: Vec<&str> = names.iter().map(|n| n.as_str()).collect();
+                println!("{level}: namespace, {}", names.join(" "));
+            }
+            _ => {
+                let bindings: Vec<String> = names
+                    .iter()
+                    .map(|name| format!("{name} = {}", short(&data[*name])))
+                    .collect();
+                println!("{level}: {}", bindings.join(", "));
+            }
+        }
+
+        level += 1;
+        env = e.outer.clone();
+    }
+}
+
+/// Shows the level of the Env chain that binds `name`, walking out from
+/// `env` like `env_find`.
+fn show_binding(env: &Env, name: &str) {
+    let mut level = 0;
+    let mut env = Some(env.clone());
+    while let Some(e) = env {
+        if let Some(value) = e.data.borrow().get(name) {
+            return println!("{level}: {name} = {}", short(value));
+        }
+
+        level += 1;
+        env = e.outer.clone();
+    }
+    println!("{name} is not bound");
+}
+
+fn evaluate(source: &str, env: &Env) {
+    let form = read_str(source);
+    SUSPENDED.with(|s| s.set(true));
+    let value = crate::eval(form, env.clone());
+    SUSPENDED.with(|s| s.set(false));
+
+    match value {
+        MalType::Error(err) => println!("Error: {}", print_string(&err, true)),
+        value => println!("{}", print_string(&value, true)),
+    }
+}
+
+/// Adds the breakpoint `spec`, or shows how to when it is empty.
+fn add(spec: &str, usage: &str) {
+    if spec.is_empty() {
+        return println!("usage: {usage}");
+    }
+    let breakpoint = Breakpoint::parse(spec);
+    DEBUGGER.with(|d| {
+        let mut d = d.borrow_mut();
+        println!("breakpoint {}, {breakpoint}", d.breakpoints.len() + 1);
+        d.breakpoints.push(breakpoint);
+    });
+}
+
+/// Deletes the breakpoint numbered `n`, from 1, as `list` shows them.
+fn delete(n: &str) {
+    DEBUGGER.with(|d| {
+        let mut d = d.borrow_mut();
+        match n.parse::<usize>() {
+            Ok(i) if (1..=d.breakpoints.len()).contains(&i) => {
+                let breakpoint = d.breakpoints.remove(i - 1);
+                println!("deleted breakpoint {i}, {breakpoint}");
+            }
+            _ => println!("no breakpoint {n}"),
+        }
+    });
+}
+
+fn list() {
+    DEBUGGER.with(|d| {
+        let d = d.borrow();
+        if d.breakpoints.is_empty() {
+            println!("no breakpoints");
+        }
+        for (i, breakpoint) in d.breakpoints.iter().enumerate() {
+            println!("{}: {breakpoint}", i + 1);
+        }
+    });
+}
+
+/// Handles a debugger command typed at the REPL, and returns what is left to
+/// evaluate: nothing for `:break`, `:delete` and `:breaks`, the form for
+/// `:step`, and the whole line otherwise. Stepping stops at the end of each
+/// line.
+pub fn command(line: &str) -> Option<String> {
+    let (command, arg) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+    let arg = arg.trim();
+
+    set_mode(Mode::Continue);
+    match command {
+        ":break" => add(arg, ":break <name|[file:]line>"),
+        ":delete" => delete(arg),
+        ":breaks" => list(),
+        ":step" => {
+            set_mode(Mode::Step);
+            return Some(arg.to_owned());
+        }
+        _ => return Some(line.to_owned()),
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::read_located;
+
+    #[test]
+    fn parses_breakpoints() {
+        let in_file = Breakpoint::Line(Some("lib/foo.mal".to_owned()), 4);
+        assert_eq!(Breakpoint::parse("lib/foo.mal:4"), in_file);
+        assert_eq!(Breakpoint::parse("4"), Breakpoint::Line(None, 4));
+        let function = |name: &str| Breakpoint::Function(name.to_owned());
+        assert_eq!(Breakpoint::parse("name"), function("name"));
+        assert_eq!(Breakpoint::parse("a:b"), function("a:b"));
+        assert_eq!(Breakpoint::parse(":4"), function(":4"));
+    }
+
+    #[test]
+    fn breaks_at_a_line_of_a_file_ending_with_the_path_given() {
+        let forms = read_located("(+ 1 2)\n(f\n 3)", "/src/lib/foo.mal").unwrap();
+        let (first, second) = (&forms[0].1, &forms[1].1);
+
+        assert!(Breakpoint::parse("1").at(first));
+        assert!(!Breakpoint::parse("1").at(second));
+        assert!(Breakpoint::parse("2").at(second));
+        assert!(!Breakpoint::parse("3").at(second));
+
+        assert!(Breakpoint::parse("lib/foo.mal:2").at(second));
+        assert!(Breakpoint::parse("foo.mal:2").at(second));
+        assert!(Breakpoint::parse("/src/lib/foo.mal:2").at(second));
+        assert!(!Breakpoint::parse("o.mal:2").at(second));
+        assert!(!Breakpoint::parse("bar.mal:2").at(second));
+        assert!(!Breakpoint::parse("f").at(second));
+    }
+}
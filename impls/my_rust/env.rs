@@ -1,20 +1,26 @@
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use std::{cell::RefCell, rc::Rc};
 
-use crate::{print_string, MalType};
+use indexmap::IndexMap;
+
+use crate::{gc, namespace, print_string, MalType};
 
 pub type Env = Rc<EnvStruct>;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct EnvStruct {
-    data: RefCell<HashMap<String, MalType>>,
+    /// The bindings, in the order they were first made, so the analyzer can
+    /// look a local up by its index.
+    pub data: RefCell<IndexMap<String, MalType>>,
     pub outer: Option<Env>,
 }
 
 pub fn env_new(outer: Option<Env>) -> Env {
-    Rc::new(EnvStruct {
-        data: RefCell::new(HashMap::new()),
+    let env = Rc::new(EnvStruct {
+        data: RefCell::new(IndexMap::new()),
         outer,
-    })
+    });
+    gc::track_env(&env);
+    env
 }
 
 pub fn env_bind(outer: Option<Env>, binds: MalType, exprs: Vec<MalType>) -> Env {
@@ -76,6 +82,32 @@ pub fn env_get(env: &Env, k: &str) -> Option<MalType> {
     if let Some(env) = env_find(env, k) {
         env.data.borrow().get(k).cloned()
     } else {
-        None
+        namespace::resolve(env, k)
+    }
+}
+
+/// The value of `k`, bound at `index` of the `Env` `depth` levels out from
+/// `env`, or None if something else is bound there.
+pub fn env_slot(env: &Env, depth: usize, index: usize, k: &str) -> Option<MalType> {
+    let mut env = env;
+    for _ in 0..depth {
+        env = env.outer.as_ref()?;
+    }
+    match env.data.borrow().get_index(index) {
+        Some((name, value)) if name == k => Some(value.clone()),
+        _ => None,
+    }
+}
+
+/// The top-level `Env` of the namespace `env` belongs to, the one right below
+/// the root `Env` with the builtins.
+pub fn env_top(env: &Env) -> Env {
+    let mut env = env.clone();
+    while let Some(outer) = env.outer.clone() {
+        if outer.outer.is_none() {
+            break;
+        }
+        env = outer;
     }
+    env
 }
@@ -0,0 +1,316 @@
+//! The formatter behind `mal-fmt`.
+//!
+//! It keeps the line breaks and comments of the source, and re-indents every
+//! line from the forms it is nested in:
+//!
+//! - the body of a form like `let*`, `fn*` or `defmacro!` is indented by the
+//!   style's indentation, from the opening parenthesis;
+//! - the arguments of a call are aligned under the first one, when that one is
+//!   on the line of the function, and under the function otherwise;
+//! - the elements of vectors, dictionaries, sets and of lists that are not
+//!   calls are aligned after the opening delimiter.
+//!
+//! It also removes trailing whitespace, whitespace right inside delimiters and
+//! blank lines over the style's maximum, and moves closing delimiters left
+//! alone on a line up to the end of the previous one.
+
+use crate::cst::{Cst, Node, NodeKind, Trivia};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Style {
+    /// How much the body of a form like `let*` is indented.
+    pub indent: usize,
+    /// Whether the arguments of a call are aligned under the first one, or
+    /// indented like a body.
+    pub align_arguments: bool,
+    /// The most blank lines kept in a row.
+    pub max_blank_lines: usize,
+    /// The forms whose body is indented by `indent`.
+    pub body_forms: Vec<String>,
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Style {
+            indent: 2,
+            align_arguments: true,
+            max_blank_lines: 1,
+            body_forms: [
+                "def!",
+                "defmacro!",
+                "fn*",
+                "let*",
+                "do",
+                "if",
+                "try*",
+                "catch*",
+                "ns",
+                "cond",
+            ]
+            .map(str::to_owned)
+            .to_vec(),
+        }
+    }
+}
+
+struct Formatter<'a> {
+    style: &'a Style,
+    out: String,
+    /// The column the next character goes in.
+    column: usize,
+    line: usize,
+    /// Whether the indentation of the current line is still to be written.
+    at_line_start: bool,
+    /// The newline last met in the source, used for the ones added.
+    line_ending: String,
+}
+
+/// Where the token of a form was written.
+#[derive(Clone, Copy)]
+struct Position {
+    line: usize,
+    column: usize,
+}
+
+impl Formatter<'_> {
+    fn write(&mut self, text: &str) {
+        if self.at_line_start {
+            self.out.push_str(&" ".repeat(self.column));
+            self.at_line_start = false;
+        }
+
+        self.out.push_str(text);
+        match text.rsplit_once('\n') {
+            Some((before, after)) => {
+                self.line += before.matches('\n').count() + 1;
+                self.column = after.chars().count();
+            }
+            None => self.column += text.chars().count(),
+        }
+    }
+
+    /// Starts a new line with `newline`, `\n` or `\r\n` as in the source.
+    fn newline(&mut self, newline: &str, indent: usize) {
+        // a blank line for each newline after the first in a row
+        let blank = self.out.trim_end_matches(['\r', '\n']);
+        let in_a_row = self.out[blank.len()..].matches('\n').count();
+        if self.out.is_empty() || in_a_row > self.style.max_blank_lines {
+            return;
+        }
+
+        self.out.push_str(newline);
+        self.line_ending = if newline.is_empty() { "\n" } else { newline }.to_owned();
+        self.line += 1;
+        self.column = indent;
+        self.at_line_start = true;
+    }
+
+    /// Writes the trivia before a form or a closing delimiter, starting any
+    /// new line at `indent`. Whitespace is dropped at the start and end of
+    /// lines, right after an opening delimiter, and before a closing one.
+    fn trivia(&mut self, trivia: &[Trivia], indent: usize, after_open: bool, before_close: bool) {
+        let has_comment = trivia.iter().any(|t| matches!(t, Trivia::Comment(_)));
+        if before_close && !has_comment {
+            return;
+        }
+
+        for (i, t) in trivia.iter().enumerate() {
+            match t {
+                Trivia::Whitespace(ws) => {
+                    let ends_line = match trivia.get(i + 1) {
+                        Some(Trivia::Newline(_)) => true,
+                        None => before_close,
+                        _ => false,
+                    };
+                    if !(self.at_line_start || ends_line || (after_open && i == 0)) {
+                        self.write(ws);
+                    }
+                }
+                Trivia::Newline(newline) => self.newline(newline, indent),
+                Trivia::Comment(comment) => self.write(comment),
+            }
+        }
+
+        // the closing delimiter cannot go on the line of a comment
+        if before_close && matches!(trivia.last(), Some(Trivia::Comment(_))) {
+            self.newline(&self.line_ending.clone(), indent);
+        }
+    }
+
+    fn node(&mut self, node: &Node, indent: usize, after_open: bool) -> Position {
+        self.trivia(&node.leading, indent, after_open, false);
+        if self.at_line_start {
+            self.column = indent;
+        }
+        let position = Position {
+            line: self.line,
+            column: self.column,
+        };
+
+        match &node.kind {
+            NodeKind::Atom(text) | NodeKind::Error(text) => self.write(text),
+            NodeKind::Prefix(prefix, forms) => {
+                self.write(prefix);
+                for (i, form) in forms.iter().enumerate() {
+                    self.node(form, position.column + 1, i == 0);
+                }
+            }
+            NodeKind::Collection {
+                open,
+                items,
+                trailing,
+                closed,
+            } => {
+                self.write(open);
+                let indent = self.items(position.column, open, items);
+                self.trivia(trailing, indent, items.is_empty(), true);
+                if *closed {
+                    self.write(NodeKind::close(open));
+                }
+            }
+        }
+
+        position
+    }
+
+    /// Writes the elements of a collection opened at column `open_column`,
+    /// and returns the indentation of its last line.
+    fn items(&mut self, open_column: usize, open: &str, items: &[Node]) -> usize {
+        let aligned = open_column + open.chars().count();
+        let head = match (open, items.first()) {
+            (
+                "(",
+                Some(Node {
+                    kind: NodeKind::Atom(head),
+                    ..
+                }),
+            ) if is_symbol(head) => Some(head.as_str()),
+            _ => None,
+        };
+        let is_body_form = head.is_some_and(|head| self.style.body_forms.iter().any(|f| f == head));
+        let body = open_column + self.style.indent;
+
+        let mut indent = match head {
+            None => aligned,
+            Some(_) if is_body_form || !self.style.align_arguments => body,
+            // the arguments go under the function until the first one is met
+            Some(_) => aligned,
+        };
+
+        let mut head_line = None;
+        let aligns_arguments = head.is_some() && !is_body_form && self.style.align_arguments;
+        for (i, item) in items.iter().enumerate() {
+            let position = self.node(item, indent, i == 0);
+            match i {
+                0 => head_line = Some(position.line),
+                1 if aligns_arguments && head_line == Some(position.line) => {
+                    indent = position.column;
+                }
+                _ => {}
+            }
+        }
+
+        indent
+    }
+}
+
+/// Whether an atom is a symbol, and so can be the head of a call.
+fn is_symbol(text: &str) -> bool {
+    let Some(c) = text.chars().next() else {
+        return false;
+    };
+    let is_number =
+        c.is_ascii_digit() || (c == '-' && text.chars().nth(1).is_some_and(|c| c.is_ascii_digit()));
+    !(is_number || matches!(c, '"' | ':' | '\\' | '#')) && !matches!(text, "nil" | "true" | "false")
+}
+
+/// Formats a whole file.
+pub fn format(cst: &Cst, style: &Style) -> String {
+    let mut formatter = Formatter {
+        style,
+        out: String::new(),
+        column: 0,
+        line: 0,
+        at_line_start: false,
+        line_ending: "\n".to_owned(),
+    };
+
+    for node in &cst.nodes {
+        formatter.node(node, 0, formatter.out.is_empty());
+    }
+    formatter.trivia(&cst.trailing, 0, false, true);
+
+    let mut out = formatter.out.trim_end().to_owned();
+    if !out.is_empty() {
+        out.push_str(&formatter.line_ending);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use my_rust::{printer::print_string, reader::read_located};
+
+    use super::*;
+    use crate::cst;
+
+    /// The `.mal` files under `dir`, and the directories in it.
+    fn mal_files(dir: &Path, files: &mut Vec<PathBuf>) {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                mal_files(&path, files);
+            } else if path.extension().is_some_and(|e| e == "mal") {
+                files.push(path);
+            }
+        }
+    }
+
+    /// The forms of `source` as they print, since a list and a vector with the
+    /// same items are equal.
+    fn forms(source: &str, file: &str) -> Vec<String> {
+        read_located(source, file)
+            .unwrap_or_else(|err| panic!("{file}: {}", print_string(&err, true)))
+            .iter()
+            .map(|(form, _)| print_string(form, true))
+            .collect()
+    }
+
+    /// Formatting the libraries and the tests, in every style, changes none
+    /// of the forms they read as, and formatting them again changes nothing.
+    #[test]
+    fn formatting_keeps_forms() {
+        let impls = Path::new(env!("CARGO_MANIFEST_DIR")).join("..");
+        let mut files = Vec::new();
+        mal_files(&impls.join("lib"), &mut files);
+        mal_files(&impls.join("tests"), &mut files);
+        assert!(!files.is_empty());
+
+        let styles = [
+            Style::default(),
+            Style {
+                indent: 4,
+                align_arguments: false,
+                ..Style::default()
+            },
+        ];
+        for path in files {
+            let file = path.display().to_string();
+            let source = std::fs::read_to_string(&path).unwrap();
+            // step files test reader errors too
+            let Ok(cst) = cst::parse(&source) else {
+                continue;
+            };
+
+            for style in &styles {
+                let formatted = format(&cst, style);
+                assert_eq!(forms(&source, &file), forms(&formatted, &file), "{file}");
+
+                let again = format(&cst::parse(&formatted).unwrap(), style);
+                assert_eq!(formatted, again, "{file}");
+            }
+        }
+    }
+}
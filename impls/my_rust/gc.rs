@@ -0,0 +1,346 @@
+//! Cycle collector for the `Rc` graph formed by environments, atoms and
+//! closures.
+//!
+//! A function defined with `def!` is stored in the same `Env` it captures, and
+//! an atom can hold a function closing over the atom itself, so reference
+//! counting alone never frees them. Every `Env`, atom (or VM upvalue cell),
+//! compiled `Closure` and `LazySeq` is registered here when it is allocated,
+//! and `collect` finds the ones only kept alive by each other using trial
+//! deletion:
+//!
+//! 1. every tracked object starts with its strong count as its external count,
+//! 2. every reference found inside another tracked object is subtracted,
+//! 3. whatever is still referenced from outside is live, as well as everything
+//!    reachable from it,
+//! 4. the rest is garbage, and its cycles are broken by emptying the
+//!    environments and cells, which lets reference counting free them.
+//!
+//! References that the traversal does not know about (the Rust stack, nodes of
+//! the analyzer, VM protos) are simply counted as external, so the collector
+//! can only err towards keeping an object alive.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::{Rc, Weak},
+};
+
+use crate::{env::*, seq::LazySeq, types::*, vm::Closure};
+
+/// Number of tracked allocations after which `collect` runs on its own, at
+/// least. It waits for twice as many allocations as there were objects left
+/// alive by the last collection, so that collecting while a long lazy seq is
+/// being realized takes time linear in its length.
+const THRESHOLD: usize = 10_000;
+
+#[derive(Default)]
+struct Registry {
+    envs: RefCell<Vec<Weak<EnvStruct>>>,
+    atoms: RefCell<Vec<Weak<RefCell<MalType>>>>,
+    closures: RefCell<Vec<Weak<Closure>>>,
+    seqs: RefCell<Vec<Weak<LazySeq>>>,
+    allocated: Cell<usize>,
+    since_last: Cell<usize>,
+    survivors: Cell<usize>,
+    collections: Cell<usize>,
+    collected: Cell<usize>,
+}
+
+thread_local! {
+    static REGISTRY: Registry = Registry::default();
+}
+
+#[derive(Clone)]
+enum Object {
+    Env(Env),
+    Atom(Atom),
+    Closure(Rc<Closure>),
+    LazySeq(Rc<LazySeq>),
+}
+
+impl Object {
+    fn address(&self) -> usize {
+        match self {
+            Object::Env(e) => Rc::as_ptr(e) as usize,
+            Object::Atom(a) => Rc::as_ptr(a) as usize,
+            Object::Closure(c) => Rc::as_ptr(c) as usize,
+            Object::LazySeq(s) => Rc::as_ptr(s) as usize,
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Object::Env(e) => Rc::strong_count(e),
+            Object::Atom(a) => Rc::strong_count(a),
+            Object::Closure(c) => Rc::strong_count(c),
+            Object::LazySeq(s) => Rc::strong_count(s),
+        }
+    }
+
+    /// Calls `visit` with the address of every tracked object directly
+    /// referenced by this one. Returns false if the object could not be
+    /// inspected, in which case it must be treated as live.
+    fn children(&self, visit: &mut dyn FnMut(usize)) -> bool {
+        match self {
+            Object::Env(env) => {
+                let Ok(data) = env.data.try_borrow() else {
+                    return false;
+                };
+                data.values().for_each(|v| value_children(v, visit));
+                if let Some(outer) = &env.outer {
+                    visit(Rc::as_ptr(outer) as usize);
+                }
+            }
+            Object::Atom(atom) => {
+                let Ok(value) = atom.try_borrow() else {
+                    return false;
+                };
+                value_children(&value, visit);
+            }
+            Object::Closure(closure) => closure
+                .upvalues
+                .iter()
+                .for_each(|u| visit(Rc::as_ptr(u) as usize)),
+            Object::LazySeq(seq) => return seq.children(&mut |v| value_children(v, visit)),
+        }
+        true
+    }
+
+    fn break_cycles(&self) {
+        match self {
+            Object::Env(env) => {
+                let data = std::mem::take(&mut *env.data.borrow_mut());
+                drop(data);
+            }
+            Object::Atom(atom) => {
+                let value = atom.replace(MalType::Nil);
+                drop(value);
+            }
+            Object::Closure(_) | Object::LazySeq(_) => {}
+        }
+    }
+}
+
+fn value_children(value: &MalType, visit: &mut dyn FnMut(usize)) {
+    match value {
+        MalType::Atom(a) => visit(Rc::as_ptr(a) as usize),
+        MalType::Compiled(c) => visit(Rc::as_ptr(c) as usize),
+        MalType::MalFunc { env: Some(env), .. } => visit(Rc::as_ptr(env) as usize),
+        MalType::List(items) | MalType::Vector(items) | MalType::Dictionary(items) => {
+            items.iter().for_each(|i| value_children(i, visit))
+        }
+        MalType::Error(value) | MalType::Reduced(value) => value_children(value, visit),
+        MalType::Set(items) => items.iter().for_each(|i| value_children(i, visit)),
+        MalType::LazySeq(seq) => visit(Rc::as_ptr(seq) as usize),
+        MalType::WithMeta(value, meta) => {
+            value_children(value, visit);
+            value_children(meta, visit);
+        }
+        _ => {}
+    }
+}
+
+pub fn track_env(env: &Env) {
+    REGISTRY.with(|r| r.envs.borrow_mut().push(Rc::downgrade(env)));
+    allocated();
+}
+
+pub fn atom(value: MalType) -> Atom {
+    let atom = Rc::new(RefCell::new(value));
+    REGISTRY.with(|r| r.atoms.borrow_mut().push(Rc::downgrade(&atom)));
+    allocated();
+    atom
+}
+
+pub fn closure(closure: Closure) -> Rc<Closure> {
+    let closure = Rc::new(closure);
+    REGISTRY.with(|r| r.closures.borrow_mut().push(Rc::downgrade(&closure)));
+    allocated();
+    closure
+}
+
+pub fn lazy_seq(seq: LazySeq) -> Rc<LazySeq> {
+    let seq = Rc::new(seq);
+    REGISTRY.with(|r| r.seqs.borrow_mut().push(Rc::downgrade(&seq)));
+    allocated();
+    seq
+}
+
+fn allocated() {
+    let run = REGISTRY.with(|r| {
+        r.allocated.set(r.allocated.get() + 1);
+        r.since_last.set(r.since_last.get() + 1);
+        r.since_last.get() >= THRESHOLD.max(2 * r.survivors.get())
+    });
+
+    if run {
+        collect();
+    }
+}
+
+/// Drops the registry entries of objects freed by reference counting and
+/// returns the ones still alive.
+fn live_objects() -> Vec<Object> {
+    REGISTRY.with(|r| {
+        let mut objects = Vec::new();
+
+        r.envs.borrow_mut().retain(|w| match w.upgrade() {
+            Some(e) => {
+                objects.push(Object::Env(e));
+                true
+            }
+            None => false,
+        });
+        r.atoms.borrow_mut().retain(|w| match w.upgrade() {
+            Some(a) => {
+                objects.push(Object::Atom(a));
+                true
+            }
+            None => false,
+        });
+        r.closures.borrow_mut().retain(|w| match w.upgrade() {
+            Some(c) => {
+                objects.push(Object::Closure(c));
+                true
+            }
+            None => false,
+        });
+        r.seqs.borrow_mut().retain(|w| match w.upgrade() {
+            Some(s) => {
+                objects.push(Object::LazySeq(s));
+                true
+            }
+            None => false,
+        });
+
+        objects
+    })
+}
+
+/// Runs a full collection and returns the number of objects found to be
+/// unreachable garbage.
+pub fn collect() -> usize {
+    REGISTRY.with(|r| r.since_last.set(0));
+
+    let objects = live_objects();
+    let index: HashMap<usize, usize> = objects
+        .iter()
+        .enumerate()
+        .map(|(i, o)| (o.address(), i))
+        .collect();
+
+    // `objects` holds one strong reference to each of them
+    let mut external: Vec<isize> = objects
+        .iter()
+        .map(|o| o.strong_count() as isize - 1)
+        .collect();
+    let mut inspected = vec![true; objects.len()];
+
+    for (i, object) in objects.iter().enumerate() {
+        inspected[i] = object.children(&mut |child| {
+            if let Some(&c) = index.get(&child) {
+                external[c] -= 1;
+            }
+        });
+    }
+
+    let mut live = vec![false; objects.len()];
+    let mut pending: Vec<usize> = (0..objects.len())
+        .filter(|&i| external[i] > 0 || !inspected[i])
+        .collect();
+
+    while let Some(i) = pending.pop() {
+        if live[i] {
+            continue;
+        }
+        live[i] = true;
+        objects[i].children(&mut |child| {
+            if let Some(&c) = index.get(&child) {
+                if !live[c] {
+                    pending.push(c);
+                }
+            }
+        });
+    }
+
+    let garbage: Vec<Object> = objects
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !live[*i])
+        .map(|(_, o)| o.clone())
+        .collect();
+    drop(objects);
+
+    garbage.iter().for_each(|o| o.break_cycles());
+
+    let collected = garbage.len();
+    REGISTRY.with(|r| {
+        r.survivors.set(live.iter().filter(|live| **live).count());
+        r.collections.set(r.collections.get() + 1);
+        r.collected.set(r.collected.get() + collected);
+    });
+
+    collected
+}
+
+pub fn stats() -> MalType {
+    let objects = live_objects();
+    let count =
+        |f: fn(&Object) -> bool| MalType::Number(objects.iter().filter(|o| f(o)).count() as i64);
+
+    let (allocated, collections, collected) =
+        REGISTRY.with(|r| (r.allocated.get(), r.collections.get(), r.collected.get()));
+
+    MalType::Dictionary(
+        [
+            MalType::String("envs".to_owned()),
+            count(|o| matches!(o, Object::Env(_))),
+            MalType::String("atoms".to_owned()),
+            count(|o| matches!(o, Object::Atom(_))),
+            MalType::String("closures".to_owned()),
+            count(|o| matches!(o, Object::Closure(_))),
+            MalType::String("seqs".to_owned()),
+            count(|o| matches!(o, Object::LazySeq(_))),
+            MalType::String("allocated".to_owned()),
+            MalType::Number(allocated as i64),
+            MalType::String("collections".to_owned()),
+            MalType::Number(collections as i64),
+            MalType::String("collected".to_owned()),
+            MalType::Number(collected as i64),
+        ]
+        .to_vec(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{interpreter::Interpreter, printer::print_string, reader::read_str};
+
+    fn rep(interpreter: &Interpreter, source: &str) -> String {
+        print_string(&interpreter.eval(read_str(source)), true)
+    }
+
+    #[test]
+    fn collects_a_function_kept_alive_by_its_own_env() {
+        let interpreter = Interpreter::new(crate::eval, Vec::new());
+        collect();
+        rep(&interpreter, "(let* [f (fn* [] f)] nil)");
+        assert!(collect() > 0);
+        assert_eq!(collect(), 0);
+    }
+
+    #[test]
+    fn keeps_what_a_lazy_seq_held_under_several_names_reaches() {
+        let interpreter = Interpreter::new(crate::eval, Vec::new());
+        rep(
+            &interpreter,
+            "(def! h (fn* [t] (let* [s (lazy-seq* t) s2 s s3 s s4 s g (fn* [] g)] 1)))",
+        );
+        rep(
+            &interpreter,
+            "(def! run (fn* [x] (do (h (fn* [] (list x))) (gc) x)))",
+        );
+        assert_eq!(rep(&interpreter, "(run 42)"), "42");
+    }
+}
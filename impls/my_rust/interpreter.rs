@@ -0,0 +1,509 @@
+//! An interpreter instance: the global `Env` built by `core_env`, the functions
+//! defined in Mal itself on top of it, and the evaluator it runs with.
+//!
+//! Values are built on `Rc` and `RefCell`, so an interpreter and everything it
+//! creates stays on the thread that created it. Running code on another thread
+//! means starting a new `Interpreter` there (see `isolate`).
+//!
+//! An interpreter can also be sandboxed, for running code that is not trusted:
+//! it only gets the builtins allowed by its capabilities, and every call to
+//! `Interpreter::eval` runs with a step budget and a cap on the size of the
+//! collections and strings that builtins create. Going over a limit raises an
+//! error that `try*` can catch, `{:type :sandbox-limit :limit <name> ...}`.
+//! Running out of fuel leaves a few more steps for the `catch*` handlers
+//! on the way up, after which every step fails. Futures and isolates started
+//! from a sandbox get the same capabilities, cap and strictness, and half of
+//! the fuel it has left, which it gives up, so that starting more of them
+//! does not add to the budget.
+//!
+//! `concat`, `str` and `repeat` check the size of their result before
+//! building it. Other builtins are only checked once they have returned, so
+//! the value over the cap has been allocated by then.
+//!
+//! Any evaluation can be stopped early, from another thread through an
+//! `InterruptHandle` or when the deadline set by `with-timeout` passes. The
+//! evaluators poll for both in `tick`, and raise `{:type :interrupted ...}` or
+//! `{:type :timeout ...}`. Futures and isolates are stopped along with the
+//! evaluation that started them, and waiting for one of them polls too.
+//!
+//! Evaluating a symbol that is not bound raises `'foo' not found`, unless the
+//! interpreter is lenient, as it used to be, where the symbol evaluates to
+//! itself. Old scripts may rely on that.
+
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{core::*, env::*, namespace::Namespaces, reader::read_str, seq, types::*};
+
+pub type Eval = fn(MalType, Env) -> MalType;
+
+/// Groups of builtins that reach outside of the interpreter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// Reading files, with `slurp`.
+    Io,
+    /// Starting other interpreters, with `future` and `spawn-isolate`.
+    Threads,
+}
+
+pub const ALL_CAPABILITIES: [Capability; 2] = [Capability::Io, Capability::Threads];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Evaluation steps left before the evaluation is stopped.
+    pub fuel: Option<u64>,
+    /// Largest number of elements (or bytes, for strings) a builtin may return.
+    pub max_size: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Sandbox {
+    pub limits: Limits,
+    pub capabilities: Vec<Capability>,
+}
+
+/// Cancels the evaluation running in an interpreter, from any thread. The
+/// request only lasts until that evaluation returns. The handle of an
+/// interpreter started by another one is also interrupted through the handle
+/// of the evaluation that started it.
+#[derive(Debug, Clone, Default)]
+pub struct InterruptHandle {
+    flag: Arc<AtomicBool>,
+    parent: Option<Box<InterruptHandle>>,
+}
+
+impl InterruptHandle {
+    pub fn interrupt(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+    }
+
+    fn is_interrupted(&self) -> bool {
+        self.flag.load(Ordering::Relaxed)
+            || self.parent.as_ref().is_some_and(|p| p.is_interrupted())
+    }
+}
+
+/// What an interpreter started on another thread takes from the evaluation
+/// that started it (see `inherit`).
+pub struct Inherited {
+    sandbox: Sandbox,
+    strict: bool,
+    deadline: Option<Instant>,
+    interrupt: Option<InterruptHandle>,
+}
+
+thread_local! {
+    static EVAL: Cell<Option<Eval>> = const { Cell::new(None) };
+    static LIMITS: Cell<Limits> = Cell::new(Limits::default());
+    static STRICT: Cell<bool> = const { Cell::new(true) };
+    static CAPABILITIES: RefCell<Vec<Capability>> = RefCell::new(ALL_CAPABILITIES.to_vec());
+    /// Whether the running evaluation has already run out of fuel once.
+    static OUT_OF_FUEL: Cell<bool> = const { Cell::new(false) };
+    static INTERRUPT: RefCell<Option<InterruptHandle>> = const { RefCell::new(None) };
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+const PRELUDE: [&str; 8] = [
+    "(def! not (fn* (a) (if a false true)))",
+    "(def! load-file (fn* (f) (eval (read-file f))))",
+    "(defmacro! cond (fn* (& xs) (if (> (count xs) 0) (list 'if (first xs) (if (> (count xs) 1) (nth xs 1) (throw \"odd number of forms to cond\")) (cons 'cond (rest (rest xs)))))))",
+    "(defmacro! ns (fn* (& form) (list 'ns* (list 'quote form))))",
+    "(defmacro! lazy-seq (fn* (& body) (list 'lazy-seq* (list 'fn* [] (cons 'do body)))))",
+    "(defmacro! future (fn* (& body) (list 'future* (list 'fn* [] (cons 'do body)))))",
+    "(defmacro! trace (fn* (& names) (cons 'do (map (fn* (name) (list 'def! name (list 'trace* (list 'quote name) name))) names))))",
+    "(defmacro! untrace (fn* (& names) (cons 'do (map (fn* (name) (list 'def! name (list 'untrace* (list 'quote name) name))) names))))",
+];
+
+pub struct Interpreter {
+    /// The `Env` of the `user` namespace.
+    pub env: Env,
+    pub eval: Eval,
+    pub limits: Limits,
+    /// Whether an unbound symbol raises an error, rather than evaluating to
+    /// itself.
+    pub strict: bool,
+    pub namespaces: Rc<Namespaces>,
+    capabilities: Vec<Capability>,
+    interrupt: InterruptHandle,
+    /// The deadline of the evaluation that started this interpreter, if any.
+    deadline: Option<Instant>,
+}
+
+impl Interpreter {
+    pub fn new(eval: Eval, argv: Vec<String>) -> Interpreter {
+        Interpreter::with_env(eval, core_env(), argv, true)
+    }
+
+    pub fn sandboxed(eval: Eval, sandbox: Sandbox) -> Interpreter {
+        let read_files = sandbox.capabilities.contains(&Capability::Io);
+        let mut interpreter = Interpreter::with_env(
+            eval,
+            core_env_with(&sandbox.capabilities),
+            Vec::new(),
+            read_files,
+        );
+        interpreter.limits = sandbox.limits;
+        interpreter.capabilities = sandbox.capabilities;
+        interpreter
+    }
+
+    /// An interpreter for another thread, sandboxed like the evaluation that
+    /// started it and as strict, which stops when that evaluation is
+    /// interrupted or times out.
+    pub fn child(eval: Eval, inherited: Inherited) -> Interpreter {
+        let mut interpreter = Interpreter::sandboxed(eval, inherited.sandbox);
+        interpreter.strict = inherited.strict;
+        interpreter.deadline = inherited.deadline;
+        interpreter.interrupt.parent = inherited.interrupt.map(Box::new);
+        interpreter
+    }
+
+    fn with_env(eval: Eval, root: Env, argv: Vec<String>, read_files: bool) -> Interpreter {
+        EVAL.with(|e| e.set(Some(eval)));
+
+        env_set(
+            &root,
+            &MalType::Symbol("*ARGV*".to_owned()),
+            MalType::List(argv.into_iter().map(MalType::String).collect()),
+        );
+        env_set(
+            &root,
+            &MalType::Symbol("*load-path*".to_owned()),
+            MalType::List(load_path().into_iter().map(MalType::String).collect()),
+        );
+
+        for (name, value) in [
+            ("*print-right-margin*", MalType::Number(80)),
+            ("*print-length*", MalType::Nil),
+            ("*print-level*", MalType::Nil),
+            ("*print-atom-ids*", MalType::False),
+        ] {
+            env_set(&root, &MalType::Symbol(name.to_owned()), value);
+        }
+
+        // defining functions with mal itself
+        for source in PRELUDE {
+            eval(read_str(source), root.clone());
+        }
+
+        let namespaces = Namespaces::new(&root, read_files);
+        namespaces.define_builtins("set", &SET_NS);
+        namespaces.define_builtins("pprint", &PPRINT_NS);
+        Interpreter {
+            env: namespaces.current().env.clone(),
+            eval,
+            limits: Limits::default(),
+            strict: true,
+            namespaces,
+            capabilities: ALL_CAPABILITIES.to_vec(),
+            interrupt: InterruptHandle::default(),
+            deadline: None,
+        }
+    }
+
+    /// The name of the namespace the next form is evaluated in.
+    pub fn current_namespace(&self) -> String {
+        self.namespaces.current().name.clone()
+    }
+
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    /// Evaluates `ast` in the current namespace, with a fresh copy of the
+    /// interpreter's limits.
+    pub fn eval(&self, ast: MalType) -> MalType {
+        let outer_limits = LIMITS.with(|l| l.replace(self.limits));
+        let outer_strict = STRICT.with(|s| s.replace(self.strict));
+        let outer_capabilities = CAPABILITIES.with(|c| c.replace(self.capabilities.clone()));
+        let outer_out_of_fuel = OUT_OF_FUEL.with(|o| o.replace(false));
+        let outer_interrupt = INTERRUPT.with(|i| i.replace(Some(self.interrupt.clone())));
+
+        let env = self.namespaces.current().env.clone();
+        let res = match self.deadline {
+            Some(deadline) => {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                with_timeout(timeout, || self.namespaces.enter(|| (self.eval)(ast, env)))
+            }
+            None => self.namespaces.enter(|| (self.eval)(ast, env)),
+        };
+
+        self.interrupt.flag.store(false, Ordering::Relaxed);
+        INTERRUPT.with(|i| i.replace(outer_interrupt));
+        OUT_OF_FUEL.with(|o| o.set(outer_out_of_fuel));
+        CAPABILITIES.with(|c| c.replace(outer_capabilities));
+        STRICT.with(|s| s.set(outer_strict));
+        LIMITS.with(|l| l.set(outer_limits));
+        res
+    }
+}
+
+/// The directories `require` looks in, from the `MAL_LOAD_PATH` environment
+/// variable if it is set.
+fn load_path() -> Vec<String> {
+    match std::env::var("MAL_LOAD_PATH") {
+        Ok(path) => path.split(':').map(str::to_owned).collect(),
+        Err(_) => vec![".".to_owned()],
+    }
+}
+
+/// What an interpreter started by the evaluation running on the current thread
+/// takes from it, for `Interpreter::child`. It gets half of the fuel that is
+/// left, which the running evaluation loses.
+pub fn inherit() -> Inherited {
+    let limits = LIMITS.with(|l| {
+        let mut limits = l.get();
+        let child = Limits {
+            fuel: limits.fuel.map(|fuel| fuel / 2),
+            ..limits
+        };
+        limits.fuel = limits.fuel.map(|fuel| fuel - fuel / 2);
+        l.set(limits);
+        child
+    });
+    Inherited {
+        sandbox: Sandbox {
+            limits,
+            capabilities: CAPABILITIES.with(|c| c.borrow().clone()),
+        },
+        strict: STRICT.with(|s| s.get()),
+        deadline: DEADLINE.with(|d| d.get()),
+        interrupt: INTERRUPT.with(|i| i.borrow().clone()),
+    }
+}
+
+/// The evaluator of the interpreter running on the current thread.
+pub fn current_eval() -> Eval {
+    EVAL.with(|e| e.get()).unwrap_or(crate::eval)
+}
+
+fn limit_error(limit: &str, message: String) -> MalType {
+    MalType::Error(Box::new(MalType::Dictionary(
+        [
+            MalType::Symbol(":type".to_owned()),
+            MalType::Symbol(":sandbox-limit".to_owned()),
+            MalType::Symbol(":limit".to_owned()),
+            MalType::Symbol(format!(":{limit}")),
+            MalType::Symbol(":message".to_owned()),
+            MalType::String(message),
+        ]
+        .to_vec(),
+    )))
+}
+
+fn stopped_error(kind: &str, message: &str) -> MalType {
+    MalType::Error(Box::new(MalType::Dictionary(
+        [
+            MalType::Symbol(":type".to_owned()),
+            MalType::Symbol(format!(":{kind}")),
+            MalType::Symbol(":message".to_owned()),
+            MalType::String(message.to_owned()),
+        ]
+        .to_vec(),
+    )))
+}
+
+/// Runs `f` with a deadline `timeout` from now, or the deadline already in
+/// place if that one is sooner.
+pub fn with_timeout(timeout: Duration, f: impl FnOnce() -> MalType) -> MalType {
+    let deadline = Instant::now() + timeout;
+    let outer = DEADLINE.with(|d| d.get());
+    let inner = outer.map_or(deadline, |outer| outer.min(deadline));
+
+    DEADLINE.with(|d| d.set(Some(inner)));
+    let res = f();
+    DEADLINE.with(|d| d.set(outer));
+    res
+}
+
+/// Steps left to the `catch*` handlers of an evaluation that ran out of fuel.
+const GRACE: u64 = 1_000;
+
+/// Checks whether the running evaluation has been interrupted or has timed
+/// out, without charging it a step, for builtins that block.
+pub fn stopped() -> Result<(), MalType> {
+    let interrupted = INTERRUPT.with(|i| i.borrow().as_ref().is_some_and(|i| i.is_interrupted()));
+    if interrupted {
+        return Err(stopped_error("interrupted", "evaluation was interrupted"));
+    }
+
+    if DEADLINE
+        .with(|d| d.get())
+        .is_some_and(|d| Instant::now() >= d)
+    {
+        return Err(stopped_error("timeout", "evaluation timed out"));
+    }
+
+    Ok(())
+}
+
+/// Charges one evaluation step to the running evaluation, and checks whether
+/// it has been interrupted or has timed out. Once either happens every step
+/// fails, so a `catch*` handler inside the evaluation can only re-raise.
+/// Running out of fuel fails one step, and then gives `GRACE` more steps
+/// before every step fails too.
+pub fn tick() -> Result<(), MalType> {
+    stopped()?;
+
+    LIMITS.with(|l| {
+        let mut limits = l.get();
+        match limits.fuel {
+            Some(0) => {
+                if !OUT_OF_FUEL.with(|o| o.replace(true)) {
+                    limits.fuel = Some(GRACE);
+                    l.set(limits);
+                }
+                Err(limit_error("fuel", "evaluation ran out of fuel".to_owned()))
+            }
+            Some(fuel) => {
+                limits.fuel = Some(fuel - 1);
+                l.set(limits);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    })
+}
+
+/// What evaluating `symbol` gives when it is not bound: an error, or the
+/// symbol itself when the running interpreter is lenient. Keywords always
+/// evaluate to themselves.
+pub fn unbound(symbol: &str) -> MalType {
+    if symbol.starts_with(':') || !STRICT.with(|s| s.get()) {
+        return MalType::Symbol(symbol.to_owned());
+    }
+    MalType::Error(Box::new(MalType::String(format!("'{symbol}' not found"))))
+}
+
+/// Checks the size of a value a builtin is about to build against the size
+/// cap of the running evaluation.
+pub fn check_size(size: usize) -> Result<(), MalType> {
+    match LIMITS.with(|l| l.get().max_size) {
+        Some(max_size) if size > max_size => Err(limit_error(
+            "size",
+            format!("a value of size {size} is over the limit of {max_size}"),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Calls a builtin, checking what it returns against the size cap. Values
+/// carrying metadata are passed as the values they carry, except to `meta`.
+pub fn call_builtin(func: fn(Vec<MalType>) -> MalType, args: Vec<MalType>) -> MalType {
+    if reads_meta(func) {
+        return capped(func(args));
+    }
+    capped(func(args.into_iter().map(MalType::without_meta).collect()))
+}
+
+/// Calls a builtin closing over values, checking what it returns against the
+/// size cap.
+pub fn call_native(native: &Native, args: Vec<MalType>) -> MalType {
+    capped((native.0)(args))
+}
+
+fn capped(res: MalType) -> MalType {
+    let Some(max_size) = LIMITS.with(|l| l.get().max_size) else {
+        return res;
+    };
+
+    let size = match &res {
+        MalType::List(items) | MalType::Vector(items) => items.len(),
+        MalType::Dictionary(items) => items.len() / 2,
+        MalType::Set(items) => items.len(),
+        MalType::String(s) => s.len(),
+        MalType::LazySeq(_) => seq::realized_count(&res, max_size + 1),
+        _ => 0,
+    };
+
+    check_size(size).map_or_else(|err| err, |()| res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::printer::print_string;
+
+    fn rep(interpreter: &Interpreter, source: &str) -> String {
+        print_string(&interpreter.eval(read_str(source)), true)
+    }
+
+    fn threads_only(fuel: Option<u64>) -> Interpreter {
+        let sandbox = Sandbox {
+            limits: Limits {
+                fuel,
+                max_size: None,
+            },
+            capabilities: vec![Capability::Threads],
+        };
+        Interpreter::sandboxed(crate::eval, sandbox)
+    }
+
+    #[test]
+    fn a_future_gets_the_capabilities_of_its_sandbox() {
+        let interpreter = threads_only(None);
+        let source = "(try* @(future (slurp \"Cargo.toml\")) (catch* e e))";
+        assert_eq!(rep(&interpreter, source), "\"'slurp' not found\"");
+    }
+
+    #[test]
+    fn a_future_gets_half_of_the_fuel_left() {
+        let interpreter = threads_only(Some(10_000));
+        rep(
+            &interpreter,
+            "(def! burn (fn* [n] (if (= n 0) :done (burn (- n 1)))))",
+        );
+        assert_eq!(rep(&interpreter, "(burn 1500)"), ":done");
+
+        let source = "(try* @(future (burn 1500)) (catch* e (get e :limit)))";
+        assert_eq!(rep(&interpreter, source), ":fuel");
+        let source = "(try* (do (future nil) (burn 1500)) (catch* e (get e :limit)))";
+        assert_eq!(rep(&interpreter, source), ":fuel");
+    }
+
+    #[test]
+    fn a_future_is_as_lenient_as_its_interpreter() {
+        let mut interpreter = threads_only(None);
+        interpreter.strict = false;
+        assert_eq!(rep(&interpreter, "@(future unbound-here)"), "unbound-here");
+    }
+
+    #[test]
+    fn checks_the_size_cap_before_building_a_value() {
+        let sandbox = Sandbox {
+            limits: Limits {
+                fuel: None,
+                max_size: Some(10),
+            },
+            capabilities: Vec::new(),
+        };
+        let interpreter = Interpreter::sandboxed(crate::eval, sandbox);
+        for source in [
+            "(repeat 1000000000000 :x)",
+            "(concat (repeat 6 :x) (repeat 6 :x))",
+            "(str \"0123456\" \"789\" \"a\")",
+        ] {
+            let source = format!("(try* {source} (catch* e (get e :limit)))");
+            assert_eq!(rep(&interpreter, &source), ":size", "{source}");
+        }
+        let fits = "(str \"0123456\" \"789\")";
+        assert_eq!(rep(&interpreter, fits), "\"0123456789\"");
+    }
+
+    #[test]
+    fn running_out_of_fuel_can_be_caught() {
+        let interpreter = threads_only(Some(10_000));
+        rep(&interpreter, "(def! spin (fn* [] (spin)))");
+        assert_eq!(
+            rep(&interpreter, "(try* (spin) (catch* e (get e :limit)))"),
+            ":fuel"
+        );
+    }
+}
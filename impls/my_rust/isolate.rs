@@ -0,0 +1,478 @@
+//! Running Mal code on other threads.
+//!
+//! Nothing that holds an `Rc` ever crosses a thread boundary: the code to run
+//! and its result are printed in full, whatever `*print-length*` says, on one
+//! side and read back with `read_str` on the other, so each thread only sees
+//! values it created itself. Only plain data can be sent this way; functions, atoms and other
+//! handles are rejected by `serialize`.
+//!
+//! A `Future` calls a function on another thread and hands back its result,
+//! the function being sent as its source along with the definitions it uses
+//! (see `spawn`). An `Isolate` is a longer lived interpreter running a file,
+//! which exchanges values with the thread that spawned it over a pair of
+//! channels. If the file raises an error, the error is the last message the
+//! isolate sends.
+
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    fmt::Debug,
+    ops::Deref,
+    rc::Rc,
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::{
+    analyzer::Node,
+    core::builtin_name,
+    env::*,
+    interpreter::*,
+    namespace::resolve,
+    print_string,
+    printer::{print_with, Options},
+    reader::read_str,
+    types::*,
+};
+
+/// How often waiting for another thread checks whether the waiting evaluation
+/// has been interrupted or has timed out.
+const POLL: Duration = Duration::from_millis(5);
+
+/// The thread evaluating a future ends with its serialized result, or with the
+/// serialized value of the error it raised.
+pub struct Future {
+    handle: RefCell<Option<JoinHandle<Result<String, String>>>>,
+    value: RefCell<Option<MalType>>,
+}
+
+impl Debug for Future {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Future")
+            .field("value", &self.value)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for Future {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Eq for Future {}
+
+impl Future {
+    pub fn is_done(&self) -> bool {
+        match self.handle.borrow().as_ref() {
+            Some(handle) => handle.is_finished(),
+            None => true,
+        }
+    }
+
+    /// Blocks until the thread is done, the first time, and returns its result.
+    /// An error raised on the other thread is raised again here, and so is
+    /// the interruption or timeout of the waiting evaluation.
+    pub fn wait(&self) -> MalType {
+        while !self.is_done() {
+            if let Err(err) = stopped() {
+                return err;
+            }
+            thread::sleep(POLL);
+        }
+
+        if let Some(handle) = self.handle.borrow_mut().take() {
+            let value = match handle.join() {
+                Ok(Ok(result)) => deserialize(&result),
+                Ok(Err(thrown)) => MalType::Error(Box::new(deserialize(&thrown))),
+                Err(_) => MalType::Error(Box::new(MalType::String("future panicked".to_owned()))),
+            };
+            *self.value.borrow_mut() = Some(value);
+        }
+
+        self.value.borrow().clone().unwrap_or(MalType::Nil)
+    }
+}
+
+pub fn serialize(value: &MalType) -> Result<String, String> {
+    fn check(value: &MalType) -> Result<(), String> {
+        match value {
+            MalType::Nil
+            | MalType::True
+            | MalType::False
+            | MalType::Number(_)
+            | MalType::Char(_)
+            | MalType::Regex(_)
+            | MalType::String(_)
+            | MalType::Symbol(_) => Ok(()),
+            MalType::List(items) | MalType::Vector(items) | MalType::Dictionary(items) => {
+                items.iter().try_for_each(check)
+            }
+            MalType::Set(items) => items.iter().try_for_each(check),
+            _ => Err(format!(
+                "cannot send a value of type {} to another thread",
+                MalType::discriminant_name(value)
+            )),
+        }
+    }
+
+    check(value)?;
+    Ok(print_with(value, true, &Options::default()))
+}
+
+pub fn deserialize(source: &str) -> MalType {
+    read_str(source)
+}
+
+/// What the names used by a function are bound to, where it was made.
+type Lookup = Box<dyn Fn(&str) -> Option<MalType>>;
+
+/// The definitions a future takes along to its thread, as `[name macro?
+/// source]` vectors, to be made there before it runs.
+#[derive(Default)]
+struct Captures {
+    names: HashSet<String>,
+    defs: Vec<MalType>,
+}
+
+impl Captures {
+    /// Captures what the symbols of `form` are bound to by `lookup`.
+    fn form(&mut self, form: &MalType, lookup: &Lookup) {
+        match form {
+            MalType::Symbol(name) if !self.names.contains(name) => {
+                let Some(value) = lookup(name) else {
+                    return;
+                };
+                // marked first, for functions that call themselves
+                self.names.insert(name.clone());
+                if let Some(source) = self.value(&value) {
+                    self.defs.push(MalType::Vector(
+                        [
+                            form.clone(),
+                            MalType::boolean(value.is_macro()),
+                            source,
+                        ]
+                        .to_vec(),
+                    ));
+                }
+            }
+            MalType::List(items) if items.first() == Some(&MalType::Symbol("quote".to_owned())) => {
+            }
+            MalType::List(items) | MalType::Vector(items) | MalType::Dictionary(items) => {
+                items.iter().for_each(|item| self.form(item, lookup))
+            }
+            MalType::Set(items) => items.iter().for_each(|item| self.form(item, lookup)),
+            _ => {}
+        }
+    }
+
+    /// The form that evaluates to `value` on the other thread, after the
+    /// definitions it needs, or None if it cannot be sent.
+    fn value(&mut self, value: &MalType) -> Option<MalType> {
+        if let Some((params, body, lookup)) = function(value) {
+            self.form(&body, &lookup);
+            let fn_star = MalType::Symbol("fn*".to_owned());
+            return Some(MalType::List([fn_star, params, body].to_vec()));
+        }
+
+        match value {
+            MalType::Func(func) => builtin_name(*func).map(MalType::Symbol),
+            value if serialize(value).is_ok() => Some(MalType::List(
+                [MalType::Symbol("quote".to_owned()), value.clone()].to_vec(),
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// The parameters and body of a Mal function, whichever evaluator made it,
+/// and what the names in its body are bound to.
+fn function(value: &MalType) -> Option<(MalType, MalType, Lookup)> {
+    match value {
+        MalType::MalFunc {
+            params,
+            body,
+            env: Some(env),
+            ..
+        } => {
+            let body = match body.deref() {
+                MalType::Analyzed(node) => match node.deref() {
+                    Node::Lazy(form, ..) => form.clone(),
+                    _ => return None,
+                },
+                body => body.clone(),
+            };
+            Some((params.deref().clone(), body, globals(env.clone())))
+        }
+        MalType::Compiled(closure) => {
+            let (params, body) = closure.proto.source.clone()?;
+            let closure = closure.clone();
+            let globals = globals(closure.proto.globals.clone());
+            let lookup = move |name: &str| {
+                let upvalues = closure.proto.upvalue_names.iter().zip(&closure.upvalues);
+                match upvalues.rev().find(|(upvalue, _)| upvalue.eq(&name)) {
+                    Some((_, cell)) => Some(cell.borrow().clone()),
+                    None => globals(name),
+                }
+            };
+            Some((params, body, Box::new(lookup)))
+        }
+        _ => None,
+    }
+}
+
+/// Looks names up in `env`, leaving out the builtins and the functions of the
+/// prelude, which every interpreter has.
+fn globals(env: Env) -> Lookup {
+    Box::new(move |name| match env_find(&env, name) {
+        Some(found) if found.outer.is_none() => None,
+        Some(found) => found.data.borrow().get(name).cloned(),
+        None => resolve(&env, name),
+    })
+}
+
+/// Starts a new interpreter on its own thread, with the same evaluator and
+/// sandbox as the current one, and calls `thunk`, a function taking no arguments, in it.
+///
+/// The function is sent as the `fn*` form it was made from, along with what
+/// the names it uses are bound to, other than builtins: plain data as it is,
+/// and functions and macros the same way, with the names they use in turn.
+/// These are all defined as globals on the other thread, so two functions
+/// using the same name for different things do not both get what they expect.
+/// Names bound to what cannot be sent, like atoms, are left unbound there.
+pub fn spawn(thunk: &MalType) -> Result<Future, String> {
+    let mut captures = Captures::default();
+    let call = match captures.value(thunk) {
+        Some(f) if function(thunk).is_some() => MalType::List([f].to_vec()),
+        _ => return Err("future takes a function of no arguments".to_owned()),
+    };
+    let source = serialize(&MalType::List(
+        [MalType::Vector(captures.defs), call].to_vec(),
+    ))?;
+    let eval = current_eval();
+    let inherited = inherit();
+
+    let handle = thread::spawn(move || {
+        let interpreter = Interpreter::child(eval, inherited);
+        let result = match deserialize(&source) {
+            MalType::List(message) => match message.as_slice() {
+                [MalType::Vector(defs), call] => define(&interpreter, defs)
+                    .map_or_else(|err| err, |()| interpreter.eval(call.clone())),
+                _ => MalType::Nil,
+            },
+            _ => MalType::Nil,
+        };
+
+        let (result, raised) = match result {
+            MalType::Error(thrown) => (*thrown, true),
+            result => (result, false),
+        };
+        match serialize(&result) {
+            Ok(result) if raised => Err(result),
+            Ok(result) => Ok(result),
+            Err(err) => Err(print_string(&MalType::String(err), true)),
+        }
+    });
+
+    Ok(Future {
+        handle: RefCell::new(Some(handle)),
+        value: RefCell::new(None),
+    })
+}
+
+/// Makes the definitions captured for a future, in order.
+fn define(interpreter: &Interpreter, defs: &[MalType]) -> Result<(), MalType> {
+    for def in defs {
+        let MalType::Vector(def) = def else {
+            continue;
+        };
+        let [name, is_macro, source] = def.as_slice() else {
+            continue;
+        };
+
+        if *is_macro == MalType::True {
+            let defmacro = MalType::Symbol("defmacro!".to_owned());
+            let value = interpreter.eval(MalType::List(
+                [defmacro, name.clone(), source.clone()].to_vec(),
+            ));
+            if value.is_error() {
+                return Err(value);
+            }
+            continue;
+        }
+
+        match interpreter.eval(source.clone()) {
+            err @ MalType::Error(_) => return Err(err),
+            value => env_set(&interpreter.env, name, value),
+        }
+    }
+    Ok(())
+}
+
+/// A serialized value sent with `send`, or the serialized value of the error
+/// that stopped an isolate.
+type Message = Result<String, String>;
+
+/// One end of the connection between two interpreters. The spawning thread
+/// holds the handle returned by `spawn-isolate`, and the isolate gets the other
+/// end bound to `*parent*`.
+pub struct Isolate {
+    sender: Sender<Message>,
+    receiver: Receiver<Message>,
+    handle: RefCell<Option<JoinHandle<()>>>,
+}
+
+impl Debug for Isolate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Isolate").finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for Isolate {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Eq for Isolate {}
+
+impl Isolate {
+    /// Whether the interpreter on the other end has stopped. Always false for
+    /// `*parent*`, whose thread is not owned by the isolate.
+    pub fn is_done(&self) -> bool {
+        match self.handle.borrow().as_ref() {
+            Some(handle) => handle.is_finished(),
+            None => false,
+        }
+    }
+
+    pub fn send(&self, value: &MalType) -> Result<(), String> {
+        let message = serialize(value)?;
+        self.sender
+            .send(Ok(message))
+            .map_err(|_| "the receiving isolate has stopped".to_owned())
+    }
+
+    /// Blocks until a value arrives. Returns `None` once the other end is gone
+    /// and nothing is left to read. An error that stopped the isolate is
+    /// raised here, and so is the interruption or timeout of the waiting
+    /// evaluation.
+    pub fn receive(&self) -> Option<MalType> {
+        loop {
+            match self.receiver.recv_timeout(POLL) {
+                Ok(Ok(message)) => return Some(deserialize(&message)),
+                Ok(Err(thrown)) => return Some(MalType::Error(Box::new(deserialize(&thrown)))),
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Err(err) = stopped() {
+                        return Some(err);
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+    }
+}
+
+/// Starts a new interpreter on its own thread, with the same evaluator and
+/// sandbox as the current one, and loads `path` in it.
+pub fn spawn_isolate(path: &str) -> Isolate {
+    let (to_isolate, from_parent) = mpsc::channel();
+    let (to_parent, from_isolate) = mpsc::channel();
+    let path = path.to_owned();
+    let eval = current_eval();
+    let inherited = inherit();
+
+    let handle = thread::spawn(move || {
+        let interpreter = Interpreter::child(eval, inherited);
+        let errors = to_parent.clone();
+        let parent = Isolate {
+            sender: to_parent,
+            receiver: from_parent,
+            handle: RefCell::new(None),
+        };
+        env_set(
+            &interpreter.env,
+            &MalType::Symbol("*parent*".to_owned()),
+            MalType::Isolate(Rc::new(parent)),
+        );
+
+        let res = interpreter.eval(MalType::List(vec![
+            MalType::Symbol("load-file".to_owned()),
+            MalType::String(path),
+        ]));
+        if let MalType::Error(thrown) = res {
+            let thrown = match serialize(&thrown) {
+                Ok(thrown) => thrown,
+                Err(err) => print_string(&MalType::String(err), true),
+            };
+            let _ = errors.send(Err(thrown));
+        }
+    });
+
+    Isolate {
+        sender: to_isolate,
+        receiver: from_isolate,
+        handle: RefCell::new(Some(handle)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Interpreter;
+
+    fn rep(interpreter: &Interpreter, source: &str) -> String {
+        print_string(&interpreter.eval(read_str(source)), true)
+    }
+
+    /// Whether `done` evaluates to true within five seconds.
+    fn eventually(interpreter: &Interpreter, done: &str) -> bool {
+        (0..500).any(|_| {
+            thread::sleep(Duration::from_millis(10));
+            rep(interpreter, done) == "true"
+        })
+    }
+
+    #[test]
+    fn interrupting_stops_the_wait_for_a_future_and_the_future() {
+        let interpreter = Interpreter::new(crate::eval, Vec::new());
+        rep(&interpreter, "(def! spin (fn* [] (spin)))");
+        rep(&interpreter, "(def! f (future (spin)))");
+
+        let handle = interpreter.interrupt_handle();
+        let interrupter = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            handle.interrupt();
+        });
+        assert!(rep(&interpreter, "@f").contains(":interrupted"));
+        interrupter.join().unwrap();
+        assert!(eventually(&interpreter, "(future-done? f)"));
+    }
+
+    #[test]
+    fn a_timeout_stops_the_wait_for_an_isolate_and_the_isolate() {
+        let path = std::env::temp_dir().join("mal-isolate-spin.mal");
+        std::fs::write(&path, "(def! spin (fn* [] (spin)))\n(spin)\n").unwrap();
+        let interpreter = Interpreter::new(crate::eval, Vec::new());
+        rep(&interpreter, "(def! w (atom nil))");
+
+        let path = path.display().to_string();
+        let spawn = format!("(reset! w (spawn-isolate {path:?}))");
+        let source = format!("(with-timeout 100 (fn* [] (do {spawn} (receive @w))))");
+        let source = format!("(try* {source} (catch* e (get e :type)))");
+        assert_eq!(rep(&interpreter, &source), ":timeout");
+        assert!(eventually(&interpreter, "(isolate-done? @w)"));
+    }
+
+    #[test]
+    fn receive_raises_the_error_that_stopped_an_isolate() {
+        let interpreter = Interpreter::new(crate::eval, Vec::new());
+        let spawn = "(def! w (spawn-isolate \"no-such-worker.mal\"))";
+        rep(&interpreter, spawn);
+        let res = rep(&interpreter, "(try* (receive w) (catch* e [:raised e]))");
+        let missing = "[:raised \"can't read no-such-worker.mal";
+        assert!(res.starts_with(missing), "{res}");
+        assert_eq!(rep(&interpreter, "(receive w)"), "nil");
+    }
+}
@@ -0,0 +1,536 @@
+//! The interpreter the `step8_macros` REPL and the `mal-lsp` and `mal-lint`
+//! tools share: the modules, and the tree-walking evaluator.
+
+#![feature(iter_array_chunks)]
+#![feature(let_chains)]
+#![feature(if_let_guard)]
+
+pub mod analyzer;
+pub mod compiler;
+pub mod core;
+pub mod cst;
+pub mod debugger;
+pub mod env;
+pub mod gc;
+pub mod interpreter;
+pub mod isolate;
+pub mod lint;
+pub mod lsp;
+pub mod namespace;
+pub mod pattern;
+pub mod pprint;
+pub mod printer;
+pub mod profile;
+pub mod reader;
+pub mod seq;
+pub mod stack;
+pub mod transducers;
+pub mod types;
+pub mod vm;
+
+use env::*;
+use indexmap::IndexSet;
+use interpreter::*;
+use printer::*;
+use reader::*;
+use std::{ops::Deref, rc::Rc};
+use types::*;
+
+pub fn read(line: &str) -> MalType {
+    read_str(line)
+}
+
+fn macroexpand(ast: MalType, env: &Env) -> MalType {
+    macroexpand_at(ast, env, None).0
+}
+
+/// Expands `ast`, read at `pos`, with where the forms of the expansion were
+/// read.
+fn macroexpand_at(
+    mut ast: MalType,
+    env: &Env,
+    mut pos: Option<Rc<Pos>>,
+) -> (MalType, Option<Rc<Pos>>) {
+    while is_macro_call(&ast, env) {
+        let MalType::List(l) = ast else {
+            panic!("{ast} must be a List")
+        };
+        let MalType::Symbol(s) = &l[0] else {
+            panic!("{} must be a Symbol", l[0])
+        };
+        let Some(f) = env_get(env, s) else {
+            panic!("{} must be a MalFunc", s)
+        };
+
+        let args = l.iter().skip(1).cloned().collect::<Vec<_>>();
+        ast = f.apply(args);
+        pos = pos.map(|pos| relocate(&ast, &MalType::List(l), &pos));
+    }
+
+    (ast, pos)
+}
+
+/// The index of the body of `(fn* params body)`, or of the one after the
+/// docstring in `(fn* params "doc" body)`.
+fn fn_body(list: &[MalType]) -> usize {
+    match list {
+        [_, _, MalType::String(_), _] => 3,
+        _ => 2,
+    }
+}
+
+fn is_macro_call(ast: &MalType, env: &Env) -> bool {
+    match ast {
+        MalType::List(l)
+            if !l.is_empty()
+                && let MalType::Symbol(s) = &l[0] =>
+        {
+            env_get(env, s).is_some_and(|f| f.is_macro())
+        }
+        _ => false,
+    }
+}
+
+/// Whether `symbol` is one like `foo#`, which a quasiquote replaces with a
+/// fresh symbol each time it is evaluated.
+fn is_auto_gensym(symbol: &str) -> bool {
+    symbol.len() > 1 && symbol.ends_with('#') && !symbol.starts_with(':')
+}
+
+/// A fresh symbol for the auto-gensym `foo#`, like `foo__12__auto__`. The
+/// `__auto__` keeps it apart from what `gensym` makes and from names people
+/// write, like `tmp__2`.
+fn auto_gensym(prefix: &str) -> MalType {
+    MalType::Symbol(format!("{prefix}__{}__auto__", core::gensym_number()))
+}
+
+/// `ast` with each auto-gensym `quasiquote` would quote replaced by a fresh
+/// symbol, the same one for all its uses, which is kept in `made`.
+fn auto_gensyms(ast: &MalType, made: &mut Vec<(String, MalType)>) -> MalType {
+    let is_form = |list: &[MalType], name: &str| {
+        !list.is_empty() && list[0] == MalType::Symbol(name.to_owned())
+    };
+    let mut items = |list: &[MalType]| -> Vec<MalType> {
+        list.iter()
+            .map(|elem| match elem {
+                MalType::List(elem_list) if is_form(elem_list, "splice-unquote") => elem.clone(),
+                _ => auto_gensyms(elem, made),
+            })
+            .collect()
+    };
+
+    match ast {
+        MalType::Symbol(s) if is_auto_gensym(s) => {
+            if let Some((_, symbol)) = made.iter().find(|(name, _)| name == s) {
+                return symbol.clone();
+            }
+            let symbol = auto_gensym(&s[..s.len() - 1]);
+            made.push((s.clone(), symbol.clone()));
+            symbol
+        }
+        MalType::List(list) if is_form(list, "unquote") => ast.clone(),
+        MalType::List(list) => MalType::List(items(list)),
+        MalType::Vector(list) => MalType::Vector(items(list)),
+        _ => ast.clone(),
+    }
+}
+
+/// Expands `(quasiquote ast)`. Each `foo#` in it becomes a fresh symbol, the
+/// same one for all the `foo#` in it, so every expansion of it, like every
+/// expansion of a macro, gets symbols of its own, whatever `gensym` is bound
+/// to.
+///
+/// The symbols it made are given too: the analyzer and the compiler expand a
+/// quasiquote once, and `freshen` what it evaluates to each time instead.
+fn quasiquote(ast: &MalType) -> (MalType, Vec<MalType>) {
+    let mut made = Vec::new();
+    let expanded = qq(&auto_gensyms(ast, &mut made));
+    let made = made.into_iter().map(|(_, symbol)| symbol).collect();
+    (expanded, made)
+}
+
+/// `value`, what an expansion of `quasiquote` evaluated to, with the symbols
+/// in `made` that the expansion made replaced by fresh ones.
+fn freshen(value: MalType, made: &[MalType]) -> MalType {
+    fn replace(value: MalType, fresh: &[(&MalType, MalType)]) -> MalType {
+        match value {
+            MalType::Symbol(_) => match fresh.iter().find(|(made, _)| **made == value) {
+                Some((_, symbol)) => symbol.clone(),
+                None => value,
+            },
+            MalType::List(items) => {
+                MalType::List(items.into_iter().map(|i| replace(i, fresh)).collect())
+            }
+            MalType::Vector(items) => {
+                MalType::Vector(items.into_iter().map(|i| replace(i, fresh)).collect())
+            }
+            value => value,
+        }
+    }
+
+    if made.is_empty() {
+        return value;
+    }
+    let fresh: Vec<_> = made
+        .iter()
+        .map(|symbol| {
+            let MalType::Symbol(name) = symbol else {
+                unreachable!()
+            };
+            let numbered = name.strip_suffix("__auto__").unwrap_or(name);
+            let prefix = &numbered[..numbered.rfind("__").unwrap_or(0)];
+            (symbol, auto_gensym(prefix))
+        })
+        .collect();
+    replace(value, &fresh)
+}
+
+fn qq(ast: &MalType) -> MalType {
+    match ast {
+        MalType::List(ast_list)
+            if !ast_list.is_empty() && ast_list[0] == MalType::Symbol("unquote".to_owned()) =>
+        {
+            ast_list[1].clone()
+        }
+        MalType::List(ast_list) => qq_iter(ast_list),
+        MalType::Vector(ast_vec) => {
+            MalType::List([MalType::Symbol("vec".to_owned()), qq_iter(ast_vec)].to_vec())
+        }
+        MalType::Dictionary(_) | MalType::Set(_) | MalType::Symbol(_) => {
+            MalType::List([MalType::Symbol("quote".to_owned()), ast.clone()].to_vec())
+        }
+        _ => ast.clone(),
+    }
+}
+
+fn qq_iter(ast_list: &[MalType]) -> MalType {
+    let mut res = Vec::new();
+
+    for elem in ast_list.iter().rev() {
+        match elem {
+            MalType::List(elem_list)
+                if !elem_list.is_empty()
+                    && elem_list[0] == MalType::Symbol("splice-unquote".to_owned()) =>
+            {
+                res = [
+                    MalType::Symbol("concat".to_owned()),
+                    elem_list[1].clone(),
+                    MalType::List(res),
+                ]
+                .to_vec();
+            }
+            _ => {
+                res = [
+                    MalType::Symbol("cons".to_owned()),
+                    qq(elem),
+                    MalType::List(res),
+                ]
+                .to_vec();
+            }
+        }
+    }
+
+    MalType::List(res)
+}
+
+pub fn eval(ast: MalType, env: Env) -> MalType {
+    let pos = take_pos(&ast);
+    eval_at(ast, env, pos)
+}
+
+/// Evaluates `ast`, read at `pos`.
+fn eval_at(ast: MalType, env: Env, pos: Option<Rc<Pos>>) -> MalType {
+    let scope = stack::enter();
+    let res = eval_in(ast, env, pos, &scope);
+    if res.is_error() {
+        stack::raised(&res, None, None);
+    }
+    res
+}
+
+fn eval_in(
+    mut ast: MalType,
+    mut env: Env,
+    mut pos: Option<Rc<Pos>>,
+    scope: &stack::Scope,
+) -> MalType {
+    let debugging = debugger::active();
+    loop {
+        if let Err(err) = tick() {
+            return err;
+        }
+        if debugging {
+            if let Err(err) = debugger::step(&ast, pos.as_ref(), &env) {
+                return err;
+            }
+        }
+
+        (ast, pos) = macroexpand_at(ast, &env, pos);
+        let at = |i: usize| pos.as_ref().and_then(|pos| pos.item(i)).cloned();
+        match ast {
+            MalType::List(ref list) if list.is_empty() => return ast,
+            MalType::List(ref list) => {
+                let first_elem = &list[0];
+
+                match first_elem {
+                    MalType::Symbol(macroexpand_symbol) if macroexpand_symbol.eq("macroexpand") => {
+                        return macroexpand(list[1].to_owned(), &env);
+                    }
+                    MalType::Symbol(quote_symbol) if quote_symbol.eq("quote") => {
+                        return list[1].to_owned();
+                    }
+                    MalType::Symbol(qqexpand_symbol) if qqexpand_symbol.eq("quasiquoteexpand") => {
+                        return quasiquote(&list[1]).0;
+                    }
+                    MalType::Symbol(quasiquote_symbol) if quasiquote_symbol.eq("quasiquote") => {
+                        ast = quasiquote(&list[1]).0;
+                        pos = None;
+                    }
+                    MalType::Symbol(eval_symbol) if eval_symbol.eq("eval") => {
+                        ast = eval_at(list[1].clone(), env.clone(), at(1));
+                        if ast.is_error() {
+                            return ast;
+                        }
+                        pos = take_pos(&ast);
+
+                        env = namespace::eval_env(&env);
+                    }
+                    MalType::Symbol(define_symbol) if define_symbol.eq("def!") => {
+                        let v = eval_ast(list[2].clone(), &env, at(2));
+
+                        if v.is_error() {
+                            return v;
+                        }
+
+                        env_set(&env, &list[1], v.clone());
+                        return v;
+                    }
+                    MalType::Symbol(define_symbol) if define_symbol.eq("defmacro!") => {
+                        let mut v = eval_ast(list[2].clone(), &env, at(2));
+
+                        if v.is_error() {
+                            return v;
+                        }
+
+                        if let MalType::MalFunc { is_macro, .. } = &mut v {
+                            *is_macro = true;
+                        } else {
+                            println!("Returned a non-MalFunc from evaluating {}", list[2]);
+                            return MalType::Nil;
+                        }
+
+                        env_set(&env, &list[1], v.clone());
+                        return v;
+                    }
+                    MalType::Symbol(let_symbol) if let_symbol.eq("let*") => {
+                        let new_env =
+                            env_bind(Some(env.clone()), list[1].clone(), list[2..].to_vec());
+
+                        let new_bindings = match &list[1] {
+                            MalType::List(l) => l,
+                            MalType::Vector(v) => v,
+                            _ => {
+                                println!(
+                                    "ERROR: first element `{}` in let* binding is not a List/Vector",
+                                    print_string(&list[1], true)
+                                );
+                                return MalType::Nil;
+                            }
+                        };
+
+                        let bindings = at(1);
+                        for (i, [s, v]) in new_bindings.iter().array_chunks::<2>().enumerate() {
+                            let v_pos = bindings.as_ref().and_then(|b| b.item(2 * i + 1)).cloned();
+                            let new_value = eval_at(v.clone(), new_env.clone(), v_pos);
+                            if new_value.is_error() {
+                                return new_value;
+                            }
+
+                            env_set(&new_env, s, new_value);
+                        }
+
+                        // tco
+                        env = new_env;
+                        pos = at(2);
+                        ast = list[2].clone();
+                    }
+                    MalType::Symbol(do_symbol) if do_symbol.eq("do") => {
+                        let Some((last, init)) = list[1..].split_last() else {
+                            return MalType::Nil;
+                        };
+
+                        for (i, item) in init.iter().enumerate() {
+                            let value = eval_ast(item.clone(), &env, at(i + 1));
+                            if value.is_error() {
+                                return value;
+                            }
+                        }
+
+                        // tco
+                        pos = at(list.len() - 1);
+                        ast = last.clone();
+                    }
+                    MalType::Symbol(if_symbol) if if_symbol.eq("if") => {
+                        let condition = eval_at(list[1].clone(), env.clone(), at(1));
+
+                        // tco
+                        let branch = match condition {
+                            MalType::Error(_) => return condition,
+                            MalType::Nil | MalType::False => {
+                                // check if there is an "else" clause
+                                if list.len() < 4 {
+                                    return MalType::Nil;
+                                }
+                                3
+                            }
+                            _ => 2,
+                        };
+                        pos = at(branch);
+                        ast = list[branch].clone();
+                    }
+                    MalType::Symbol(try_symbol) if try_symbol.eq("try*") => {
+                        let res = eval_at(list[1].clone(), env.clone(), at(1));
+
+                        match (res, list.get(2)) {
+                            (MalType::Error(thrown), Some(MalType::List(catch)))
+                                if catch.first() == Some(&MalType::Symbol("catch*".to_owned())) =>
+                            {
+                                stack::catch(&thrown);
+                                let catch_env = env_new(Some(env.clone()));
+                                env_set(&catch_env, &catch[1], *thrown);
+
+                                // tco
+                                env = catch_env;
+                                pos = at(2).and_then(|catch| catch.item(2).cloned());
+                                ast = catch[2].clone();
+                            }
+                            (res, _) => return res,
+                        }
+                    }
+                    MalType::Symbol(fn_symbol) if fn_symbol.eq("fn*") => {
+                        let body = fn_body(list);
+                        return MalType::MalFunc {
+                            params: Box::new(list[1].clone()),
+                            body: Box::new(list[body].clone()),
+                            env: Some(env.clone()),
+                            eval: crate::eval,
+                            is_macro: false,
+                            pos: at(body),
+                        };
+                    }
+                    _ => {
+                        // new list as a result of calling eval on each member
+                        let mut evaled_list = Vec::new();
+
+                        for (i, item) in list.iter().enumerate() {
+                            let value = eval_ast(item.clone(), &env, at(i));
+                            if value.is_error() {
+                                return value;
+                            }
+
+                            evaled_list.push(value);
+                        }
+
+                        match &evaled_list[0] {
+                            MalType::Func(func) => {
+                                let args = evaled_list.iter().skip(1).cloned().collect::<Vec<_>>();
+                                profile::enter_builtin(*func);
+                                let res = call_builtin(*func, args);
+                                profile::leave();
+                                if res.is_error() {
+                                    stack::raised(&res, Some(&ast), pos.as_ref());
+                                }
+                                return res;
+                            }
+                            MalType::MalFunc {
+                                params,
+                                body,
+                                env: func_env,
+                                pos: body_pos,
+                                ..
+                            } => {
+                                scope.apply(&ast, pos.as_ref());
+                                let args = evaled_list.iter().skip(1).cloned().collect::<Vec<_>>();
+                                pos = body_pos.clone();
+                                ast = body.deref().clone();
+                                let new_env =
+                                    env_bind(func_env.clone(), params.deref().clone(), args);
+                                env = new_env;
+                            }
+                            MalType::Compiled(_) | MalType::Native(_) | MalType::WithMeta(..) => {
+                                let args = evaled_list.iter().skip(1).cloned().collect::<Vec<_>>();
+                                let res = evaled_list[0].apply(args);
+                                if res.is_error() {
+                                    stack::raised(&res, Some(&ast), pos.as_ref());
+                                }
+                                return res;
+                            }
+                            _ => return MalType::Nil,
+                        };
+                    }
+                }
+            }
+            _ => return eval_ast(ast, &env, pos),
+        }
+    }
+}
+
+/// Evaluates `ast`, read at `pos`, which is not a special form.
+fn eval_ast(ast: MalType, env: &Env, pos: Option<Rc<Pos>>) -> MalType {
+    match ast {
+        MalType::Symbol(s) => {
+            // lookup symbol and return value or raise error
+            if let Some(item) = env_get(env, s.as_str()) {
+                item
+            } else {
+                unbound(&s)
+            }
+        }
+        MalType::Vector(vector) => {
+            let mut res = Vec::new();
+
+            for (i, item) in vector.into_iter().enumerate() {
+                let item_pos = pos.as_ref().and_then(|pos| pos.item(i)).cloned();
+                let value = eval_ast(item, env, item_pos);
+                if value.is_error() {
+                    return value;
+                }
+
+                res.push(value);
+            }
+
+            MalType::Vector(res)
+        }
+        MalType::Dictionary(dict) => {
+            let mut res = Vec::new();
+
+            for (i, item) in dict.into_iter().enumerate() {
+                let item_pos = pos.as_ref().and_then(|pos| pos.item(i)).cloned();
+                let value = eval_ast(item, env, item_pos);
+                if value.is_error() {
+                    return value;
+                }
+
+                res.push(value);
+            }
+
+            MalType::Dictionary(res)
+        }
+        MalType::Set(set) => {
+            let mut res = IndexSet::new();
+
+            for item in set {
+                let value = eval_ast(item, env, None);
+                if value.is_error() {
+                    return value;
+                }
+
+                res.insert(value);
+            }
+
+            MalType::Set(res)
+        }
+        MalType::List(_) => eval_at(ast, env.clone(), pos),
+        _ => ast,
+    }
+}
@@ -0,0 +1,703 @@
+//! `mal-lint [files...]` checks Mal source files without running them, and
+//! reports:
+//!
+//! - symbols that are neither bound locally, defined by the file or a file it
+//!   loads, nor builtins, which the evaluator would silently take as themselves;
+//! - special forms of the wrong shape, like a `let*` with an odd number of
+//!   forms in its bindings or an `if` with more than 3 arguments;
+//! - calls to builtins with the wrong number of arguments;
+//! - bindings and definitions shadowing a builtin or a special form;
+//! - `let*` bindings and `fn*` parameters that are never used, unless their
+//!   name starts with `_`.
+//!
+//! Macros are expanded before their expansion is checked, so the top-level
+//! `defmacro!` forms of a file, and of the files it loads with a literal path,
+//! are evaluated first, along with the `def!` forms defining a function with a
+//! literal `fn*`, which macros may call. Other `def!` forms are not run, only
+//! their names are taken as defined. All of this runs in a sandbox with no
+//! capabilities and a step budget, so a macro that loops forever is reported
+//! as failing to expand.
+//! The exit status is 1 if anything was reported.
+
+use std::{collections::HashSet, ops::Range, path::Path};
+
+use crate::{
+    core,
+    cst::{self, Node, NodeKind},
+    env::env_get,
+    interpreter::{Interpreter, Limits, Sandbox},
+    reader::{read_errors, read_node},
+    types::MalType,
+};
+
+const SPECIAL_FORMS: [&str; 14] = [
+    "def!",
+    "defmacro!",
+    "let*",
+    "fn*",
+    "do",
+    "if",
+    "quote",
+    "quasiquote",
+    "quasiquoteexpand",
+    "macroexpand",
+    "try*",
+    "catch*",
+    "eval",
+    "ns",
+];
+
+/// A form along with the span of the text it was read from, and the same for
+/// its elements. The forms a macro adds to its expansion have the span of its
+/// call.
+#[derive(Clone)]
+struct Form {
+    value: MalType,
+    span: Range<usize>,
+    items: Vec<Form>,
+}
+
+impl Form {
+    fn from_node(node: &Node) -> Form {
+        let value = read_node(node);
+        let items = match (&node.kind, &value) {
+            (NodeKind::Collection { items, .. }, _) => items.iter().map(Form::from_node).collect(),
+            // the metadata, then the form
+            (NodeKind::Prefix(_, forms), MalType::WithMeta(..)) => {
+                forms.iter().map(Form::from_node).collect()
+            }
+            // the symbol the reader macro stands for, like quote for ', then
+            // the form
+            (NodeKind::Prefix(prefix, forms), MalType::List(items)) => {
+                let symbol = Form {
+                    value: items[0].clone(),
+                    span: node.span.start..node.span.start + prefix.len(),
+                    items: Vec::new(),
+                };
+                std::iter::once(symbol)
+                    .chain(forms.iter().map(Form::from_node))
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+
+        Form {
+            value,
+            span: node.span.clone(),
+            items,
+        }
+    }
+
+    /// The form of a macro expansion, whose parts that were written in the
+    /// `call` keep their spans.
+    fn from_value(value: MalType, call: &Form) -> Form {
+        if let Some(form) = call.items.iter().find_map(|item| item.find(&value)) {
+            return form.clone();
+        }
+
+        let items = match &value {
+            MalType::List(items) | MalType::Vector(items) | MalType::Dictionary(items) => items
+                .iter()
+                .map(|item| Form::from_value(item.clone(), call))
+                .collect(),
+            MalType::Set(items) => items
+                .iter()
+                .map(|item| Form::from_value(item.clone(), call))
+                .collect(),
+            MalType::WithMeta(value, meta) => vec![
+                Form::from_value((**meta).clone(), call),
+                Form::from_value((**value).clone(), call),
+            ],
+            _ => Vec::new(),
+        };
+
+        Form {
+            value,
+            span: call.span.clone(),
+            items,
+        }
+    }
+
+    /// This form or the first of its parts that is `value`.
+    fn find(&self, value: &MalType) -> Option<&Form> {
+        match self.value == *value {
+            true => Some(self),
+            false => self.items.iter().find_map(|item| item.find(value)),
+        }
+    }
+
+    fn symbol(&self) -> Option<&str> {
+        match &self.value {
+            MalType::Symbol(name) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+struct Binding {
+    name: String,
+    span: Range<usize>,
+    used: bool,
+    /// Whether it is reported when unused, which it is not when a macro
+    /// introduced it.
+    reported: bool,
+    /// Whether its value is evaluated yet, which a `let*` binding is not
+    /// before its form. Functions defined before that can still use it.
+    bound: bool,
+    /// How many `fn*` bodies it is in.
+    depth: usize,
+}
+
+struct Report {
+    span: Range<usize>,
+    level: &'static str,
+    message: String,
+}
+
+struct Linter {
+    interpreter: Interpreter,
+    /// The builtins and the globals of the prelude.
+    core: HashSet<String>,
+    /// The names defined by the file and the files it loads.
+    globals: HashSet<String>,
+    /// The files loaded so far, so as to load each once.
+    loaded: HashSet<String>,
+    scopes: Vec<Vec<Binding>>,
+    /// How many macro expansions are being checked.
+    expansions: usize,
+    /// How many `fn*` bodies are being checked.
+    depth: usize,
+    reports: Vec<Report>,
+}
+
+/// `min` to `max` arguments, in words.
+fn arity(min: usize, max: Option<usize>) -> String {
+    match max {
+        Some(1) if min == 1 => "1 argument".to_owned(),
+        Some(max) if max == min => format!("{min} arguments"),
+        Some(max) if max == min + 1 => format!("{min} or {max} arguments"),
+        Some(max) => format!("{min} to {max} arguments"),
+        None => format!("{min} or more arguments"),
+    }
+}
+
+/// The names that `def!` and `defmacro!` forms in `value` define.
+fn definitions(value: &MalType, names: &mut HashSet<String>) {
+    let (MalType::List(items) | MalType::Vector(items) | MalType::Dictionary(items)) = value else {
+        return;
+    };
+
+    if let [MalType::Symbol(head), MalType::Symbol(name), ..] = items.as_slice() {
+        if head == "def!" || head == "defmacro!" {
+            names.insert(name.clone());
+        }
+    }
+    for item in items {
+        definitions(item, names);
+    }
+}
+
+/// Whether `value` is a `defmacro!` form, or a `def!` form defining a
+/// function with a literal `fn*`, which is safe to evaluate.
+fn is_definition(value: &MalType) -> bool {
+    let MalType::List(items) = value else {
+        return false;
+    };
+    match items.as_slice() {
+        [MalType::Symbol(head), ..] if head == "defmacro!" => true,
+        [MalType::Symbol(head), _, MalType::List(value)] if head == "def!" => {
+            matches!(value.first(), Some(MalType::Symbol(f)) if f == "fn*")
+        }
+        _ => false,
+    }
+}
+
+/// Evaluation steps each definition and macro expansion may take.
+const FUEL: u64 = 1_000_000;
+
+/// The interpreter evaluating the definitions of a file and expanding its
+/// macros, which can neither reach outside of it nor run for long.
+fn sandbox() -> Interpreter {
+    let sandbox = Sandbox {
+        limits: Limits {
+            fuel: Some(FUEL),
+            max_size: Some(1_000_000),
+        },
+        capabilities: Vec::new(),
+    };
+    Interpreter::sandboxed(crate::eval, sandbox)
+}
+
+/// The path of the file `(load-file path)` or `(load-file-once path)` loads.
+fn loaded_file(value: &MalType) -> Option<&str> {
+    match value {
+        MalType::List(items) => match items.as_slice() {
+            [MalType::Symbol(head), MalType::String(path)]
+                if head == "load-file" || head == "load-file-once" =>
+            {
+                Some(path)
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+impl Linter {
+    fn report(&mut self, span: &Range<usize>, level: &'static str, message: String) {
+        self.reports.push(Report {
+            span: span.clone(),
+            level,
+            message,
+        });
+    }
+
+    /// Evaluates the definitions of `source`, a file in `dir`, and of the
+    /// files it loads, and adds their names to the globals.
+    fn prepare(&mut self, source: &str, dir: &Path) {
+        let (cst, _) = cst::parse_lossless(source);
+        for node in &cst.nodes {
+            let value = read_node(node);
+            if let Some(path) = loaded_file(&value) {
+                self.load(path, dir);
+            }
+
+            definitions(&value, &mut self.globals);
+            if is_definition(&value) {
+                self.interpreter.eval(value);
+            }
+        }
+    }
+
+    /// Prepares the file at `path`, relative to `dir` or else to the current
+    /// directory.
+    fn load(&mut self, path: &str, dir: &Path) {
+        let path = match dir.join(path) {
+            joined if joined.exists() => joined,
+            _ => Path::new(path).to_path_buf(),
+        };
+        if !self.loaded.insert(path.to_string_lossy().into_owned()) {
+            return;
+        }
+
+        if let Ok(source) = std::fs::read_to_string(&path) {
+            let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+            self.prepare(&source, &dir);
+        }
+    }
+
+    fn is_local(&self, name: &str) -> bool {
+        self.scopes.iter().flatten().any(|b| b.name == name)
+    }
+
+    fn lookup(&self, name: &str) -> Option<MalType> {
+        let env = self.interpreter.namespaces.current().env.clone();
+        self.interpreter.namespaces.enter(|| env_get(&env, name))
+    }
+
+    fn resolve(&mut self, name: &str, span: &Range<usize>) {
+        if name.starts_with(':') || name == "&" {
+            return;
+        }
+
+        let depth = self.depth;
+        let local = self
+            .scopes
+            .iter_mut()
+            .rev()
+            .flat_map(|scope| scope.iter_mut().rev())
+            .find(|b| b.name == name && (b.bound || b.depth < depth));
+        if let Some(binding) = local {
+            binding.used = true;
+            return;
+        }
+
+        if self.globals.contains(name)
+            || self.core.contains(name)
+            || SPECIAL_FORMS.contains(&name)
+            || self.lookup(name).is_some()
+        {
+            return;
+        }
+        self.report(span, "error", format!("unresolved symbol '{name}'"));
+    }
+
+    /// Binds `form` in the innermost scope, from `what` like `let*`.
+    fn bind(&mut self, form: &Form, what: &str) {
+        let Some(name) = form.symbol() else {
+            let message = format!("{what} binds {}, not a symbol", form.value);
+            return self.report(&form.span, "error", message);
+        };
+
+        let reported = self.expansions == 0;
+        if reported && (self.core.contains(name) || SPECIAL_FORMS.contains(&name)) {
+            let message = format!("'{name}' shadows a core name");
+            self.report(&form.span, "warning", message);
+        }
+
+        let binding = Binding {
+            name: name.to_owned(),
+            span: form.span.clone(),
+            used: false,
+            reported,
+            bound: true,
+            depth: self.depth,
+        };
+        self.scopes.last_mut().unwrap().push(binding);
+    }
+
+    fn pop_scope(&mut self) {
+        for binding in self.scopes.pop().unwrap_or_default() {
+            if binding.reported && !binding.used && !binding.name.starts_with('_') {
+                let message = format!("unused binding '{}'", binding.name);
+                self.report(&binding.span, "warning", message);
+            }
+        }
+    }
+
+    /// Reports a special form with fewer than `min` or more than `max`
+    /// arguments, and returns whether it has the right number.
+    fn shape(&mut self, form: &Form, name: &str, min: usize, max: Option<usize>) -> bool {
+        let count = form.items.len() - 1;
+        if count < min || max.is_some_and(|max| count > max) {
+            let message = format!("'{name}' takes {}, got {count}", arity(min, max));
+            self.report(&form.span, "error", message);
+            return false;
+        }
+        true
+    }
+
+    fn walk(&mut self, form: &Form) {
+        match &form.value {
+            MalType::Symbol(name) => self.resolve(name, &form.span),
+            MalType::List(items) if !items.is_empty() => self.list(form),
+            _ => {
+                for item in &form.items {
+                    self.walk(item);
+                }
+            }
+        }
+    }
+
+    fn walk_all(&mut self, forms: &[Form]) {
+        for form in forms {
+            self.walk(form);
+        }
+    }
+
+    /// The parts of a quasiquoted form that are unquoted.
+    fn walk_quasiquoted(&mut self, form: &Form) {
+        match form.items.first().and_then(Form::symbol) {
+            Some("unquote" | "splice-unquote") => self.walk_all(&form.items[1..]),
+            _ if matches!(form.value, MalType::WithMeta(..)) => {}
+            _ => {
+                for item in &form.items {
+                    self.walk_quasiquoted(item);
+                }
+            }
+        }
+    }
+
+    fn list(&mut self, form: &Form) {
+        let head = match form.items[0].symbol() {
+            Some(head) if !self.is_local(head) => head.to_owned(),
+            _ => return self.walk_all(&form.items),
+        };
+        let args = &form.items[1..];
+
+        match head.as_str() {
+            "def!" | "defmacro!" => {
+                if self.shape(form, &head, 2, Some(2)) {
+                    match args[0].symbol() {
+                        Some(name) if self.core.contains(name) && self.expansions == 0 => {
+                            let message = format!("'{name}' redefines a core name");
+                            self.report(&args[0].span, "warning", message);
+                        }
+                        Some(_) => {}
+                        None => {
+                            let message =
+                                format!("'{head}' defines {}, not a symbol", args[0].value);
+                            self.report(&args[0].span, "error", message);
+                        }
+                    }
+                    self.walk(&args[1]);
+                }
+            }
+            "let*" => {
+                if !self.shape(form, &head, 2, Some(2)) {
+                    return;
+                }
+                let bindings = &args[0];
+                if !matches!(bindings.value, MalType::List(_) | MalType::Vector(_)) {
+                    let message = format!("'let*' takes a binding vector, not {}", bindings.value);
+                    return self.report(&bindings.span, "error", message);
+                }
+                if !bindings.items.len().is_multiple_of(2) {
+                    let message = format!(
+                        "'let*' bindings need an even number of forms, got {}",
+                        bindings.items.len()
+                    );
+                    self.report(&bindings.span, "error", message);
+                }
+
+                // the functions in the bindings can use the later ones
+                self.scopes.push(Vec::new());
+                let pairs = bindings.items.chunks_exact(2);
+                for pair in pairs.clone() {
+                    self.bind(&pair[0], "'let*'");
+                }
+                for binding in self.scopes.last_mut().unwrap() {
+                    binding.bound = false;
+                }
+
+                let mut bound = 0;
+                for pair in pairs {
+                    self.walk(&pair[1]);
+                    if pair[0].symbol().is_some() {
+                        self.scopes.last_mut().unwrap()[bound].bound = true;
+                        bound += 1;
+                    }
+                }
+                self.walk(&args[1]);
+                self.pop_scope();
+            }
+            "fn*" => {
+                if !self.shape(form, &head, 2, Some(3)) {
+                    return;
+                }
+                if args.len() == 3 && !matches!(args[1].value, MalType::String(_)) {
+                    let message = "'fn*' takes one body, after an optional docstring".to_owned();
+                    return self.report(&form.span, "error", message);
+                }
+                let params = &args[0];
+                if !matches!(params.value, MalType::List(_) | MalType::Vector(_)) {
+                    let message = format!("'fn*' takes a parameter vector, not {}", params.value);
+                    return self.report(&params.span, "error", message);
+                }
+
+                self.scopes.push(Vec::new());
+                let names: Vec<&Form> = params
+                    .items
+                    .iter()
+                    .filter(|p| p.symbol() != Some("&"))
+                    .collect();
+                let rest = params.items.iter().position(|p| p.symbol() == Some("&"));
+                if rest.is_some_and(|i| i + 2 != params.items.len()) {
+                    let message = "'&' must be followed by exactly one parameter".to_owned();
+                    self.report(&params.span, "error", message);
+                }
+                for name in names {
+                    self.bind(name, "'fn*'");
+                }
+                self.depth += 1;
+                self.walk(args.last().unwrap());
+                self.depth -= 1;
+                self.pop_scope();
+            }
+            "if" => {
+                self.shape(form, &head, 2, Some(3));
+                self.walk_all(args);
+            }
+            "do" => self.walk_all(args),
+            "quote" | "macroexpand" => {
+                self.shape(form, &head, 1, Some(1));
+            }
+            "quasiquote" | "quasiquoteexpand" => {
+                if self.shape(form, &head, 1, Some(1)) {
+                    self.walk_quasiquoted(&args[0]);
+                }
+            }
+            "eval" => {
+                self.shape(form, &head, 1, Some(1));
+                self.walk_all(args);
+            }
+            "try*" => {
+                if !self.shape(form, &head, 1, Some(2)) {
+                    return;
+                }
+                self.walk(&args[0]);
+
+                let Some(catch) = args.get(1) else {
+                    return;
+                };
+                match catch.items.as_slice() {
+                    [head, binding, body] if head.symbol() == Some("catch*") => {
+                        self.scopes.push(Vec::new());
+                        self.bind(binding, "'catch*'");
+                        // the error is often not needed
+                        if let Some(binding) = self.scopes.last_mut().unwrap().last_mut() {
+                            binding.reported = false;
+                        }
+                        self.walk(body);
+                        self.pop_scope();
+                    }
+                    _ => {
+                        let message = "'try*' takes a (catch* symbol body) form".to_owned();
+                        self.report(&catch.span, "error", message);
+                    }
+                }
+            }
+            "catch*" => {
+                let message = "'catch*' is only allowed at the end of 'try*'".to_owned();
+                self.report(&form.span, "error", message);
+            }
+            "ns" => {}
+            _ if self.lookup(&head).is_some_and(|f| f.is_macro()) => self.expand(form),
+            _ => {
+                self.call(&head, form);
+                self.walk_all(&form.items);
+            }
+        }
+    }
+
+    /// Checks the arity of a call to a builtin.
+    fn call(&mut self, head: &str, form: &Form) {
+        // a builtin redefined by the file may take other arguments
+        if self.globals.contains(head) {
+            return;
+        }
+
+        let count = form.items.len() - 1;
+        if let Some((min, max)) = core::arity(head) {
+            if count < min || max.is_some_and(|max| count > max) {
+                let message = format!("'{head}' takes {}, got {count}", arity(min, max));
+                self.report(&form.span, "error", message);
+            }
+        }
+    }
+
+    fn expand(&mut self, form: &Form) {
+        let call = MalType::List(vec![
+            MalType::Symbol("macroexpand".to_owned()),
+            form.value.clone(),
+        ]);
+        match self.interpreter.eval(call) {
+            MalType::Error(err) => {
+                let message = format!("expanding this macro failed: {err}");
+                self.report(&form.span, "error", message);
+            }
+            expansion => {
+                self.expansions += 1;
+                self.walk(&Form::from_value(expansion, form));
+                self.expansions -= 1;
+            }
+        }
+    }
+}
+
+/// The reports for the file at `path`, sorted by position, or the error
+/// reading it.
+fn lint(interpreter: Interpreter, path: &str) -> Result<(String, Vec<Report>), String> {
+    let source = std::fs::read_to_string(path).map_err(|err| format!("{path}: {err}"))?;
+
+    // with the builtins the sandbox leaves out, like `slurp`
+    let core = {
+        let mut names: HashSet<String> = core::core_env().data.borrow().keys().cloned().collect();
+        let mut env = Some(interpreter.namespaces.current().env.clone());
+        while let Some(e) = env {
+            names.extend(e.data.borrow().keys().cloned());
+            env = e.outer.clone();
+        }
+        names
+    };
+    let mut linter = Linter {
+        interpreter,
+        core,
+        globals: HashSet::new(),
+        loaded: HashSet::new(),
+        scopes: Vec::new(),
+        expansions: 0,
+        depth: 0,
+        reports: Vec::new(),
+    };
+
+    let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+    linter.loaded.insert(path.to_owned());
+    linter.prepare(&source, dir);
+
+    let (cst, errors) = cst::parse_lossless(&source);
+    for err in errors {
+        let span = err.offset..err.offset;
+        linter.report(&span, "error", err.message);
+    }
+    for node in &cst.nodes {
+        let mut errors = Vec::new();
+        read_errors(node, &mut errors);
+        if errors.is_empty() && !matches!(node.kind, NodeKind::Error(_)) {
+            linter.walk(&Form::from_node(node));
+        }
+        for (span, message) in errors {
+            linter.report(&span, "error", message);
+        }
+    }
+
+    let mut reports = linter.reports;
+    reports.sort_by_key(|r| r.span.start);
+    // a macro may expand to the same mistake many times at its call
+    reports.dedup_by(|a, b| a.span == b.span && a.message == b.message);
+    Ok((source, reports))
+}
+
+/// Lints `files`, and returns the exit status.
+pub fn run(files: &[String]) -> i32 {
+    let mut status = 0;
+    for file in files {
+        match lint(sandbox(), file) {
+            Ok((source, reports)) => {
+                for report in &reports {
+                    let err = cst::ParseError {
+                        message: String::new(),
+                        offset: report.span.start,
+                    };
+                    let (line, col) = err.line_col(&source);
+                    println!("{file}:{line}:{col}: {}: {}", report.level, report.message);
+                }
+                if !reports.is_empty() {
+                    status = status.max(1);
+                }
+            }
+            Err(err) => {
+                eprintln!("{err}");
+                status = 2;
+            }
+        }
+    }
+
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The messages reported for `source`, saved as `name`.
+    fn messages(name: &str, source: &str) -> Vec<String> {
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, source).unwrap();
+        let (_, reports) = lint(sandbox(), &path.to_string_lossy()).unwrap();
+        reports.into_iter().map(|r| r.message).collect()
+    }
+
+    #[test]
+    fn does_not_run_definitions_that_are_not_functions() {
+        let source = "(def! spin (fn* [] (spin)))\n(def! y (spin))\n(def! z (slurp y))\n";
+        assert!(messages("lint-spin.mal", source).is_empty());
+    }
+
+    #[test]
+    fn reports_a_macro_that_does_not_expand_in_time() {
+        let source = "(def! spin (fn* [] (spin)))\n(defmacro! m (fn* [] (spin)))\n(m)\n";
+        let reports = messages("lint-macro-spin.mal", source);
+        assert_eq!(reports.len(), 1);
+        assert!(reports[0].starts_with("expanding this macro failed"));
+    }
+
+    #[test]
+    fn expands_macros_calling_functions_of_the_file() {
+        let source = "(def! twice (fn* [x] (list 'do x x)))\n(defmacro! m (fn* [x] (twice x)))\n(m undefined)\n";
+        assert_eq!(
+            messages("lint-expand.mal", source),
+            ["unresolved symbol 'undefined'"]
+        );
+    }
+}
@@ -0,0 +1,615 @@
+//! A language server for Mal, speaking JSON-RPC over standard input and output,
+//! run as `mal-lsp`.
+//!
+//! Open documents are parsed again on every change, with the lossless parse of
+//! `cst.rs` and the atoms read by `reader.rs`, for:
+//!
+//! - diagnostics: unbalanced delimiters, unterminated strings, invalid regexes
+//!   and dictionaries with an odd number of forms;
+//! - go-to-definition, hover and document symbols for the `def!` and
+//!   `defmacro!` forms of the open documents;
+//! - completion from the special forms, the builtins of `core_env` and those
+//!   definitions.
+//!
+//! The documentation of a definition is the string starting the body of its
+//! `fn*`, or else the comments on the lines right above it.
+
+use std::{collections::HashMap, error::Error, ops::Range};
+
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::{
+    notification::{
+        DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument,
+        Notification as LspNotification, PublishDiagnostics,
+    },
+    request::{
+        Completion, DocumentSymbolRequest, GotoDefinition, HoverRequest, Request as LspRequest,
+    },
+    CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams, CompletionResponse,
+    Diagnostic, DiagnosticSeverity, DocumentSymbol, DocumentSymbolParams, DocumentSymbolResponse,
+    GotoDefinitionParams, GotoDefinitionResponse, Hover, HoverContents, HoverParams,
+    HoverProviderCapability, Location, MarkupContent, MarkupKind, OneOf, Position,
+    PublishDiagnosticsParams, ServerCapabilities, SymbolKind, TextDocumentPositionParams,
+    TextDocumentSyncCapability, TextDocumentSyncKind, Uri,
+};
+use serde_json::Value;
+
+use crate::{
+    core::core_env,
+    cst::{self, Cst, Node, NodeKind, Trivia},
+    reader::{read_errors, read_node},
+    types::MalType,
+};
+
+const SPECIAL_FORMS: [&str; 14] = [
+    "def!",
+    "defmacro!",
+    "let*",
+    "fn*",
+    "do",
+    "if",
+    "quote",
+    "quasiquote",
+    "quasiquoteexpand",
+    "macroexpand",
+    "try*",
+    "catch*",
+    "eval",
+    "ns",
+];
+
+/// A `def!` or `defmacro!` form.
+struct Definition {
+    name: String,
+    is_macro: bool,
+    /// The parameters of the `fn*` it defines, as written.
+    params: Option<String>,
+    doc: Option<String>,
+    span: Range<usize>,
+    name_span: Range<usize>,
+}
+
+impl Definition {
+    /// The signature and documentation, in Markdown.
+    fn markdown(&self) -> String {
+        let head = if self.is_macro { "defmacro!" } else { "def!" };
+        let signature = match &self.params {
+            Some(params) => format!("({head} {} {params})", self.name),
+            None => format!("({head} {})", self.name),
+        };
+        match &self.doc {
+            Some(doc) => format!("```mal\n{signature}\n```\n\n{doc}"),
+            None => format!("```mal\n{signature}\n```"),
+        }
+    }
+}
+
+struct Document {
+    uri: Uri,
+    source: String,
+    cst: Cst,
+    diagnostics: Vec<Diagnostic>,
+    definitions: Vec<Definition>,
+}
+
+impl Document {
+    fn new(uri: Uri, source: String) -> Document {
+        let (cst, errors) = cst::parse_lossless(&source);
+
+        let mut diagnostics: Vec<Diagnostic> = errors
+            .iter()
+            .map(|err| {
+                let len = source[err.offset..]
+                    .chars()
+                    .next()
+                    .map_or(0, char::len_utf8);
+                diagnostic(&source, &(err.offset..err.offset + len), &err.message)
+            })
+            .collect();
+        let mut definitions = Vec::new();
+        let mut errors = Vec::new();
+        for node in &cst.nodes {
+            read_errors(node, &mut errors);
+            define(&source, node, &mut definitions);
+        }
+        for (span, message) in errors {
+            diagnostics.push(diagnostic(&source, &span, &message));
+        }
+
+        Document {
+            uri,
+            source,
+            cst,
+            diagnostics,
+            definitions,
+        }
+    }
+
+    fn offset(&self, position: Position) -> usize {
+        let mut start = 0;
+        for _ in 0..position.line {
+            match self.source[start..].find('\n') {
+                Some(i) => start += i + 1,
+                None => return self.source.len(),
+            }
+        }
+
+        let mut units = 0;
+        for (i, c) in self.source[start..].char_indices() {
+            if units >= position.character || c == '\n' {
+                return start + i;
+            }
+            units += c.len_utf16() as u32;
+        }
+        self.source.len()
+    }
+
+    fn range(&self, span: &Range<usize>) -> lsp_types::Range {
+        range(&self.source, span)
+    }
+
+    fn location(&self, span: &Range<usize>) -> Location {
+        Location::new(self.uri.clone(), self.range(span))
+    }
+
+    /// The symbol under `position`, or the one ending right before it.
+    fn symbol_at(&self, position: Position) -> Option<(&str, Range<usize>)> {
+        let offset = self.offset(position);
+        let mut nodes = &self.cst.nodes;
+        loop {
+            let node = nodes
+                .iter()
+                .find(|node| node.span.start <= offset && offset <= node.span.end)?;
+            match &node.kind {
+                NodeKind::Atom(text) => return Some((text, node.span.clone())),
+                NodeKind::Prefix(_, forms) => nodes = forms,
+                NodeKind::Collection { items, .. } => nodes = items,
+                NodeKind::Error(_) => return None,
+            }
+        }
+    }
+}
+
+/// The LSP position of a byte offset, with the character counted in UTF-16
+/// code units.
+fn position(source: &str, offset: usize) -> Position {
+    let before = &source[..offset];
+    let start = before.rfind('\n').map_or(0, |i| i + 1);
+    Position::new(
+        before.matches('\n').count() as u32,
+        before[start..].encode_utf16().count() as u32,
+    )
+}
+
+fn range(source: &str, span: &Range<usize>) -> lsp_types::Range {
+    lsp_types::Range::new(position(source, span.start), position(source, span.end))
+}
+
+fn diagnostic(source: &str, span: &Range<usize>, message: &str) -> Diagnostic {
+    Diagnostic {
+        range: range(source, span),
+        severity: Some(DiagnosticSeverity::ERROR),
+        source: Some("mal".to_owned()),
+        message: message.to_owned(),
+        ..Default::default()
+    }
+}
+
+/// The text of the comments on their own lines right above `node`.
+fn comments_above(source: &str, node: &Node) -> Option<String> {
+    let mut lines = Vec::new();
+    let mut newlines = 0;
+    let mut offset = node.span.start;
+    for trivia in node.leading.iter().rev() {
+        offset -= trivia.text().len();
+        match trivia {
+            Trivia::Whitespace(_) => {}
+            Trivia::Newline(_) => {
+                newlines += 1;
+                // a blank line ends the comments
+                if newlines > lines.len() + 1 {
+                    break;
+                }
+            }
+            Trivia::Comment(comment) => {
+                let line = source[..offset].rsplit('\n').next().unwrap_or("");
+                if !line.trim_matches([' ', '\t', ',']).is_empty() {
+                    break;
+                }
+                lines.push(comment.trim_start_matches(';').trim().to_owned());
+            }
+        }
+    }
+
+    lines.reverse();
+    (!lines.is_empty()).then(|| lines.join("\n"))
+}
+
+/// The items of `node` if it is a list whose head is the symbol `head`.
+fn call<'a>(node: &'a Node, head: &[&str]) -> Option<&'a [Node]> {
+    match &node.kind {
+        NodeKind::Collection { open, items, .. } if open == "(" => match items.first() {
+            Some(Node {
+                kind: NodeKind::Atom(symbol),
+                ..
+            }) if head.contains(&symbol.as_str()) => Some(items),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The definition made by `node`, if it is a `def!` or `defmacro!` form.
+fn definition(source: &str, node: &Node) -> Option<Definition> {
+    let [head, name, rest @ ..] = call(node, &["def!", "defmacro!"])? else {
+        return None;
+    };
+    let NodeKind::Atom(symbol) = &name.kind else {
+        return None;
+    };
+
+    let function = rest.first().and_then(|value| call(value, &["fn*"]));
+    let params = function
+        .and_then(|items| items.get(1))
+        .map(|params| params.kind.to_string());
+    // a string is only a docstring when there is a body after it, which is
+    // how the evaluators tell them apart
+    let docstring = match function.and_then(|items| items.get(2..)) {
+        Some([doc, _]) => match (&doc.kind, read_node(doc)) {
+            (NodeKind::Atom(_), MalType::String(doc)) => Some(doc),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    Some(Definition {
+        name: symbol.clone(),
+        is_macro: head.kind == NodeKind::Atom("defmacro!".to_owned()),
+        params,
+        doc: docstring.or_else(|| comments_above(source, node)),
+        span: node.span.clone(),
+        name_span: name.span.clone(),
+    })
+}
+
+/// Adds the `def!` and `defmacro!` forms in `node` to `definitions`.
+fn define(source: &str, node: &Node, definitions: &mut Vec<Definition>) {
+    definitions.extend(definition(source, node));
+
+    let items = match &node.kind {
+        NodeKind::Prefix(_, forms) => forms,
+        NodeKind::Collection { items, .. } => items,
+        _ => return,
+    };
+    for item in items {
+        define(source, item, definitions);
+    }
+}
+
+struct Server {
+    documents: HashMap<String, Document>,
+    builtins: Vec<String>,
+}
+
+impl Server {
+    fn document(&self, uri: &Uri) -> Option<&Document> {
+        self.documents.get(uri.as_str())
+    }
+
+    /// The definitions named `name`, those of `uri` first.
+    fn definitions<'a>(
+        &'a self,
+        uri: &'a Uri,
+        name: &'a str,
+    ) -> impl Iterator<Item = (&'a Document, &'a Definition)> {
+        let current = self.document(uri).into_iter();
+        let others = self.documents.values().filter(move |doc| doc.uri != *uri);
+        current
+            .chain(others)
+            .flat_map(|doc| doc.definitions.iter().map(move |def| (doc, def)))
+            .filter(move |(_, def)| def.name == name)
+    }
+
+    fn symbol_at(
+        &self,
+        params: &TextDocumentPositionParams,
+    ) -> Option<(&Document, &str, Range<usize>)> {
+        let doc = self.document(&params.text_document.uri)?;
+        let (symbol, span) = doc.symbol_at(params.position)?;
+        Some((doc, symbol, span))
+    }
+
+    fn definition(&self, params: GotoDefinitionParams) -> Option<GotoDefinitionResponse> {
+        let params = params.text_document_position_params;
+        let (_, symbol, _) = self.symbol_at(&params)?;
+        let locations: Vec<Location> = self
+            .definitions(&params.text_document.uri, symbol)
+            .map(|(doc, def)| doc.location(&def.name_span))
+            .collect();
+        (!locations.is_empty()).then_some(GotoDefinitionResponse::Array(locations))
+    }
+
+    fn hover(&self, params: HoverParams) -> Option<Hover> {
+        let params = params.text_document_position_params;
+        let (doc, symbol, span) = self.symbol_at(&params)?;
+
+        let value = match self.definitions(&params.text_document.uri, symbol).next() {
+            Some((_, def)) => def.markdown(),
+            None if SPECIAL_FORMS.contains(&symbol) => format!("`{symbol}`: special form"),
+            None if self.builtins.iter().any(|b| b == symbol) => {
+                format!("`{symbol}`: builtin function")
+            }
+            None => return None,
+        };
+
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value,
+            }),
+            range: Some(doc.range(&span)),
+        })
+    }
+
+    fn completion(&self, params: CompletionParams) -> Option<CompletionResponse> {
+        let params = params.text_document_position;
+        let doc = self.document(&params.text_document.uri)?;
+        let offset = doc.offset(params.position);
+        let prefix = match doc.symbol_at(params.position) {
+            Some((_, span)) => &doc.source[span.start..offset],
+            None => "",
+        };
+
+        let mut items: Vec<CompletionItem> = Vec::new();
+        let mut add = |item: CompletionItem| {
+            if item.label.starts_with(prefix) && !items.iter().any(|i| i.label == item.label) {
+                items.push(item);
+            }
+        };
+
+        let definitions = self.documents.values().flat_map(|doc| &doc.definitions);
+        for def in definitions {
+            add(CompletionItem {
+                label: def.name.clone(),
+                kind: Some(match def.params {
+                    Some(_) => CompletionItemKind::FUNCTION,
+                    None => CompletionItemKind::VARIABLE,
+                }),
+                detail: def.params.clone(),
+                documentation: def.doc.clone().map(lsp_types::Documentation::String),
+                ..Default::default()
+            });
+        }
+        for form in SPECIAL_FORMS {
+            add(CompletionItem {
+                label: form.to_owned(),
+                kind: Some(CompletionItemKind::KEYWORD),
+                detail: Some("special form".to_owned()),
+                ..Default::default()
+            });
+        }
+        for builtin in &self.builtins {
+            add(CompletionItem {
+                label: builtin.clone(),
+                kind: Some(CompletionItemKind::FUNCTION),
+                detail: Some("builtin".to_owned()),
+                ..Default::default()
+            });
+        }
+
+        Some(CompletionResponse::Array(items))
+    }
+
+    fn document_symbols(&self, params: DocumentSymbolParams) -> Option<DocumentSymbolResponse> {
+        let doc = self.document(&params.text_document.uri)?;
+        let symbols = doc
+            .definitions
+            .iter()
+            .map(|def| {
+                #[allow(deprecated)]
+                DocumentSymbol {
+                    name: def.name.clone(),
+                    detail: def.is_macro.then(|| "macro".to_owned()),
+                    kind: match def.params {
+                        Some(_) => SymbolKind::FUNCTION,
+                        None => SymbolKind::VARIABLE,
+                    },
+                    tags: None,
+                    deprecated: None,
+                    range: doc.range(&def.span),
+                    selection_range: doc.range(&def.name_span),
+                    children: None,
+                }
+            })
+            .collect();
+        Some(DocumentSymbolResponse::Nested(symbols))
+    }
+
+    fn handle<R: LspRequest>(
+        &self,
+        params: Value,
+        handler: fn(&Self, R::Params) -> R::Result,
+    ) -> Result<Value, serde_json::Error> {
+        serde_json::to_value(handler(self, serde_json::from_value(params)?))
+    }
+
+    fn request(&self, req: Request) -> Response {
+        let result = match req.method.as_str() {
+            GotoDefinition::METHOD => self.handle::<GotoDefinition>(req.params, Self::definition),
+            HoverRequest::METHOD => self.handle::<HoverRequest>(req.params, Self::hover),
+            Completion::METHOD => self.handle::<Completion>(req.params, Self::completion),
+            DocumentSymbolRequest::METHOD => {
+                self.handle::<DocumentSymbolRequest>(req.params, Self::document_symbols)
+            }
+            method => {
+                let message = format!("unknown method {method}");
+                return Response::new_err(req.id, ErrorCode::MethodNotFound as i32, message);
+            }
+        };
+
+        match result {
+            Ok(result) => Response::new_ok(req.id, result),
+            Err(err) => Response::new_err(req.id, ErrorCode::InvalidParams as i32, err.to_string()),
+        }
+    }
+
+    /// Updates the documents, and returns the diagnostics to publish.
+    fn notification(
+        &mut self,
+        not: Notification,
+    ) -> Result<Option<PublishDiagnosticsParams>, serde_json::Error> {
+        let (uri, source) = match not.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params: <DidOpenTextDocument as LspNotification>::Params =
+                    serde_json::from_value(not.params)?;
+                (params.text_document.uri, params.text_document.text)
+            }
+            DidChangeTextDocument::METHOD => {
+                let params: <DidChangeTextDocument as LspNotification>::Params =
+                    serde_json::from_value(not.params)?;
+                // the whole text is sent on every change
+                let Some(change) = params.content_changes.into_iter().last() else {
+                    return Ok(None);
+                };
+                (params.text_document.uri, change.text)
+            }
+            DidCloseTextDocument::METHOD => {
+                let params: <DidCloseTextDocument as LspNotification>::Params =
+                    serde_json::from_value(not.params)?;
+                self.documents.remove(params.text_document.uri.as_str());
+                return Ok(Some(PublishDiagnosticsParams::new(
+                    params.text_document.uri,
+                    Vec::new(),
+                    None,
+                )));
+            }
+            _ => return Ok(None),
+        };
+
+        let doc = Document::new(uri.clone(), source);
+        let diagnostics = doc.diagnostics.clone();
+        self.documents.insert(uri.as_str().to_owned(), doc);
+        Ok(Some(PublishDiagnosticsParams::new(uri, diagnostics, None)))
+    }
+}
+
+/// Serves one client on standard input and output, until it exits.
+pub fn run() -> Result<(), Box<dyn Error + Sync + Send>> {
+    let (connection, io_threads) = Connection::stdio();
+
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        definition_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        completion_provider: Some(CompletionOptions::default()),
+        document_symbol_provider: Some(OneOf::Left(true)),
+        ..Default::default()
+    };
+    connection.initialize(serde_json::to_value(capabilities)?)?;
+
+    let mut builtins: Vec<String> = core_env().data.borrow().keys().cloned().collect();
+    builtins.sort();
+    let mut server = Server {
+        documents: HashMap::new(),
+        builtins,
+    };
+
+    for message in &connection.receiver {
+        match message {
+            Message::Request(req) => {
+                if connection.handle_shutdown(&req)? {
+                    break;
+                }
+                connection
+                    .sender
+                    .send(Message::Response(server.request(req)))?;
+            }
+            Message::Notification(not) => {
+                if let Some(params) = server.notification(not)? {
+                    let not = Notification::new(PublishDiagnostics::METHOD.to_owned(), params);
+                    connection.sender.send(Message::Notification(not))?;
+                }
+            }
+            Message::Response(_) => {}
+        }
+    }
+
+    drop(connection);
+    io_threads.join()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp_server::RequestId;
+    use serde_json::json;
+
+    use super::*;
+
+    const URI: &str = "file:///test.mal";
+
+    /// A server with `source` open as `URI`, and the diagnostics it published.
+    fn open(source: &str) -> (Server, PublishDiagnosticsParams) {
+        let mut server = Server {
+            documents: HashMap::new(),
+            builtins: vec!["+".to_owned(), "map".to_owned()],
+        };
+        let params = json!({
+            "textDocument": {"uri": URI, "languageId": "mal", "version": 1, "text": source}
+        });
+        let not = Notification::new(DidOpenTextDocument::METHOD.to_owned(), params);
+        let published = server.notification(not).unwrap().unwrap();
+        (server, published)
+    }
+
+    /// The result of the request `method` at `line` and `character` of `URI`.
+    fn request(server: &Server, method: &str, line: u32, character: u32) -> Value {
+        let params = json!({
+            "textDocument": {"uri": URI},
+            "position": {"line": line, "character": character}
+        });
+        let req = Request::new(RequestId::from(1), method.to_owned(), params);
+        let response = server.request(req);
+        assert!(response.error.is_none(), "{:?}", response.error);
+        response.result.unwrap()
+    }
+
+    #[test]
+    fn publishes_a_diagnostic_for_unbalanced_input() {
+        let (_, published) = open("(def! x (+ 1 2)\n");
+        assert_eq!(published.uri.as_str(), URI);
+        assert_eq!(published.diagnostics.len(), 1);
+        let diagnostic = &published.diagnostics[0];
+        assert_eq!(diagnostic.severity, Some(DiagnosticSeverity::ERROR));
+        assert_eq!(diagnostic.range.start, Position::new(0, 0));
+
+        let (_, published) = open("(def! x (+ 1 2))\n");
+        assert!(published.diagnostics.is_empty());
+    }
+
+    #[test]
+    fn hovers_over_a_builtin() {
+        let (server, _) = open("(map inc [1 2])\n");
+        let hover = request(&server, HoverRequest::METHOD, 0, 2);
+        assert_eq!(hover["contents"]["value"], "`map`: builtin function");
+        assert_eq!(hover["range"]["start"], json!({"line": 0, "character": 1}));
+        assert_eq!(hover["range"]["end"], json!({"line": 0, "character": 4}));
+
+        assert_eq!(request(&server, HoverRequest::METHOD, 0, 6), Value::Null);
+    }
+
+    #[test]
+    fn goes_to_the_definition_of_a_def() {
+        let source = "(def! inc (fn* [x] (+ x 1)))\n\n(inc 2)\n";
+        let (server, _) = open(source);
+        let locations = request(&server, GotoDefinition::METHOD, 2, 2);
+        let expected = json!([{
+            "uri": URI,
+            "range": {
+                "start": {"line": 0, "character": 6},
+                "end": {"line": 0, "character": 9}
+            }
+        }]);
+        assert_eq!(locations, expected);
+    }
+}
@@ -0,0 +1,190 @@
+//! `mal-fmt [options] [files...]` formats Mal source files in place, or
+//! standard input to standard output when no file is given.
+//!
+//! Options:
+//!
+//! - `--check`: only list the files that are not formatted, and exit with
+//!   status 1 if there are any;
+//! - `--indent=N`: indent bodies by `N` spaces (2 by default);
+//! - `--no-align`: indent the arguments of calls like bodies, rather than
+//!   aligning them under the first one;
+//! - `--max-blank-lines=N`: keep at most `N` blank lines in a row (1 by
+//!   default);
+//! - `--body-forms=a,b`: also indent the forms `a` and `b` like `let*`;
+//! - `--round-trip`: only check that the files, even those that do not parse,
+//!   are printed back byte for byte from their concrete syntax tree, and that
+//!   the span of each form is where its text is.
+
+pub mod cst;
+pub mod format;
+
+use std::{
+    io::{Read, Write},
+    process::ExitCode,
+};
+
+use format::Style;
+
+fn usage() -> ExitCode {
+    eprintln!("usage: mal-fmt [--check] [--indent=N] [--no-align] [--max-blank-lines=N] [--body-forms=a,b] [--round-trip] [files...]");
+    ExitCode::from(2)
+}
+
+/// Formats `source`, with the error message for a file that does not parse.
+fn format_source(name: &str, source: &str, style: &Style) -> Result<String, String> {
+    match cst::parse(source) {
+        Ok(cst) => Ok(format::format(&cst, style)),
+        Err(err) => {
+            let (line, col) = err.line_col(source);
+            Err(format!("{name}:{line}:{col}: {}", err.message))
+        }
+    }
+}
+
+/// Where the text of `node` is not at its span, or not printed back from it.
+fn check_spans(source: &str, node: &cst::Node) -> Result<(), usize> {
+    if source.get(node.span.clone()) != Some(node.kind.to_string().as_str()) {
+        return Err(node.span.start);
+    }
+
+    match &node.kind {
+        cst::NodeKind::Prefix(_, nodes) | cst::NodeKind::Collection { items: nodes, .. } => {
+            nodes.iter().try_for_each(|node| check_spans(source, node))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn check_round_trip(files: &[String]) -> ExitCode {
+    let mut status = ExitCode::SUCCESS;
+    for file in files {
+        let source = match std::fs::read_to_string(file) {
+            Ok(source) => source,
+            Err(err) => {
+                eprintln!("{file}: {err}");
+                status = ExitCode::from(2);
+                continue;
+            }
+        };
+
+        let (cst, _) = cst::parse_lossless(&source);
+        let printed = cst.to_string();
+        let mismatch = if printed != source {
+            let at = source
+                .bytes()
+                .zip(printed.bytes())
+                .position(|(a, b)| a != b)
+                .unwrap_or(source.len().min(printed.len()));
+            Some((at, "not printed back"))
+        } else {
+            cst.nodes
+                .iter()
+                .try_for_each(|node| check_spans(&source, node))
+                .err()
+                .map(|at| (at, "not at its span"))
+        };
+
+        if let Some((at, message)) = mismatch {
+            let err = cst::ParseError {
+                message: message.to_owned(),
+                offset: at,
+            };
+            let (line, col) = err.line_col(&source);
+            println!("{file}:{line}:{col}: {message}");
+            status = ExitCode::from(1);
+        }
+    }
+
+    status
+}
+
+pub fn main() -> ExitCode {
+    let mut style = Style::default();
+    let mut check = false;
+    let mut round_trip = false;
+    let mut files = Vec::new();
+
+    for arg in std::env::args().skip(1) {
+        match arg.split_once('=') {
+            None if arg.eq("--check") => check = true,
+            None if arg.eq("--no-align") => style.align_arguments = false,
+            None if arg.eq("--round-trip") => round_trip = true,
+            Some(("--indent", n)) => match n.parse() {
+                Ok(n) => style.indent = n,
+                Err(_) => return usage(),
+            },
+            Some(("--max-blank-lines", n)) => match n.parse() {
+                Ok(n) => style.max_blank_lines = n,
+                Err(_) => return usage(),
+            },
+            Some(("--body-forms", forms)) => {
+                style.body_forms.extend(
+                    forms
+                        .split(',')
+                        .filter(|f| !f.is_empty())
+                        .map(str::to_owned),
+                );
+            }
+            _ if arg.starts_with("--") => return usage(),
+            _ => files.push(arg),
+        }
+    }
+
+    if round_trip {
+        return check_round_trip(&files);
+    }
+
+    if files.is_empty() {
+        let mut source = String::new();
+        if let Err(err) = std::io::stdin().read_to_string(&mut source) {
+            eprintln!("<stdin>: {err}");
+            return ExitCode::from(2);
+        }
+
+        return match format_source("<stdin>", &source, &style) {
+            Ok(formatted) if check => ExitCode::from(u8::from(formatted != source)),
+            Ok(formatted) => {
+                let _ = std::io::stdout().write_all(formatted.as_bytes());
+                ExitCode::SUCCESS
+            }
+            Err(err) => {
+                eprintln!("{err}");
+                ExitCode::from(2)
+            }
+        };
+    }
+
+    let mut status = ExitCode::SUCCESS;
+    for file in files {
+        let source = match std::fs::read_to_string(&file) {
+            Ok(source) => source,
+            Err(err) => {
+                eprintln!("{file}: {err}");
+                status = ExitCode::from(2);
+                continue;
+            }
+        };
+
+        match format_source(&file, &source, &style) {
+            Ok(formatted) if formatted == source => {}
+            Ok(_) if check => {
+                println!("{file}");
+                if status == ExitCode::SUCCESS {
+                    status = ExitCode::from(1);
+                }
+            }
+            Ok(formatted) => {
+                if let Err(err) = std::fs::write(&file, formatted) {
+                    eprintln!("{file}: {err}");
+                    status = ExitCode::from(2);
+                }
+            }
+            Err(err) => {
+                eprintln!("{err}");
+                status = ExitCode::from(2);
+            }
+        }
+    }
+
+    status
+}
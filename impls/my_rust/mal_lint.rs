@@ -0,0 +1,11 @@
+//! `mal-lint [files...]` checks Mal source files without running them; see
+//! `lint.rs`.
+
+use std::process::ExitCode;
+
+use my_rust::lint;
+
+pub fn main() -> ExitCode {
+    let files: Vec<String> = std::env::args().skip(1).collect();
+    ExitCode::from(lint::run(&files) as u8)
+}
@@ -0,0 +1,16 @@
+//! `mal-lsp` is the language server for Mal, speaking JSON-RPC over standard
+//! input and output; see `lsp.rs`.
+
+use std::process::ExitCode;
+
+use my_rust::lsp;
+
+pub fn main() -> ExitCode {
+    match lsp::run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,402 @@
+//! Namespaces and the `require` module system.
+//!
+//! Every namespace has its own top-level `Env`, nested right below the root
+//! `Env` holding the builtins, so definitions from different namespaces do
+//! not clash and the builtins stay visible everywhere. The REPL and `require`
+//! evaluate each top-level form in the namespace that is current at that
+//! point, which is what `ns` switches.
+//!
+//! A symbol like `str/join` is looked up as `join` in the namespace `str`,
+//! where `str` is either an alias created with `(require '[... :as str])` in
+//! the namespace the symbol is evaluated in, or a full namespace name.
+//!
+//! `(require 'foo.bar)` looks for `foo/bar.mal` in the directories listed in
+//! `*load-path*`, then among the files of `impls/lib` built into the binary,
+//! and only loads a namespace the first time it is required, unless `:reload`
+//! is given.
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+use crate::{
+    core::FuncTuple,
+    env::*,
+    interpreter::current_eval,
+    reader::{locate, read_located},
+    types::*,
+};
+
+pub struct Namespace {
+    pub name: String,
+    pub env: Env,
+    aliases: RefCell<HashMap<String, String>>,
+}
+
+/// All the namespaces of an interpreter.
+pub struct Namespaces {
+    root: Env,
+    namespaces: RefCell<HashMap<String, Rc<Namespace>>>,
+    current: RefCell<Rc<Namespace>>,
+    loaded: RefCell<HashSet<String>>,
+    /// Whether `require` may read files, which a sandbox can forbid.
+    read_files: bool,
+}
+
+thread_local! {
+    static NAMESPACES: RefCell<Option<Rc<Namespaces>>> = const { RefCell::new(None) };
+}
+
+impl Namespaces {
+    /// Creates the registry with a `user` namespace on top of `root`, which
+    /// starts out as the current one.
+    pub fn new(root: &Env, read_files: bool) -> Rc<Namespaces> {
+        let user = Rc::new(Namespace {
+            name: "user".to_owned(),
+            env: env_new(Some(root.clone())),
+            aliases: RefCell::new(HashMap::new()),
+        });
+
+        let namespaces = Rc::new(Namespaces {
+            root: root.clone(),
+            namespaces: RefCell::new(HashMap::from([("user".to_owned(), user.clone())])),
+            current: RefCell::new(user),
+            loaded: RefCell::new(HashSet::new()),
+            read_files,
+        });
+        namespaces.set_current("user");
+
+        namespaces
+    }
+
+    pub fn current(&self) -> Rc<Namespace> {
+        self.current.borrow().clone()
+    }
+
+    fn get_or_create(&self, name: &str) -> Rc<Namespace> {
+        self.namespaces
+            .borrow_mut()
+            .entry(name.to_owned())
+            .or_insert_with(|| {
+                Rc::new(Namespace {
+                    name: name.to_owned(),
+                    env: env_new(Some(self.root.clone())),
+                    aliases: RefCell::new(HashMap::new()),
+                })
+            })
+            .clone()
+    }
+
+    /// Makes `name` the current namespace, creating it if needed.
+    pub fn set_current(&self, name: &str) -> Rc<Namespace> {
+        let namespace = self.get_or_create(name);
+
+        *self.current.borrow_mut() = namespace.clone();
+        env_set(
+            &self.root,
+            &MalType::Symbol("*ns*".to_owned()),
+            MalType::Symbol(name.to_owned()),
+        );
+
+        namespace
+    }
+
+    /// Defines the namespace `name` out of builtins, already loaded so that
+    /// requiring it only adds the aliases and referred symbols.
+    pub fn define_builtins(&self, name: &str, builtins: &[FuncTuple]) {
+        let namespace = self.get_or_create(name);
+        for (symbol, func, _) in builtins {
+            env_set(
+                &namespace.env,
+                &MalType::Symbol((*symbol).to_owned()),
+                MalType::Func(*func),
+            );
+        }
+        self.loaded.borrow_mut().insert(name.to_owned());
+    }
+
+    fn get(&self, name: &str) -> Option<Rc<Namespace>> {
+        self.namespaces.borrow().get(name).cloned()
+    }
+
+    /// The namespace whose top-level `Env` encloses `env`.
+    fn of_env(&self, env: &Env) -> Option<Rc<Namespace>> {
+        let top = env_top(env);
+        self.namespaces
+            .borrow()
+            .values()
+            .find(|ns| Rc::ptr_eq(&ns.env, &top))
+            .cloned()
+    }
+
+    /// Runs `f` with these namespaces available to `ns*`, `require` and
+    /// qualified symbols.
+    pub fn enter<T>(self: &Rc<Self>, f: impl FnOnce() -> T) -> T {
+        let outer = NAMESPACES.with(|n| n.replace(Some(self.clone())));
+        let res = f();
+        NAMESPACES.with(|n| n.replace(outer));
+        res
+    }
+}
+
+fn namespaces() -> Result<Rc<Namespaces>, MalType> {
+    NAMESPACES
+        .with(|n| n.borrow().clone())
+        .ok_or_else(|| error("namespaces are not available here".to_owned()))
+}
+
+fn error(message: String) -> MalType {
+    MalType::Error(Box::new(MalType::String(message)))
+}
+
+/// The `Env` that `eval` evaluates in: the top-level one of the current
+/// namespace, or of the namespace `env` belongs to when there is no registry.
+pub fn eval_env(env: &Env) -> Env {
+    match NAMESPACES.with(|n| n.borrow().clone()) {
+        Some(namespaces) => namespaces.current().env.clone(),
+        None => env_top(env),
+    }
+}
+
+/// The value of a global like `*print-length*` in the current namespace, where
+/// it may have been redefined.
+pub fn global(name: &str) -> Option<MalType> {
+    let namespaces = NAMESPACES.with(|n| n.borrow().clone())?;
+    env_get(&namespaces.current().env, name)
+}
+
+/// Looks up a qualified symbol like `str/join`, as seen from `env`.
+pub fn resolve(env: &Env, symbol: &str) -> Option<MalType> {
+    let (qualifier, name) = symbol.rsplit_once('/')?;
+    if qualifier.is_empty() || name.is_empty() {
+        return None;
+    }
+
+    let namespaces = NAMESPACES.with(|n| n.borrow().clone())?;
+    let target = namespaces
+        .of_env(env)
+        .and_then(|ns| ns.aliases.borrow().get(qualifier).cloned())
+        .unwrap_or_else(|| qualifier.to_owned());
+
+    env_get(&namespaces.get(&target)?.env, name)
+}
+
+/// `(ns* '(name (:require spec...)...))`, what the `ns` macro expands to.
+pub fn ns(form: &MalType) -> Result<MalType, MalType> {
+    let (MalType::List(form) | MalType::Vector(form)) = form else {
+        return Err(error(format!("bad ns form {form}")));
+    };
+    let Some(MalType::Symbol(name)) = form.first() else {
+        return Err(error("ns expects a namespace name".to_owned()));
+    };
+
+    let namespaces = namespaces()?;
+    namespaces.set_current(name);
+
+    for clause in &form[1..] {
+        match clause {
+            MalType::List(clause)
+                if clause.first() == Some(&MalType::Symbol(":require".to_owned())) =>
+            {
+                for spec in &clause[1..] {
+                    require(spec, false)?;
+                }
+            }
+            _ => return Err(error(format!("unsupported ns clause {clause}"))),
+        }
+    }
+
+    Ok(MalType::Nil)
+}
+
+/// Loads the namespace named by `spec` into the current one, either `name`
+/// or `[name :as alias :refer [symbol...]]` (or `:refer :all`).
+pub fn require(spec: &MalType, reload: bool) -> Result<MalType, MalType> {
+    let (name, options) = match spec {
+        MalType::Symbol(name) => (name, &[][..]),
+        MalType::List(spec) | MalType::Vector(spec)
+            if let Some(MalType::Symbol(name)) = spec.first() =>
+        {
+            (name, &spec[1..])
+        }
+        _ => return Err(error(format!("bad require spec {spec}"))),
+    };
+
+    let namespaces = namespaces()?;
+    let requiring = namespaces.current();
+
+    // a namespace already in the registry, say made with `(ns foo)` at the
+    // REPL, has nothing to load unless asked to
+    let known = namespaces.loaded.borrow().contains(name) || namespaces.get(name).is_some();
+    if reload || !known {
+        // marked first, so that namespaces requiring each other terminate
+        namespaces.loaded.borrow_mut().insert(name.clone());
+        let res = load(&namespaces, name, &requiring.env);
+        namespaces.set_current(&requiring.name);
+
+        if let Err(err) = res {
+            namespaces.loaded.borrow_mut().remove(name);
+            return Err(err);
+        }
+    }
+
+    let Some(required) = namespaces.get(name) else {
+        return Err(error(format!("namespace {name} was not defined")));
+    };
+
+    for option in options.chunks(2) {
+        match option {
+            [MalType::Symbol(key), MalType::Symbol(alias)] if key.eq(":as") => {
+                requiring
+                    .aliases
+                    .borrow_mut()
+                    .insert(alias.clone(), name.clone());
+            }
+            [MalType::Symbol(key), MalType::Symbol(all)] if key.eq(":refer") && all.eq(":all") => {
+                for (symbol, value) in required.env.data.borrow().iter() {
+                    env_set(
+                        &requiring.env,
+                        &MalType::Symbol(symbol.clone()),
+                        value.clone(),
+                    );
+                }
+            }
+            [MalType::Symbol(key), MalType::List(symbols) | MalType::Vector(symbols)]
+                if key.eq(":refer") =>
+            {
+                for symbol in symbols {
+                    let MalType::Symbol(s) = symbol else {
+                        return Err(error(format!("cannot refer to {symbol}")));
+                    };
+                    let Some(value) = required.env.data.borrow().get(s).cloned() else {
+                        return Err(error(format!("{s} is not defined in {name}")));
+                    };
+                    env_set(&requiring.env, symbol, value);
+                }
+            }
+            _ => {
+                return Err(error(format!(
+                    "bad require option {}",
+                    MalType::List(option.to_vec())
+                )))
+            }
+        }
+    }
+
+    Ok(MalType::Nil)
+}
+
+/// The files of `impls/lib`, which `require` finds by name when they are not
+/// on the `*load-path*`. `pprint` is not among them, being built in instead
+/// (see `PPRINT_NS`).
+const LIBRARY: [(&str, &str); 11] = [
+    ("alias-hacks", include_str!("../lib/alias-hacks.mal")),
+    ("benchmark", include_str!("../lib/benchmark.mal")),
+    ("equality", include_str!("../lib/equality.mal")),
+    ("load-file-once", include_str!("../lib/load-file-once.mal")),
+    ("memoize", include_str!("../lib/memoize.mal")),
+    ("perf", include_str!("../lib/perf.mal")),
+    ("protocols", include_str!("../lib/protocols.mal")),
+    ("reducers", include_str!("../lib/reducers.mal")),
+    ("test_cascade", include_str!("../lib/test_cascade.mal")),
+    ("threading", include_str!("../lib/threading.mal")),
+    ("trivial", include_str!("../lib/trivial.mal")),
+];
+
+enum Source {
+    /// The path of a file, and its text.
+    File(String, String),
+    Bundled(&'static str),
+}
+
+/// Evaluates the file of namespace `name` form by form, each in whichever
+/// namespace is current when it runs.
+fn load(namespaces: &Rc<Namespaces>, name: &str, requiring: &Env) -> Result<(), MalType> {
+    let source = find_source(namespaces, name, requiring)?;
+
+    namespaces.set_current(name);
+
+    let (file, source, bundled) = match &source {
+        Source::File(path, source) => (path.clone(), source.as_str(), false),
+        Source::Bundled(source) => (format!("lib/{name}.mal"), *source, true),
+    };
+    let forms = read_located(source, &file)?;
+
+    let eval = current_eval();
+    for (form, pos) in forms {
+        let res = match bundled_dependency(&form) {
+            Some("load-file-once") => continue,
+            Some(dependency) if bundled => {
+                let spec = [dependency, ":refer", ":all"].map(|s| MalType::Symbol(s.to_owned()));
+                require(&MalType::List(spec.to_vec()), false)?
+            }
+            _ => {
+                locate(&form, pos);
+                eval(form, namespaces.current().env.clone())
+            }
+        };
+
+        if res.is_error() {
+            return Err(res);
+        }
+    }
+
+    Ok(())
+}
+
+/// The library files are shared with the other implementations, and pull each
+/// other in with `(load-file-once "../lib/foo.mal")`. When they are loaded from
+/// the binary, these become a `require` of the bundled file instead, which
+/// refers to all of it as loading it would have defined it all.
+fn bundled_dependency(form: &MalType) -> Option<&'static str> {
+    let MalType::List(form) = form else {
+        return None;
+    };
+    let [MalType::Symbol(load), MalType::String(path)] = form.as_slice() else {
+        return None;
+    };
+    if !load.eq("load-file") && !load.eq("load-file-once") {
+        return None;
+    }
+
+    let name = path.strip_prefix("../lib/")?.strip_suffix(".mal")?;
+    LIBRARY.iter().find(|(n, _)| n.eq(&name)).map(|(n, _)| *n)
+}
+
+/// Reads `foo/bar.mal` for `foo.bar`, from the first directory of
+/// `*load-path*` that has it, or else from the bundled library.
+fn find_source(namespaces: &Namespaces, name: &str, env: &Env) -> Result<Source, MalType> {
+    let file = format!("{}.mal", name.replace('.', "/"));
+
+    if namespaces.read_files {
+        let directories = match env_get(env, "*load-path*") {
+            Some(MalType::List(dirs) | MalType::Vector(dirs)) => dirs,
+            _ => vec![MalType::String(".".to_owned())],
+        };
+
+        for directory in directories {
+            let MalType::String(directory) = directory else {
+                continue;
+            };
+
+            let path = std::path::Path::new(&directory).join(&file);
+            if let Ok(source) = std::fs::read_to_string(&path) {
+                return Ok(Source::File(path.display().to_string(), source));
+            }
+        }
+    }
+
+    if let Some((_, source)) = LIBRARY.iter().find(|(n, _)| n.eq(&name)) {
+        return Ok(Source::Bundled(source));
+    }
+
+    if namespaces.read_files {
+        Err(error(format!("could not find {file} on the *load-path*")))
+    } else {
+        Err(error(format!(
+            "cannot load {name}, reading files is not allowed"
+        )))
+    }
+}
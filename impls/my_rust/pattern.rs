@@ -0,0 +1,144 @@
+//! Regular expressions, read from `#"..."` literals or built by `re-pattern`.
+//!
+//! A match is returned as the matched string when the pattern has no capture
+//! groups, and otherwise as a vector of the whole match followed by each group,
+//! with nil for a group that did not take part in the match.
+
+use std::{fmt::Debug, rc::Rc};
+
+use regex::{Captures, Regex};
+
+use crate::{seq, types::*};
+
+pub struct Pattern(pub Regex);
+
+impl Debug for Pattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Pattern").field(&self.0.as_str()).finish()
+    }
+}
+
+/// Two patterns are equal when they were written the same way.
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Eq for Pattern {}
+
+impl Pattern {
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+fn error(message: String) -> MalType {
+    MalType::Error(Box::new(MalType::String(message)))
+}
+
+/// Compiles `source`, raising an error if it is not a valid regex.
+pub fn compile(source: &str) -> MalType {
+    match Regex::new(source) {
+        Ok(re) => MalType::Regex(Rc::new(Pattern(re))),
+        Err(err) => error(format!("invalid regex #\"{source}\": {err}")),
+    }
+}
+
+fn matched(captures: &Captures) -> MalType {
+    if captures.len() == 1 {
+        return MalType::String(captures[0].to_owned());
+    }
+
+    MalType::Vector(
+        captures
+            .iter()
+            .map(|group| group.map_or(MalType::Nil, |m| MalType::String(m.as_str().to_owned())))
+            .collect(),
+    )
+}
+
+/// `(re-find re s)`: the first match of `re` in `s`, or nil.
+pub fn find(re: &Pattern, s: &str) -> MalType {
+    re.0.captures(s).map_or(MalType::Nil, |c| matched(&c))
+}
+
+/// `(re-matches re s)`: the match of `re` if it spans the whole of `s`, or nil.
+pub fn matches(re: &Pattern, s: &str) -> MalType {
+    match re.0.captures(s) {
+        Some(c) if c[0].len() == s.len() => matched(&c),
+        // a shorter match found first does not rule out one of the whole string
+        Some(_) => match Regex::new(&format!("^(?:{})$", re.as_str())) {
+            Ok(anchored) => anchored.captures(s).map_or(MalType::Nil, |c| matched(&c)),
+            Err(_) => MalType::Nil,
+        },
+        None => MalType::Nil,
+    }
+}
+
+/// `(re-seq re s)`: a lazy sequence of the successive matches of `re` in `s`.
+pub fn find_all(re: Rc<Pattern>, s: Rc<str>, from: usize) -> MalType {
+    seq::lazy(move || {
+        let Some(captures) = re.0.captures_at(&s, from) else {
+            return MalType::Nil;
+        };
+
+        let whole = captures.get(0).unwrap();
+        // an empty match moves on by a character, so as not to find it again
+        let next = if whole.is_empty() {
+            match s[whole.end()..].chars().next() {
+                Some(c) => whole.end() + c.len_utf8(),
+                None => return seq::cons(matched(&captures), MalType::Nil),
+            }
+        } else {
+            whole.end()
+        };
+
+        seq::cons(matched(&captures), find_all(re.clone(), s.clone(), next))
+    })
+}
+
+/// `(re-split re s)`: the parts of `s` between the matches of `re`.
+pub fn split(re: &Pattern, s: &str) -> MalType {
+    MalType::Vector(
+        re.0.split(s)
+            .map(|part| MalType::String(part.to_owned()))
+            .collect(),
+    )
+}
+
+/// `(re-replace re s replacement)` replaces every match of `re`. A string
+/// replacement can refer to groups as `$1` or `${name}`, while a function is
+/// called with each match and returns its replacement.
+pub fn replace(s: &str, re: &Pattern, replacement: &MalType) -> MalType {
+    match replacement {
+        MalType::String(to) => MalType::String(re.0.replace_all(s, to.as_str()).into_owned()),
+        MalType::Char(c) => MalType::String(
+            re.0.replace_all(s, regex::NoExpand(&c.to_string()))
+                .into_owned(),
+        ),
+        f => {
+            let mut res = String::new();
+            let mut last = 0;
+            for captures in re.0.captures_iter(s) {
+                let whole = captures.get(0).unwrap();
+                res.push_str(&s[last..whole.start()]);
+                last = whole.end();
+
+                match f.apply(vec![matched(&captures)]) {
+                    err @ MalType::Error(_) => return err,
+                    MalType::String(to) => res.push_str(&to),
+                    MalType::Char(c) => res.push(c),
+                    other => {
+                        return error(format!(
+                            "re-replace: the replacement function returned a {}, not a String",
+                            MalType::discriminant_name(&other)
+                        ))
+                    }
+                }
+            }
+            res.push_str(&s[last..]);
+            MalType::String(res)
+        }
+    }
+}
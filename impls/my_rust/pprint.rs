@@ -0,0 +1,359 @@
+//! The pretty printer behind `pprint` and the `--pprint` REPL mode.
+//!
+//! A value is first turned into a document, in the style of Wadler's "A
+//! prettier printer": text, line breaks, and groups whose breaks are either all
+//! taken or all printed as spaces, depending on whether the group fits in what
+//! is left of the line. The document is then laid out within the width.
+//!
+//! Calls keep their first argument next to the function and align the others
+//! under it, while data collections align their elements after the opening
+//! bracket. Forms with a body, like `let*`, `fn*` and `defmacro!`, indent it
+//! by two spaces instead, and the bindings of `let*` and the clauses of `cond`
+//! are kept in pairs.
+
+use std::{cell::RefCell, ops::Deref, rc::Rc};
+
+use crate::{
+    printer::{atom_id, print_with, Options},
+    seq,
+    types::*,
+};
+
+enum Doc {
+    Text(String),
+    /// A space, or a new line at the current indentation when the group it is
+    /// in is broken.
+    Line,
+    Concat(Vec<Doc>),
+    /// Indents the lines in `Doc` by more than the enclosing indentation.
+    Nest(usize, Box<Doc>),
+    /// Sets the indentation of the lines in `Doc` to the current column.
+    Align(Box<Doc>),
+    Group(Box<Doc>),
+}
+
+fn text(s: impl Into<String>) -> Doc {
+    Doc::Text(s.into())
+}
+
+fn nest(indent: usize, doc: Doc) -> Doc {
+    Doc::Nest(indent, Box::new(doc))
+}
+
+fn align(doc: Doc) -> Doc {
+    Doc::Align(Box::new(doc))
+}
+
+fn group(doc: Doc) -> Doc {
+    Doc::Group(Box::new(doc))
+}
+
+/// `docs` with a `Line` between each.
+fn lines(docs: Vec<Doc>) -> Doc {
+    let mut res = Vec::new();
+    for doc in docs {
+        if !res.is_empty() {
+            res.push(Doc::Line);
+        }
+        res.push(doc);
+    }
+    Doc::Concat(res)
+}
+
+/// Forms whose first arguments stay on the line of the form, with the rest
+/// being a body indented by two spaces.
+const BODY_FORMS: [(&str, usize); 9] = [
+    ("def!", 1),
+    ("defmacro!", 1),
+    ("fn*", 1),
+    ("let*", 1),
+    ("do", 0),
+    ("try*", 0),
+    ("catch*", 1),
+    ("ns", 1),
+    ("cond", 0),
+];
+
+struct Printer {
+    options: Options,
+    /// The atoms whose value is being laid out.
+    atoms: RefCell<Vec<Atom>>,
+}
+
+impl Printer {
+    fn doc(&self, value: &MalType, depth: usize) -> Doc {
+        match value {
+            MalType::List(items) => self.list(items, depth),
+            MalType::Vector(items) => self.collection("[", items, "]", depth),
+            MalType::Set(items) => {
+                let items: Vec<MalType> = items.iter().cloned().collect();
+                self.collection("#{", &items, "}", depth)
+            }
+            MalType::Dictionary(items) => self.dictionary(items, depth),
+            MalType::LazySeq(_) => {
+                let realized = match self.options.length {
+                    Some(length) => seq::take(length + 1, value),
+                    None => seq::to_vec(value),
+                };
+                match realized {
+                    Ok(items) => self.collection("(", &items, ")", depth),
+                    Err(err) => self.doc(&err, depth),
+                }
+            }
+            MalType::Atom(a) => {
+                let cycle = self.atoms.borrow().iter().any(|outer| Rc::ptr_eq(outer, a));
+                if self.options.atom_ids || cycle {
+                    return text(atom_id(a));
+                }
+
+                self.atoms.borrow_mut().push(a.clone());
+                let doc = self.collection(
+                    "(",
+                    &[
+                        MalType::Symbol("atom".to_owned()),
+                        a.borrow().deref().clone(),
+                    ],
+                    ")",
+                    depth,
+                );
+                self.atoms.borrow_mut().pop();
+                doc
+            }
+            MalType::WithMeta(value, meta) => self.collection(
+                "(",
+                &[
+                    MalType::Symbol("with-meta".to_owned()),
+                    meta.deref().clone(),
+                    value.deref().clone(),
+                ],
+                ")",
+                depth,
+            ),
+            MalType::Error(e) => Doc::Concat(vec![text("Error: "), self.doc(e, depth)]),
+            _ => text(print_with(value, true, &self.options)),
+        }
+    }
+
+    /// The docs of `items`, up to the print length.
+    fn items(&self, items: &[MalType], depth: usize) -> Vec<Doc> {
+        let length = self.options.length.unwrap_or(usize::MAX);
+        let mut docs: Vec<Doc> = items
+            .iter()
+            .take(length)
+            .map(|item| self.doc(item, depth + 1))
+            .collect();
+        if items.len() > length {
+            docs.push(text("..."));
+        }
+        docs
+    }
+
+    fn collection(&self, open: &str, items: &[MalType], close: &str, depth: usize) -> Doc {
+        if self.options.too_deep(depth) {
+            return text("#");
+        }
+
+        group(Doc::Concat(vec![
+            text(open),
+            align(lines(self.items(items, depth))),
+            text(close),
+        ]))
+    }
+
+    fn dictionary(&self, items: &[MalType], depth: usize) -> Doc {
+        if self.options.too_deep(depth) {
+            return text("#");
+        }
+
+        group(Doc::Concat(vec![
+            text("{"),
+            align(lines(self.pairs(items, depth))),
+            text("}"),
+        ]))
+    }
+
+    /// The docs of `items` taken two by two, each pair on a line with the
+    /// second aligned after the first, up to the print length.
+    fn pairs(&self, items: &[MalType], depth: usize) -> Vec<Doc> {
+        let length = self.options.length.unwrap_or(usize::MAX);
+        let mut docs: Vec<Doc> = items
+            .chunks(2)
+            .take(length)
+            .map(|pair| match pair {
+                [k, v] => Doc::Concat(vec![
+                    self.doc(k, depth + 1),
+                    text(" "),
+                    align(self.doc(v, depth + 1)),
+                ]),
+                _ => self.doc(&pair[0], depth + 1),
+            })
+            .collect();
+        if items.len().div_ceil(2) > length {
+            docs.push(text("..."));
+        }
+        docs
+    }
+
+    fn list(&self, items: &[MalType], depth: usize) -> Doc {
+        let Some((MalType::Symbol(head), args)) = items.split_first() else {
+            return self.collection("(", items, ")", depth);
+        };
+        if self.options.too_deep(depth) {
+            return text("#");
+        }
+        if args.is_empty()
+            || self
+                .options
+                .length
+                .is_some_and(|length| items.len() > length)
+        {
+            return self.collection("(", items, ")", depth);
+        }
+
+        let Some(&(_, leading)) = BODY_FORMS.iter().find(|(name, _)| name == head) else {
+            // a call, with the arguments aligned under the first one
+            return group(Doc::Concat(vec![
+                text(format!("({head} ")),
+                align(lines(self.items(args, depth))),
+                text(")"),
+            ]));
+        };
+
+        let leading = leading.min(args.len());
+        let mut first_line = vec![text(format!("({head}"))];
+        for (i, arg) in args[..leading].iter().enumerate() {
+            first_line.push(text(" "));
+            if i == 0 && head.eq("let*") {
+                first_line.push(self.bindings(arg, depth + 1));
+            } else {
+                first_line.push(self.doc(arg, depth + 1));
+            }
+        }
+
+        let body = &args[leading..];
+        let body = if head.eq("cond") {
+            self.clauses(body, depth)
+        } else {
+            body.iter().map(|form| self.doc(form, depth + 1)).collect()
+        };
+
+        let mut body_lines = Vec::new();
+        for doc in body {
+            body_lines.push(Doc::Line);
+            body_lines.push(doc);
+        }
+
+        group(Doc::Concat(vec![
+            Doc::Concat(first_line),
+            nest(2, Doc::Concat(body_lines)),
+            text(")"),
+        ]))
+    }
+
+    /// The bindings vector of `let*`, one binding per line when it does not
+    /// fit on one.
+    fn bindings(&self, bindings: &MalType, depth: usize) -> Doc {
+        match bindings {
+            MalType::Vector(items) if !self.options.too_deep(depth) => group(Doc::Concat(vec![
+                text("["),
+                align(lines(self.pairs(items, depth))),
+                text("]"),
+            ])),
+            _ => self.doc(bindings, depth),
+        }
+    }
+
+    /// The clauses of `cond`, each a test with its expression, which goes
+    /// under it, indented, when they do not fit on one line.
+    fn clauses(&self, items: &[MalType], depth: usize) -> Vec<Doc> {
+        items
+            .chunks(2)
+            .map(|clause| match clause {
+                [test, expr] => group(Doc::Concat(vec![
+                    self.doc(test, depth + 1),
+                    nest(2, Doc::Concat(vec![Doc::Line, self.doc(expr, depth + 1)])),
+                ])),
+                _ => self.doc(&clause[0], depth + 1),
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Flat,
+    Break,
+}
+
+/// Whether the docs fit in `width` columns up to the next line break, with
+/// `doc` printed flat, followed by the `rest` still to print in their own mode.
+fn fits(mut width: isize, doc: &Doc, rest: &[(usize, Mode, &Doc)]) -> bool {
+    let mut stack: Vec<(Mode, &Doc)> = vec![(Mode::Flat, doc)];
+    let mut rest = rest.iter().rev();
+
+    while width >= 0 {
+        let Some((mode, doc)) = stack
+            .pop()
+            .or_else(|| rest.next().map(|(_, m, d)| (*m, *d)))
+        else {
+            return true;
+        };
+
+        match doc {
+            Doc::Text(s) => width -= s.chars().count() as isize,
+            Doc::Line if mode == Mode::Break => return true,
+            Doc::Line => width -= 1,
+            Doc::Concat(docs) => stack.extend(docs.iter().rev().map(|d| (mode, d))),
+            Doc::Nest(_, doc) | Doc::Align(doc) | Doc::Group(doc) => stack.push((mode, doc)),
+        }
+    }
+
+    false
+}
+
+fn layout(doc: &Doc, width: usize) -> String {
+    let mut out = String::new();
+    let mut column = 0;
+    let mut stack: Vec<(usize, Mode, &Doc)> = vec![(0, Mode::Break, doc)];
+
+    while let Some((indent, mode, doc)) = stack.pop() {
+        match doc {
+            Doc::Text(s) => {
+                out.push_str(s);
+                column += s.chars().count();
+            }
+            Doc::Line if mode == Mode::Flat => {
+                out.push(' ');
+                column += 1;
+            }
+            Doc::Line => {
+                out.push('\n');
+                out.push_str(&" ".repeat(indent));
+                column = indent;
+            }
+            Doc::Concat(docs) => stack.extend(docs.iter().rev().map(|d| (indent, mode, d))),
+            Doc::Nest(by, doc) => stack.push((indent + by, mode, doc)),
+            Doc::Align(doc) => stack.push((column, mode, doc)),
+            Doc::Group(doc) => {
+                let mode =
+                    if mode == Mode::Flat || fits(width as isize - column as isize, doc, &stack) {
+                        Mode::Flat
+                    } else {
+                        Mode::Break
+                    };
+                stack.push((indent, mode, doc));
+            }
+        }
+    }
+
+    out
+}
+
+/// Lays out `value` readably within the width of `options`.
+pub fn pprint(value: &MalType, options: &Options) -> String {
+    let printer = Printer {
+        options: *options,
+        atoms: RefCell::new(Vec::new()),
+    };
+    layout(&printer.doc(value, 0), options.width)
+}
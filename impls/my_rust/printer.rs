@@ -1,49 +1,282 @@
-use std::ops::Deref;
+//! Printing values as text, readably for `pr-str` and `prn`, or as is for
+//! `str` and `println`.
+//!
+//! Collections are cut short by `*print-length*`, which caps the elements
+//! printed of each, followed by `...`, and `*print-level*`, which caps how
+//! deeply they nest, deeper ones printing as `#`. An atom prints as `(atom
+//! value)`, unless `*print-atom-ids*` is set, in which case it prints as
+//! `#<atom id>`. That is also how an atom prints inside its own value, so that
+//! an atom holding a collection that holds the atom does not print forever.
 
-use crate::MalType;
+use std::{ops::Deref, rc::Rc};
 
-pub fn print_string(mal_type: &MalType, print_readably: bool) -> String {
-    match mal_type {
-        MalType::List(seq) => print_seq(seq, print_readably, "(", ")", " "),
-        MalType::Vector(seq) => print_seq(seq, print_readably, "[", "]", " "),
-        MalType::Dictionary(seq) => print_seq(seq, print_readably, "{", "}", " "),
-        MalType::WithMeta(var, meta) => {
-            let var = print_string(var.as_ref(), true);
-            let meta = print_string(meta.as_ref(), true);
-            format!("(with-meta {meta} {var})")
+use crate::{env::*, namespace, seq, types::Atom, MalType};
+
+/// How values are printed, from `*print-right-margin*` (for `pprint`),
+/// `*print-length*`, `*print-level*` and `*print-atom-ids*`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Options {
+    pub width: usize,
+    /// The number of elements printed of each collection, followed by `...`
+    /// when there are more.
+    pub length: Option<usize>,
+    /// How deeply collections are printed, nested ones below that being `#`.
+    pub level: Option<usize>,
+    /// Whether atoms print as `#<atom id>` rather than with their value.
+    pub atom_ids: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            width: 80,
+            length: None,
+            level: None,
+            atom_ids: false,
+        }
+    }
+}
+
+impl Options {
+    /// The settings as seen from the current namespace.
+    pub fn current() -> Options {
+        Options::read(namespace::global)
+    }
+
+    /// The settings as seen from `env`.
+    pub fn from_env(env: &Env) -> Options {
+        Options::read(|name| env_get(env, name))
+    }
+
+    fn read(get: impl Fn(&str) -> Option<MalType>) -> Options {
+        let number = |name| match get(name) {
+            Some(MalType::Number(n)) => usize::try_from(n).ok(),
+            _ => None,
+        };
+
+        Options {
+            width: number("*print-right-margin*").unwrap_or(Options::default().width),
+            length: number("*print-length*"),
+            level: number("*print-level*"),
+            atom_ids: !matches!(
+                get("*print-atom-ids*"),
+                None | Some(MalType::Nil | MalType::False)
+            ),
         }
-        MalType::Func(_) | MalType::MalFunc { .. } => "#<function>".to_string(),
-        MalType::Nil => "nil".to_string(),
-        MalType::True => "true".to_string(),
-        MalType::False => "false".to_string(),
-        MalType::Symbol(s) => s.to_string(),
-        MalType::Number(n) => format!("{n}"),
-        MalType::String(s) => {
-            if print_readably {
-                format!("\"{}\"", escape_str(s))
-            } else {
-                s.clone()
+    }
+
+    /// Whether collections at `depth` are elided as `#`.
+    pub fn too_deep(&self, depth: usize) -> bool {
+        self.level.is_some_and(|level| depth >= level)
+    }
+}
+
+/// How an atom prints with `*print-atom-ids*`, or inside its own value.
+pub fn atom_id(atom: &Atom) -> String {
+    format!("#<atom {:p}>", Rc::as_ptr(atom))
+}
+
+pub fn print_string(mal_type: &MalType, print_readably: bool) -> String {
+    print_with(mal_type, print_readably, &Options::current())
+}
+
+/// Prints `mal_type` with the given settings, where `print_string` uses those
+/// of the current namespace.
+pub fn print_with(mal_type: &MalType, print_readably: bool, options: &Options) -> String {
+    let mut printer = Printer {
+        readably: print_readably,
+        options,
+        atoms: Vec::new(),
+    };
+    printer.print(mal_type, 0)
+}
+
+struct Printer<'a> {
+    readably: bool,
+    options: &'a Options,
+    /// The atoms whose value is being printed.
+    atoms: Vec<Atom>,
+}
+
+impl Printer<'_> {
+    fn print(&mut self, mal_type: &MalType, depth: usize) -> String {
+        match mal_type {
+            MalType::List(seq) => self.collection(seq, "(", ")", depth),
+            MalType::Vector(seq) => self.collection(seq, "[", "]", depth),
+            MalType::Dictionary(seq) => self.dictionary(seq, depth),
+            MalType::Set(set) => {
+                let items: Vec<MalType> = set.iter().cloned().collect();
+                self.collection(&items, "#{", "}", depth)
+            }
+            MalType::WithMeta(var, meta) => {
+                let var = self.print_readably(var, depth);
+                let meta = self.print_readably(meta, depth);
+                format!("(with-meta {meta} {var})")
+            }
+            MalType::Func(_)
+            | MalType::MalFunc { .. }
+            | MalType::Compiled(_)
+            | MalType::Native(_) => "#<function>".to_string(),
+            MalType::Analyzed(_) => "#<analyzed>".to_string(),
+            MalType::Error(e) => format!("Error: {}", self.print_readably(e, depth)),
+            MalType::Future(f) => {
+                if f.is_done() {
+                    "#<future done>".to_string()
+                } else {
+                    "#<future pending>".to_string()
+                }
+            }
+            MalType::Isolate(i) => {
+                if i.is_done() {
+                    "#<isolate done>".to_string()
+                } else {
+                    "#<isolate>".to_string()
+                }
+            }
+            MalType::LazySeq(_) if self.options.too_deep(depth) => "#".to_string(),
+            MalType::LazySeq(_) => {
+                let realized = match self.options.length {
+                    Some(length) => seq::take(length + 1, mal_type),
+                    None => seq::to_vec(mal_type),
+                };
+                match realized {
+                    Ok(seq) => self.collection(&seq, "(", ")", depth),
+                    Err(e) => self.print(&e, depth),
+                }
+            }
+            MalType::Reduced(value) => format!("#<reduced {}>", self.print_readably(value, depth)),
+            MalType::Nil => "nil".to_string(),
+            MalType::True => "true".to_string(),
+            MalType::False => "false".to_string(),
+            MalType::Symbol(s) => s.to_string(),
+            MalType::Number(n) => format!("{n}"),
+            MalType::Char(c) => {
+                if self.readably {
+                    char_literal(*c)
+                } else {
+                    c.to_string()
+                }
+            }
+            MalType::Regex(re) => {
+                if self.readably {
+                    regex_literal(re.as_str())
+                } else {
+                    re.as_str().to_owned()
+                }
+            }
+            MalType::String(s) => {
+                if self.readably {
+                    format!("\"{}\"", escape_str(s))
+                } else {
+                    s.clone()
+                }
+            }
+            MalType::Atom(a) => {
+                if self.options.atom_ids || self.atoms.iter().any(|outer| Rc::ptr_eq(outer, a)) {
+                    return atom_id(a);
+                }
+                if self.options.too_deep(depth) {
+                    return "#".to_string();
+                }
+
+                self.atoms.push(a.clone());
+                let value = self.print_readably(a.deref().borrow().deref(), depth + 1);
+                self.atoms.pop();
+                format!("(atom {})", value)
             }
         }
-        MalType::Atom(a) => {
-            let atom = print_string(a.deref().borrow().deref(), true);
-            format!("(atom {})", atom)
+    }
+
+    /// Prints `mal_type` readably, whatever the printer does otherwise.
+    fn print_readably(&mut self, mal_type: &MalType, depth: usize) -> String {
+        let readably = std::mem::replace(&mut self.readably, true);
+        let res = self.print(mal_type, depth);
+        self.readably = readably;
+        res
+    }
+
+    fn collection(&mut self, seq: &[MalType], prefix: &str, postfix: &str, depth: usize) -> String {
+        if self.options.too_deep(depth) {
+            return "#".to_string();
         }
+
+        let length = self.options.length.unwrap_or(usize::MAX);
+        let mut strs: Vec<String> = seq
+            .iter()
+            .take(length)
+            .map(|mt| self.print(mt, depth + 1))
+            .collect();
+        if seq.len() > length {
+            strs.push("...".to_string());
+        }
+        format!("{}{}{}", prefix, strs.join(" "), postfix)
+    }
+
+    fn dictionary(&mut self, seq: &[MalType], depth: usize) -> String {
+        if self.options.too_deep(depth) {
+            return "#".to_string();
+        }
+
+        let length = self.options.length.unwrap_or(usize::MAX);
+        let mut strs: Vec<String> = seq
+            .iter()
+            .take(length.saturating_mul(2))
+            .map(|mt| self.print(mt, depth + 1))
+            .collect();
+        if seq.len().div_ceil(2) > length {
+            strs.push("...".to_string());
+        }
+        format!("{{{}}}", strs.join(" "))
     }
 }
 
+/// A `#"..."` literal for a regex, with a backslash added before any quote
+/// that does not have one, as one built by `re-pattern` may.
+fn regex_literal(source: &str) -> String {
+    let mut literal = "#\"".to_owned();
+    let mut escaped = false;
+    for c in source.chars() {
+        if c == '"' && !escaped {
+            literal.push('\\');
+        }
+        escaped = c == '\\' && !escaped;
+        literal.push(c);
+    }
+    literal.push('"');
+    literal
+}
+
 fn escape_str(s: &str) -> String {
     s.chars()
         .map(|c| match c {
             '"' => "\\\"".to_string(),
             '\n' => "\\n".to_string(),
+            '\t' => "\\t".to_string(),
+            '\r' => "\\r".to_string(),
+            '\0' => "\\0".to_string(),
             '\\' => "\\\\".to_string(),
+            c if c.is_control() => format!("\\u{{{:x}}}", c as u32),
             _ => c.to_string(),
         })
         .collect::<Vec<String>>()
         .join("")
 }
 
+/// A character the way the reader reads it back.
+fn char_literal(c: char) -> String {
+    match c {
+        '\n' => "\\newline".to_string(),
+        ' ' => "\\space".to_string(),
+        '\t' => "\\tab".to_string(),
+        '\r' => "\\return".to_string(),
+        '\u{8}' => "\\backspace".to_string(),
+        '\u{c}' => "\\formfeed".to_string(),
+        '\0' => "\\nul".to_string(),
+        c if c.is_control() => format!("\\u{:04x}", c as u32),
+        c => format!("\\{c}"),
+    }
+}
+
 pub fn print_seq(
     seq: &[MalType],
     print_readably: bool,
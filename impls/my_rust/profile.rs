@@ -0,0 +1,344 @@
+//! Tracing function calls with `trace`, and profiling with `--profile`.
+//!
+//! `(trace f g)` redefines `f` and `g` as wrappers that print each call they
+//! get and what it returns, indented by how deeply traced calls nest, until
+//! `(untrace f g)` puts the originals back. The wrappers are plain functions,
+//! so builtins can be traced too, under every evaluator.
+//!
+//! `--profile` times each application of a Mal function, as the `stack` of
+//! the tree-walking evaluator sees it, and each call of a builtin, and counts
+//! the allocations made meanwhile with the `Counting` allocator. Without it,
+//! the allocator does not count and the calls are not followed, at the cost
+//! of checking one flag each time. When the
+//! interpreter exits, it prints a flat report of the time and allocations
+//! of each function to stderr, and writes the time spent in each stack of
+//! calls in the folded format `flamegraph.pl` and `inferno` read.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    fs,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    core::builtin_name, printer::print_string, stack, transducers::native, types::MalType,
+};
+
+/// Counts the allocations of the whole program while profiling.
+pub struct Counting;
+
+static PROFILING: AtomicBool = AtomicBool::new(false);
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+fn profiling() -> bool {
+    PROFILING.load(Ordering::Relaxed)
+}
+
+fn count() {
+    if profiling() {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count();
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+fn allocations() -> u64 {
+    ALLOCATIONS.load(Ordering::Relaxed)
+}
+
+/// The calls of one function, builtin or not.
+#[derive(Default)]
+struct Stats {
+    builtin: bool,
+    calls: u64,
+    /// Counting the calls it made, but only once for recursive calls.
+    total: Duration,
+    own: Duration,
+    allocations: u64,
+    own_allocations: u64,
+}
+
+/// A stack of calls, as the path from the top level in a tree of them.
+struct Node {
+    name: String,
+    parent: Option<usize>,
+    children: HashMap<String, usize>,
+    own: Duration,
+}
+
+/// A call in progress.
+struct Call {
+    node: usize,
+    start: Instant,
+    allocations: u64,
+    /// What the calls it made took.
+    children: Duration,
+    child_allocations: u64,
+}
+
+#[derive(Default)]
+struct Profile {
+    functions: HashMap<String, Stats>,
+    nodes: Vec<Node>,
+    /// The nodes called from the top level.
+    roots: HashMap<String, usize>,
+    calls: Vec<Call>,
+    /// The names of the builtins seen so far, by address.
+    builtins: HashMap<usize, String>,
+}
+
+thread_local! {
+    static PROFILE: RefCell<Option<Profile>> = const { RefCell::new(None) };
+    /// How deeply the traced calls in progress nest.
+    static TRACE_DEPTH: Cell<usize> = const { Cell::new(0) };
+    /// The traced functions, and what they were before.
+    static TRACED: RefCell<Vec<(String, MalType)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Prints the report and writes the folded stacks to `path` when dropped,
+/// at the end of `main`.
+pub struct Report {
+    path: String,
+}
+
+/// Starts profiling this thread.
+pub fn start(path: &str) -> Report {
+    PROFILE.with(|p| p.replace(Some(Profile::default())));
+    PROFILING.store(true, Ordering::Relaxed);
+    Report {
+        path: path.to_owned(),
+    }
+}
+
+impl Profile {
+    fn enter(&mut self, name: String, builtin: bool) {
+        let parent = self.calls.last().map(|call| call.node);
+        let next = self.nodes.len();
+        let siblings = match parent {
+            Some(parent) => &mut self.nodes[parent].children,
+            None => &mut self.roots,
+        };
+        let node = match siblings.get(&name) {
+            Some(node) => *node,
+            None => {
+                siblings.insert(name.clone(), next);
+                self.nodes.push(Node {
+                    name: name.clone(),
+                    parent,
+                    children: HashMap::new(),
+                    own: Duration::ZERO,
+                });
+                next
+            }
+        };
+
+        self.functions.entry(name).or_default().builtin = builtin;
+        self.calls.push(Call {
+            node,
+            start: Instant::now(),
+            allocations: allocations(),
+            children: Duration::ZERO,
+            child_allocations: 0,
+        });
+    }
+
+    fn leave(&mut self) {
+        let Some(call) = self.calls.pop() else {
+            return;
+        };
+        let elapsed = call.start.elapsed();
+        let allocated = allocations() - call.allocations;
+
+        let name = &self.nodes[call.node].name;
+        let recursive = self
+            .calls
+            .iter()
+            .any(|outer| self.nodes[outer.node].name == *name);
+        let stats = self.functions.get_mut(name).unwrap();
+        stats.calls += 1;
+        stats.own += elapsed.saturating_sub(call.children);
+        stats.own_allocations += allocated - call.child_allocations;
+        if !recursive {
+            stats.total += elapsed;
+            stats.allocations += allocated;
+        }
+        self.nodes[call.node].own += elapsed.saturating_sub(call.children);
+
+        if let Some(caller) = self.calls.last_mut() {
+            caller.children += elapsed;
+            caller.child_allocations += allocated;
+        }
+    }
+
+    /// The names of the calls from the top level down to `node`.
+    fn path(&self, node: usize) -> String {
+        let mut names = vec![self.nodes[node].name.as_str()];
+        let mut parent = self.nodes[node].parent;
+        while let Some(node) = parent {
+            names.push(&self.nodes[node].name);
+            parent = self.nodes[node].parent;
+        }
+        names.reverse();
+        names.join(";")
+    }
+}
+
+fn with_profile(f: impl FnOnce(&mut Profile)) {
+    if !profiling() {
+        return;
+    }
+    PROFILE.with(|p| {
+        if let Some(profile) = p.borrow_mut().as_mut() {
+            f(profile)
+        }
+    })
+}
+
+/// Starts the application of a Mal function by `call`.
+pub fn enter(call: &MalType) {
+    with_profile(|profile| profile.enter(stack::name(call), false));
+}
+
+/// Starts a call of the builtin `func`.
+pub fn enter_builtin(func: fn(Vec<MalType>) -> MalType) {
+    with_profile(|profile| {
+        let name = profile
+            .builtins
+            .entry(func as usize)
+            .or_insert_with(|| builtin_name(func).unwrap_or_else(|| "builtin".to_owned()))
+            .clone();
+        profile.enter(name, true);
+    });
+}
+
+/// Ends the innermost call.
+pub fn leave() {
+    with_profile(Profile::leave);
+}
+
+impl Drop for Report {
+    fn drop(&mut self) {
+        let Some(mut profile) = PROFILE.with(|p| p.take()) else {
+            return;
+        };
+        PROFILING.store(false, Ordering::Relaxed);
+        while !profile.calls.is_empty() {
+            profile.leave();
+        }
+
+        let mut functions: Vec<(&String, &Stats)> = profile.functions.iter().collect();
+        functions.sort_by(|(a, x), (b, y)| y.own.cmp(&x.own).then(a.cmp(b)));
+
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        eprintln!(
+            "{:>10} {:>12} {:>12} {:>12} {:>12}  function",
+            "calls", "total ms", "self ms", "allocs", "self allocs"
+        );
+        for (name, stats) in functions {
+            let kind = if stats.builtin { " (builtin)" } else { "" };
+            eprintln!(
+                "{:>10} {:>12.3} {:>12.3} {:>12} {:>12}  {name}{kind}",
+                stats.calls,
+                ms(stats.total),
+                ms(stats.own),
+                stats.allocations,
+                stats.own_allocations
+            );
+        }
+
+        let mut stacks: Vec<String> = (0..profile.nodes.len())
+            .filter(|node| profile.nodes[*node].own.as_micros() > 0)
+            .map(|node| {
+                let micros = profile.nodes[node].own.as_micros();
+                format!("{} {micros}\n", profile.path(node))
+            })
+            .collect();
+        stacks.sort();
+        match fs::write(&self.path, stacks.concat()) {
+            Ok(()) => eprintln!("Wrote the folded stacks, in microseconds, to {}", self.path),
+            Err(err) => eprintln!("Error: can't write {}: {err}", self.path),
+        }
+    }
+}
+
+/// `(trace* 'name f)` gives a function that prints its calls and what they
+/// return, and otherwise calls `f`, for the `trace` macro to define as
+/// `name`. Tracing a traced function gives it back as it is.
+pub fn trace(args: Vec<MalType>) -> MalType {
+    let [MalType::Symbol(name), f] = args.as_slice() else {
+        let message = "trace* takes the name of a function and the function".to_owned();
+        return MalType::Error(Box::new(MalType::String(message)));
+    };
+    if TRACED.with(|t| t.borrow().iter().any(|(traced, _)| traced == name)) {
+        return f.clone();
+    }
+    if f.is_macro() {
+        let message = format!("can't trace {name}, which is a macro");
+        return MalType::Error(Box::new(MalType::String(message)));
+    }
+    let (MalType::Func(_) | MalType::MalFunc { .. } | MalType::Compiled(_) | MalType::Native(_)) =
+        f
+    else {
+        let message = format!("can't trace {name}, which is not a function");
+        return MalType::Error(Box::new(MalType::String(message)));
+    };
+
+    TRACED.with(|t| t.borrow_mut().push((name.clone(), f.clone())));
+    let (name, f) = (name.clone(), f.clone());
+    native(move |args| {
+        let depth = TRACE_DEPTH.with(|d| d.replace(d.get() + 1));
+        let indent = "| ".repeat(depth);
+        let mut call = vec![MalType::Symbol(name.clone())];
+        call.extend(args.iter().cloned());
+        println!(
+            "TRACE: {indent}{}",
+            print_string(&MalType::List(call), true)
+        );
+
+        let res = f.apply(args);
+        TRACE_DEPTH.with(|d| d.set(depth));
+        match &res {
+            MalType::Error(err) => println!("TRACE: {indent}=> threw {}", print_string(err, true)),
+            res => println!("TRACE: {indent}=> {}", print_string(res, true)),
+        }
+        res
+    })
+}
+
+/// `(untrace* 'name f)` gives back what `name` was before it was traced, or
+/// `f` if it is not traced.
+pub fn untrace(args: Vec<MalType>) -> MalType {
+    let [MalType::Symbol(name), f] = args.as_slice() else {
+        let message = "untrace* takes the name of a function and the function".to_owned();
+        return MalType::Error(Box::new(MalType::String(message)));
+    };
+    TRACED.with(|t| {
+        let mut traced = t.borrow_mut();
+        match traced.iter().position(|(traced, _)| traced == name) {
+            Some(i) => traced.remove(i).1,
+            None => f.clone(),
+        }
+    })
+}
@@ -1,6 +1,213 @@
-use std::collections::VecDeque;
+use std::{cell::RefCell, collections::VecDeque, ops::Range, rc::Rc};
 
-use crate::{MalType, Operator, Token, TokenKind};
+use crate::{
+    cst::{self, Node, NodeKind},
+    pattern, print_string, MalType, Operator, Token, TokenKind,
+};
+
+/// The text of a file, or of a line typed at the REPL, that forms were read
+/// from.
+#[derive(Debug)]
+pub struct Source {
+    pub file: String,
+    pub text: String,
+    /// The offset each line starts at.
+    lines: Vec<usize>,
+}
+
+impl Source {
+    pub fn new(file: &str, text: &str) -> Rc<Source> {
+        let lines = [0]
+            .into_iter()
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Rc::new(Source {
+            file: file.to_owned(),
+            text: text.to_owned(),
+            lines,
+        })
+    }
+}
+
+/// Where a form was read: the bytes of it in its source, and where the forms
+/// in it were read, one for each element of the list, vector or dictionary
+/// it reads as. The evaluator carries it along with the form, as forms are
+/// copied as they are evaluated, for stack traces and the debugger.
+#[derive(Debug)]
+pub struct Pos {
+    pub source: Rc<Source>,
+    pub span: Range<usize>,
+    pub items: Vec<Rc<Pos>>,
+}
+
+impl Pos {
+    /// Where the `i`th element of the form was read.
+    pub fn item(&self, i: usize) -> Option<&Rc<Pos>> {
+        self.items.get(i)
+    }
+
+    /// The line and column the form starts at, from 1.
+    pub fn line_col(&self) -> (usize, usize) {
+        let lines = &self.source.lines;
+        let line = lines.partition_point(|start| *start <= self.span.start);
+        let start = lines[line - 1];
+        let col = self.source.text[start..self.span.start].chars().count() + 1;
+        (line, col)
+    }
+
+    pub fn text(&self) -> &str {
+        &self.source.text[self.span.clone()]
+    }
+
+    /// The form as it was read.
+    pub fn form(&self) -> MalType {
+        read_str(self.text())
+    }
+
+    /// Whether `other` was read from inside this form.
+    pub fn contains(&self, other: &Pos) -> bool {
+        Rc::ptr_eq(&self.source, &other.source)
+            && self.span.start <= other.span.start
+            && other.span.end <= self.span.end
+    }
+
+    fn leaf(source: &Rc<Source>, span: Range<usize>) -> Rc<Pos> {
+        Rc::new(Pos {
+            source: source.clone(),
+            span,
+            items: Vec::new(),
+        })
+    }
+}
+
+/// Reads the forms of `text`, which is the source of `file`, with where each
+/// of them was read.
+pub fn read_located(text: &str, file: &str) -> Result<Vec<(MalType, Rc<Pos>)>, MalType> {
+    read_source(&Source::new(file, text))
+}
+
+fn read_source(source: &Rc<Source>) -> Result<Vec<(MalType, Rc<Pos>)>, MalType> {
+    let cst = cst::parse(&source.text).map_err(|err| error(err.message))?;
+
+    cst.nodes
+        .iter()
+        .map(|node| match read_node(node) {
+            err @ MalType::Error(_) => Err(err),
+            form => Ok((form, position(node, source))),
+        })
+        .collect()
+}
+
+/// Reads the forms of the file `file`, with `text` as its source, as one
+/// `(do forms... nil)` form to evaluate.
+pub fn read_file(text: &str, file: &str) -> Result<(MalType, Rc<Pos>), MalType> {
+    let source = Source::new(file, text);
+    let forms = read_source(&source)?;
+    let empty = Pos::leaf(&source, 0..0);
+
+    let mut list = vec![MalType::Symbol("do".to_owned())];
+    let mut items = vec![empty.clone()];
+    for (form, pos) in forms {
+        list.push(form);
+        items.push(pos);
+    }
+    list.push(MalType::Nil);
+    items.push(empty);
+
+    let pos = Pos {
+        source,
+        span: 0..text.len(),
+        items,
+    };
+    Ok((MalType::List(list), Rc::new(pos)))
+}
+
+/// The position of `node`, parallel to the form `read_node` reads from it.
+fn position(node: &Node, source: &Rc<Source>) -> Rc<Pos> {
+    let items = match &node.kind {
+        NodeKind::Prefix(prefix, forms) if prefix != "^" => {
+            let start = node.span.start;
+            let symbol = Pos::leaf(source, start..start + prefix.len());
+            [symbol]
+                .into_iter()
+                .chain(forms.iter().map(|form| position(form, source)))
+                .collect()
+        }
+        NodeKind::Prefix(_, forms) => forms.iter().map(|form| position(form, source)).collect(),
+        NodeKind::Collection { open, items, .. } if open != "#{" => {
+            items.iter().map(|item| position(item, source)).collect()
+        }
+        _ => Vec::new(),
+    };
+    Rc::new(Pos {
+        source: source.clone(),
+        span: node.span.clone(),
+        items,
+    })
+}
+
+/// The positions for `expanded`, which the macro call `call` read at `pos`
+/// expanded to: the forms of the call it has are where the call has them,
+/// and the forms the macro made are where the call is.
+pub fn relocate(expanded: &MalType, call: &MalType, pos: &Rc<Pos>) -> Rc<Pos> {
+    fn parts<'a>(form: &'a MalType, pos: &Rc<Pos>, found: &mut Vec<(&'a MalType, Rc<Pos>)>) {
+        if let MalType::List(items) | MalType::Vector(items) | MalType::Dictionary(items) = form {
+            found.push((form, pos.clone()));
+            for (item, pos) in items.iter().zip(&pos.items) {
+                parts(item, pos, found);
+            }
+        }
+    }
+
+    fn relocated(form: &MalType, pos: &Rc<Pos>, found: &[(&MalType, Rc<Pos>)]) -> Rc<Pos> {
+        let (MalType::List(items) | MalType::Vector(items) | MalType::Dictionary(items)) = form
+        else {
+            return pos.clone();
+        };
+        if let Some((_, part)) = found.iter().find(|(part, _)| *part == form) {
+            return part.clone();
+        }
+        Rc::new(Pos {
+            source: pos.source.clone(),
+            span: pos.span.clone(),
+            items: items
+                .iter()
+                .map(|item| relocated(item, pos, found))
+                .collect(),
+        })
+    }
+
+    let mut found = Vec::new();
+    if let MalType::List(args) = call {
+        for (arg, pos) in args.iter().zip(&pos.items).skip(1) {
+            parts(arg, pos, &mut found);
+        }
+    }
+    relocated(expanded, pos, &found)
+}
+
+thread_local! {
+    /// A form about to be evaluated, by the address of its elements, and
+    /// where it was read.
+    static LOCATED: RefCell<Option<(*const MalType, Rc<Pos>)>> = const { RefCell::new(None) };
+}
+
+/// Tells the evaluator that `form`, which it is about to be given, was read
+/// at `pos`.
+pub fn locate(form: &MalType, pos: Rc<Pos>) {
+    if let MalType::List(items) | MalType::Vector(items) = form {
+        LOCATED.with(|l| l.replace(Some((items.as_ptr(), pos))));
+    }
+}
+
+/// Where `form` was read, if it is the form last given to `locate`.
+pub fn take_pos(form: &MalType) -> Option<Rc<Pos>> {
+    let (items, pos) = LOCATED.with(|l| l.borrow_mut().take())?;
+    match form {
+        MalType::List(list) | MalType::Vector(list) if list.as_ptr() == items => Some(pos),
+        _ => None,
+    }
+}
 
 pub fn read_str(source: &str) -> MalType {
     let mut tokens = tokenize(source);
@@ -9,6 +216,112 @@ pub fn read_str(source: &str) -> MalType {
     read_form(&mut tokens)
 }
 
+fn error(message: String) -> MalType {
+    MalType::Error(Box::new(MalType::String(message)))
+}
+
+/// Converts a node of a concrete syntax tree to the form `read_str` reads
+/// from its text, raising an error for the parts that do not parse.
+pub fn read_node(node: &Node) -> MalType {
+    match &node.kind {
+        NodeKind::Atom(text) => read_str(text),
+        NodeKind::Error(text) if text.starts_with(['"', '#']) => {
+            error("unterminated string".to_owned())
+        }
+        NodeKind::Error(text) => error(format!("unexpected '{text}'")),
+        NodeKind::Prefix(prefix, forms) => {
+            let expected = if prefix == "^" { 2 } else { 1 };
+            if forms.len() < expected {
+                return error(format!("expected a form after '{prefix}', got EOF"));
+            }
+
+            let forms = match read_nodes(forms) {
+                Ok(forms) => forms,
+                Err(err) => return err,
+            };
+            let symbol = match prefix.as_str() {
+                "'" => "quote",
+                "`" => "quasiquote",
+                "~" => "unquote",
+                "~@" => "splice-unquote",
+                "@" => "deref",
+                _ => {
+                    let [meta, form] = <[MalType; 2]>::try_from(forms).unwrap();
+                    return MalType::WithMeta(Box::new(meta), Box::new(form));
+                }
+            };
+            MalType::List(
+                [MalType::Symbol(symbol.to_owned())]
+                    .into_iter()
+                    .chain(forms)
+                    .collect(),
+            )
+        }
+        NodeKind::Collection {
+            open,
+            items,
+            closed,
+            ..
+        } => {
+            if !closed {
+                return error(format!("expected '{}', got EOF", NodeKind::close(open)));
+            }
+
+            let items = match read_nodes(items) {
+                Ok(items) => items,
+                Err(err) => return err,
+            };
+            match open.as_str() {
+                "(" => MalType::List(items),
+                "[" => MalType::Vector(items),
+                "#{" => MalType::Set(items.into_iter().collect()),
+                _ if items.len() % 2 != 0 => {
+                    error("a dictionary needs an even number of forms".to_owned())
+                }
+                _ => MalType::Dictionary(items),
+            }
+        }
+    }
+}
+
+/// Adds the errors `read_node` raises for the atoms of `node`, and for its
+/// dictionaries with an odd number of forms, to `errors` with their spans.
+pub fn read_errors(node: &Node, errors: &mut Vec<(Range<usize>, String)>) {
+    match &node.kind {
+        NodeKind::Atom(_) => {
+            if let MalType::Error(err) = read_node(node) {
+                errors.push((node.span.clone(), print_string(&err, false)));
+            }
+        }
+        NodeKind::Prefix(_, forms) => {
+            for form in forms {
+                read_errors(form, errors);
+            }
+        }
+        NodeKind::Collection { open, items, .. } => {
+            if open == "{" && items.len() % 2 != 0 {
+                let message = "a dictionary needs an even number of forms".to_owned();
+                errors.push((node.span.clone(), message));
+            }
+            for item in items {
+                read_errors(item, errors);
+            }
+        }
+        NodeKind::Error(_) => {}
+    }
+}
+
+/// Converts each node, or raises the first error found.
+pub fn read_nodes(nodes: &[Node]) -> Result<Vec<MalType>, MalType> {
+    nodes
+        .iter()
+        .map(|node| match read_node(node) {
+            err @ MalType::Error(_) => Err(err),
+            form => Ok(form),
+        })
+        .collect()
+}
+
 fn read_form(tokens: &mut VecDeque<Token>) -> MalType {
     match tokens.front().unwrap().kind {
         TokenKind::LeftParenthesis => {
@@ -25,6 +338,10 @@ fn read_form(tokens: &mut VecDeque<Token>) -> MalType {
             assert_eq!(dict.len() % 2, 0);
             MalType::Dictionary(dict)
         }
+        TokenKind::HashBrace => {
+            tokens.pop_front();
+            MalType::Set(read_collection(tokens, "}").into_iter().collect())
+        }
         TokenKind::Quote => {
             tokens.pop_front();
             MalType::List([MalType::Symbol("quote".to_owned()), read_form(tokens)].to_vec())
@@ -81,6 +398,8 @@ fn read_atom(tokens: &mut VecDeque<Token>) -> MalType {
             TokenKind::Identifier(id) if id.eq("nil") => MalType::Nil,
             TokenKind::Identifier(id) => MalType::Symbol(id),
             TokenKind::String(s) => MalType::String(s),
+            TokenKind::Char(c) => MalType::Char(c),
+            TokenKind::Regex(source) => pattern::compile(&source),
             TokenKind::EOF => MalType::Symbol("EOF".to_string()),
             _ => MalType::Symbol(token.kind.to_string()),
         },
@@ -139,6 +458,7 @@ fn tokenize(source: &str) -> VecDeque<Token> {
             }
             '/' => TokenKind::Operator(Operator::Slash),
             '"' => parse_string(c, &mut iter, &mut col),
+            '\\' => parse_char(&mut iter, &mut col),
             ';' => {
                 for nt in iter.by_ref() {
                     if '\n'.eq(&nt) {
@@ -153,6 +473,16 @@ fn tokenize(source: &str) -> VecDeque<Token> {
             ')' => TokenKind::RightParenthesis,
             '[' => TokenKind::LeftBracket,
             ']' => TokenKind::RightBracket,
+            '#' if iter.peek().is_some_and(|nt| '{'.eq(nt)) => {
+                iter.next();
+                col += 1;
+                TokenKind::HashBrace
+            }
+            '#' if iter.peek().is_some_and(|nt| '"'.eq(nt)) => {
+                iter.next();
+                col += 1;
+                parse_regex(&mut iter, &mut col)
+            }
             '{' => TokenKind::LeftBrace,
             '}' => TokenKind::RightBrace,
             '\'' => TokenKind::Quote,
@@ -189,49 +519,126 @@ fn tokenize(source: &str) -> VecDeque<Token> {
     tokens
 }
 
+/// Reads a string literal up to its closing quote. The escapes are `\\`,
+/// `\"`, `\n`, `\t`, `\r`, `\0` and `\u{XXXX}` for any code point, while a
+/// backslash before anything else is kept as is.
 fn parse_string(
     _c: char,
     iter: &mut std::iter::Peekable<std::str::Chars<'_>>,
     col: &mut usize,
 ) -> TokenKind {
-    let mut can_escape = false;
     let mut id = String::new();
-    while let Some(c) = iter.peek() {
-        // replace("\\\\", "\\")
-        // replace("\\\"", "\"")
-        // replace("\\n", "\n")
-        let ch = if can_escape {
-            match c {
-                '\\' => {
-                    id.pop();
-                    '\\'
-                }
-                'n' => {
-                    id.pop();
-                    '\n'
+    while let Some(c) = iter.next() {
+        *col += 1;
+
+        match c {
+            '"' => return TokenKind::String(id),
+            '\\' => {
+                let Some(escaped) = iter.next() else {
+                    break;
+                };
+                *col += 1;
+
+                match escaped {
+                    '\\' | '"' => id.push(escaped),
+                    'n' => id.push('\n'),
+                    't' => id.push('\t'),
+                    'r' => id.push('\r'),
+                    '0' => id.push('\0'),
+                    'u' if iter.peek().is_some_and(|nt| '{'.eq(nt)) => {
+                        let mut code = String::new();
+                        for nt in iter.by_ref() {
+                            *col += 1;
+                            code.push(nt);
+                            if nt == '}' {
+                                break;
+                            }
+                        }
+
+                        let codepoint = code
+                            .strip_prefix('{')
+                            .and_then(|c| c.strip_suffix('}'))
+                            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                            .and_then(char::from_u32);
+                        match codepoint {
+                            Some(c) => id.push(c),
+                            None => id.push_str(&format!("\\u{code}")),
+                        }
+                    }
+                    _ => {
+                        id.push('\\');
+                        id.push(escaped);
+                    }
                 }
-                '\"' => {
-                    id.pop();
-                    '\"'
+            }
+            _ => id.push(c),
+        }
+    }
+    TokenKind::EOF
+}
+
+/// Reads a regex literal after its `#"`, up to the closing quote. Backslashes
+/// are kept for the regex itself to interpret, so `#"\d+"` is written as is,
+/// and `\"` does not end the literal.
+fn parse_regex(iter: &mut std::iter::Peekable<std::str::Chars<'_>>, col: &mut usize) -> TokenKind {
+    let mut source = String::new();
+    while let Some(c) = iter.next() {
+        *col += 1;
+
+        match c {
+            '"' => return TokenKind::Regex(source),
+            '\\' => {
+                source.push(c);
+                if let Some(escaped) = iter.next() {
+                    *col += 1;
+                    source.push(escaped);
                 }
-                _ => *c,
             }
-        } else {
-            *c
-        };
+            _ => source.push(c),
+        }
+    }
+    TokenKind::EOF
+}
+
+/// Reads a character literal after its backslash: `\a`, `\λ`, a name like
+/// `\newline`, or a code point like `\u03bb`. The first character is taken
+/// as is, so that `\(` or `\;` are characters too.
+fn parse_char(iter: &mut std::iter::Peekable<std::str::Chars<'_>>, col: &mut usize) -> TokenKind {
+    let Some(first) = iter.next() else {
+        return TokenKind::Identifier("\\".to_owned());
+    };
+    *col += 1;
 
-        let is_str_ending = !can_escape && c.eq(&'"');
-        can_escape = !can_escape && c.eq(&'\\');
+    let mut name = first.to_string();
+    while let Some(c) = iter.peek() {
+        if is_char_symbol_separator(c) {
+            break;
+        }
+        name.push(*c);
         iter.next();
         *col += 1;
+    }
 
-        if is_str_ending {
-            return TokenKind::String(id);
-        }
+    let c = match name.as_str() {
+        _ if name.chars().count() == 1 => Some(first),
+        "newline" => Some('\n'),
+        "space" => Some(' '),
+        "tab" => Some('\t'),
+        "return" => Some('\r'),
+        "backspace" => Some('\u{8}'),
+        "formfeed" => Some('\u{c}'),
+        "nul" => Some('\0'),
+        _ => name
+            .strip_prefix('u')
+            .filter(|hex| hex.len() == 4)
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .and_then(char::from_u32),
+    };
 
-        id.push(ch);
+    match c {
+        Some(c) => TokenKind::Char(c),
+        None => TokenKind::Identifier(format!("\\{name}")),
     }
-    TokenKind::EOF
 }
 
 fn parse_symbol(
@@ -0,0 +1,474 @@
+//! Lazy sequences, and the seq abstraction over everything that can be walked
+//! one element at a time.
+//!
+//! A `LazySeq` holds a thunk that is only run when its first element is asked
+//! for, and remembers the result. That result is any seqable value: nil or an
+//! empty collection for the end, a collection, or another `LazySeq`, typically
+//! one built by `cons` with a lazy tail. Lists, vectors, sets, strings
+//! (one-character strings), dictionaries (`[key value]` vectors) and nil are all
+//! seqable, and `step` splits any of them into a first element and the rest.
+
+use std::{cell::RefCell, fmt::Debug, rc::Rc};
+
+use crate::{gc, types::*};
+
+pub enum Thunk {
+    /// A Mal function taking no arguments, from `lazy-seq`.
+    Mal(MalType),
+    /// A sequence function implemented in Rust, like `map` on a lazy input.
+    Native(Rc<dyn Fn() -> MalType>),
+}
+
+/// A seq split into its first element and the rest, or the end of it.
+#[derive(Debug, Clone)]
+pub enum Step {
+    Empty,
+    Cons(MalType, MalType),
+}
+
+enum State {
+    Pending(Thunk),
+    Realizing,
+    Realized(Step),
+}
+
+pub struct LazySeq {
+    state: RefCell<State>,
+}
+
+impl Debug for LazySeq {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LazySeq").finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for LazySeq {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Eq for LazySeq {}
+
+impl LazySeq {
+    pub fn new(thunk: MalType) -> LazySeq {
+        LazySeq {
+            state: RefCell::new(State::Pending(Thunk::Mal(thunk))),
+        }
+    }
+
+    pub fn native(thunk: impl Fn() -> MalType + 'static) -> LazySeq {
+        LazySeq {
+            state: RefCell::new(State::Pending(Thunk::Native(Rc::new(thunk)))),
+        }
+    }
+
+    /// An already realized seq of `first` followed by the seqable `rest`.
+    pub fn cons(first: MalType, rest: MalType) -> LazySeq {
+        LazySeq {
+            state: RefCell::new(State::Realized(Step::Cons(first, rest))),
+        }
+    }
+
+    pub fn is_realized(&self) -> bool {
+        matches!(*self.state.borrow(), State::Realized(_))
+    }
+
+    /// Runs the thunk the first time, and returns the first step of the seq.
+    /// If the thunk raises an error it is kept, to be run again next time.
+    pub fn step(&self) -> Result<Step, MalType> {
+        let state = std::mem::replace(&mut *self.state.borrow_mut(), State::Realizing);
+        let thunk = match state {
+            State::Realized(step) => {
+                *self.state.borrow_mut() = State::Realized(step.clone());
+                return Ok(step);
+            }
+            State::Realizing => {
+                return Err(error("a lazy sequence depends on its own value".to_owned()));
+            }
+            State::Pending(thunk) => thunk,
+        };
+
+        let value = match &thunk {
+            Thunk::Mal(f) => f.apply(Vec::new()),
+            Thunk::Native(f) => f(),
+        };
+
+        match step(&value) {
+            Ok(step) => {
+                *self.state.borrow_mut() = State::Realized(step.clone());
+                Ok(step)
+            }
+            Err(err) => {
+                *self.state.borrow_mut() = State::Pending(thunk);
+                Err(err)
+            }
+        }
+    }
+
+    /// Calls `visit` with the values held by the seq, for the cycle
+    /// collector: the thunk of a pending one, or the first element and rest of
+    /// a realized one. Returns false if the seq is being realized, and so
+    /// cannot be inspected.
+    pub fn children(&self, visit: &mut dyn FnMut(&MalType)) -> bool {
+        let Ok(state) = self.state.try_borrow() else {
+            return false;
+        };
+        match &*state {
+            State::Pending(Thunk::Mal(f)) => visit(f),
+            State::Realized(Step::Cons(first, rest)) => {
+                visit(first);
+                visit(rest);
+            }
+            _ => {}
+        }
+        true
+    }
+
+    /// The rest of a realized seq, without realizing anything.
+    fn realized_rest(&self) -> Option<MalType> {
+        match &*self.state.try_borrow().ok()? {
+            State::Realized(Step::Cons(_, rest)) => Some(rest.clone()),
+            _ => None,
+        }
+    }
+
+    /// Takes the rest of a realized seq out of it.
+    fn take_rest(&mut self) -> Option<MalType> {
+        match self.state.get_mut() {
+            State::Realized(Step::Cons(_, rest)) => Some(std::mem::replace(rest, MalType::Nil)),
+            _ => None,
+        }
+    }
+}
+
+/// Unlinks the realized rest of the seq one cell at a time, where dropping it
+/// the usual way would recurse once per element, and overflow the stack on
+/// a long one.
+impl Drop for LazySeq {
+    fn drop(&mut self) {
+        let mut rest = self.take_rest();
+        while let Some(MalType::LazySeq(seq)) = rest {
+            rest = match Rc::try_unwrap(seq) {
+                Ok(mut seq) => seq.take_rest(),
+                // still used elsewhere, so not dropped now
+                Err(_) => None,
+            };
+        }
+    }
+}
+
+fn error(message: String) -> MalType {
+    MalType::Error(Box::new(MalType::String(message)))
+}
+
+pub fn is_seqable(value: &MalType) -> bool {
+    matches!(
+        value,
+        MalType::Nil
+            | MalType::List(_)
+            | MalType::Vector(_)
+            | MalType::String(_)
+            | MalType::Dictionary(_)
+            | MalType::Set(_)
+            | MalType::LazySeq(_)
+    )
+}
+
+/// Splits a seqable value into its first element and the rest.
+pub fn step(value: &MalType) -> Result<Step, MalType> {
+    match value {
+        MalType::Error(_) => Err(value.clone()),
+        MalType::Nil => Ok(Step::Empty),
+        MalType::List(items) | MalType::Vector(items) => match items.split_first() {
+            Some((first, rest)) => Ok(Step::Cons(first.clone(), MalType::List(rest.to_vec()))),
+            None => Ok(Step::Empty),
+        },
+        MalType::String(s) => {
+            let mut chars = s.chars();
+            match chars.next() {
+                Some(c) => Ok(Step::Cons(
+                    MalType::String(c.to_string()),
+                    MalType::String(chars.as_str().to_owned()),
+                )),
+                None => Ok(Step::Empty),
+            }
+        }
+        MalType::Dictionary(items) => match items.as_slice() {
+            [key, value, rest @ ..] => Ok(Step::Cons(
+                MalType::Vector([key.clone(), value.clone()].to_vec()),
+                MalType::Dictionary(rest.to_vec()),
+            )),
+            _ => Ok(Step::Empty),
+        },
+        MalType::Set(items) => match items.first() {
+            Some(first) => Ok(Step::Cons(
+                first.clone(),
+                MalType::Set(items.iter().skip(1).cloned().collect()),
+            )),
+            None => Ok(Step::Empty),
+        },
+        MalType::LazySeq(seq) => seq.step(),
+        _ => Err(error(format!(
+            "{} is not seqable",
+            MalType::discriminant_name(value)
+        ))),
+    }
+}
+
+/// Walks the elements of a seqable value, realizing them one at a time.
+pub enum Iter<'a> {
+    Slice(std::slice::Iter<'a, MalType>),
+    Owned(std::vec::IntoIter<MalType>),
+    Steps(Option<MalType>),
+}
+
+impl Iterator for Iter<'_> {
+    type Item = Result<MalType, MalType>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Iter::Slice(items) => items.next().cloned().map(Ok),
+            Iter::Owned(items) => items.next().map(Ok),
+            Iter::Steps(rest) => match step(rest.as_ref()?) {
+                Ok(Step::Cons(first, next)) => {
+                    *rest = Some(next);
+                    Some(Ok(first))
+                }
+                Ok(Step::Empty) => {
+                    *rest = None;
+                    None
+                }
+                Err(err) => {
+                    *rest = None;
+                    Some(Err(err))
+                }
+            },
+        }
+    }
+}
+
+pub fn iter(value: &MalType) -> Iter<'_> {
+    match value {
+        MalType::List(items) | MalType::Vector(items) => Iter::Slice(items.iter()),
+        MalType::String(_) | MalType::Dictionary(_) | MalType::Set(_) => {
+            Iter::Owned(to_vec(value).unwrap_or_default().into_iter())
+        }
+        _ => Iter::Steps(Some(value.clone())),
+    }
+}
+
+/// Realizes all the elements of a seqable value.
+pub fn to_vec(value: &MalType) -> Result<Vec<MalType>, MalType> {
+    match value {
+        MalType::List(items) | MalType::Vector(items) => Ok(items.clone()),
+        MalType::String(s) => Ok(s.chars().map(|c| MalType::String(c.to_string())).collect()),
+        MalType::Dictionary(items) => Ok(items
+            .chunks(2)
+            .map(|pair| MalType::Vector(pair.to_vec()))
+            .collect()),
+        MalType::Set(items) => Ok(items.iter().cloned().collect()),
+        _ => {
+            let mut items = Vec::new();
+            let mut rest = value.clone();
+            while let Step::Cons(first, next) = step(&rest)? {
+                items.push(first);
+                rest = next;
+            }
+            Ok(items)
+        }
+    }
+}
+
+/// How many elements of a seqable value are already realized, counting up to
+/// `limit` at most.
+pub fn realized_count(value: &MalType, limit: usize) -> usize {
+    let mut count = 0;
+    let mut rest = value.clone();
+    while count < limit {
+        rest = match &rest {
+            MalType::LazySeq(seq) => match seq.realized_rest() {
+                Some(next) => next,
+                None => break,
+            },
+            MalType::List(items) | MalType::Vector(items) => return limit.min(count + items.len()),
+            _ => break,
+        };
+        count += 1;
+    }
+    count
+}
+
+/// Skips `n` elements of a seqable value, realizing only those.
+pub fn drop(n: usize, value: &MalType) -> Result<MalType, MalType> {
+    let mut rest = value.clone();
+    for _ in 0..n {
+        match step(&rest)? {
+            Step::Cons(_, next) => rest = next,
+            Step::Empty => break,
+        }
+    }
+    Ok(rest)
+}
+
+/// Realizes at most `n` elements of a seqable value.
+pub fn take(n: usize, value: &MalType) -> Result<Vec<MalType>, MalType> {
+    let mut items = Vec::new();
+    let mut rest = value.clone();
+    while items.len() < n {
+        match step(&rest)? {
+            Step::Cons(first, next) => {
+                items.push(first);
+                rest = next;
+            }
+            Step::Empty => break,
+        }
+    }
+    Ok(items)
+}
+
+pub fn lazy(thunk: impl Fn() -> MalType + 'static) -> MalType {
+    MalType::LazySeq(gc::lazy_seq(LazySeq::native(thunk)))
+}
+
+pub fn cons(first: MalType, rest: MalType) -> MalType {
+    MalType::LazySeq(gc::lazy_seq(LazySeq::cons(first, rest)))
+}
+
+/// `(concat colls...)` where at least one of the colls is lazy.
+pub fn concat(colls: Vec<MalType>) -> MalType {
+    lazy(move || {
+        for (i, coll) in colls.iter().enumerate() {
+            match coll {
+                MalType::LazySeq(_) => match step(coll) {
+                    Ok(Step::Cons(first, next)) => {
+                        let mut colls = colls[i..].to_vec();
+                        colls[0] = next;
+                        return cons(first, concat(colls));
+                    }
+                    Ok(Step::Empty) => {}
+                    Err(err) => return err,
+                },
+                _ => match to_vec(coll) {
+                    Ok(items) if items.is_empty() => {}
+                    Ok(items) => {
+                        let tail = concat(colls[i + 1..].to_vec());
+                        return items.into_iter().rev().fold(tail, |tail, item| cons(item, tail));
+                    }
+                    Err(err) => return err,
+                },
+            }
+        }
+        MalType::Nil
+    })
+}
+
+/// `(map f colls...)` where at least one of the colls is lazy.
+pub fn map(f: MalType, colls: Vec<MalType>) -> MalType {
+    lazy(move || {
+        let mut firsts = Vec::new();
+        let mut rests = Vec::new();
+        for coll in &colls {
+            match step(coll) {
+                Ok(Step::Cons(first, rest)) => {
+                    firsts.push(first);
+                    rests.push(rest);
+                }
+                Ok(Step::Empty) => return MalType::Nil,
+                Err(err) => return err,
+            }
+        }
+
+        match f.apply(firsts) {
+            err @ MalType::Error(_) => err,
+            value => cons(value, map(f.clone(), rests)),
+        }
+    })
+}
+
+/// `(filter pred coll)` where coll is lazy.
+pub fn filter(pred: MalType, coll: MalType) -> MalType {
+    lazy(move || {
+        let mut rest = coll.clone();
+        loop {
+            let (first, next) = match step(&rest) {
+                Ok(Step::Cons(first, next)) => (first, next),
+                Ok(Step::Empty) => return MalType::Nil,
+                Err(err) => return err,
+            };
+
+            match pred.apply([first.clone()].to_vec()) {
+                err @ MalType::Error(_) => return err,
+                MalType::Nil | MalType::False => rest = next,
+                _ => return cons(first, filter(pred.clone(), next)),
+            }
+        }
+    })
+}
+
+/// The infinite seq `x`, `(f x)`, `(f (f x))`...
+pub fn iterate(f: MalType, x: MalType) -> MalType {
+    let next = {
+        let x = x.clone();
+        lazy(move || match f.apply([x.clone()].to_vec()) {
+            err @ MalType::Error(_) => err,
+            value => iterate(f.clone(), value),
+        })
+    };
+    cons(x, next)
+}
+
+/// The infinite seq of `x` repeated.
+pub fn repeat(x: MalType) -> MalType {
+    let rest = {
+        let x = x.clone();
+        lazy(move || repeat(x.clone()))
+    };
+    cons(x, rest)
+}
+
+/// The infinite seq of numbers from `start`, by `step`.
+pub fn range_from(start: i64, by: i64) -> MalType {
+    lazy(move || cons(MalType::Number(start), range_from(start + by, by)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbers(values: Vec<MalType>) -> Vec<i64> {
+        values
+            .into_iter()
+            .map(|value| match value {
+                MalType::Number(n) => n,
+                other => panic!("not a number: {other:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn drops_a_long_realized_seq() {
+        let seq = range_from(0, 1);
+        assert_eq!(take(1_000_000, &seq).unwrap().len(), 1_000_000);
+        std::mem::drop(seq);
+    }
+
+    #[test]
+    fn concat_of_an_infinite_seq_is_lazy() {
+        let nine = MalType::Vector(vec![MalType::Number(9)]);
+        let seq = concat(vec![range_from(0, 1), nine]);
+        assert_eq!(numbers(take(3, &seq).unwrap()), [0, 1, 2]);
+    }
+
+    #[test]
+    fn walks_strings_dictionaries_and_nil() {
+        let string = |s: &str| MalType::String(s.to_owned());
+        assert_eq!(to_vec(&string("ab")).unwrap(), [string("a"), string("b")]);
+
+        let dict = MalType::Dictionary(vec![MalType::Symbol(":a".to_owned()), MalType::Number(1)]);
+        let entry = MalType::Vector(vec![MalType::Symbol(":a".to_owned()), MalType::Number(1)]);
+        assert_eq!(to_vec(&dict).unwrap(), [entry]);
+
+        assert_eq!(to_vec(&MalType::Nil).unwrap(), []);
+    }
+}
@@ -0,0 +1,325 @@
+//! A shadow call stack of the Mal function applications in progress in the
+//! tree-walking evaluator, for stack traces and the debugger.
+//!
+//! Each `eval` call runs inside a `Scope`, which starts out with the frames of
+//! its caller and drops the ones it added when it ends. A tail call replaces
+//! the frame it is made from, like it replaces its `eval` loop iteration, and
+//! the frame keeps count of the calls it replaced as elided.
+//!
+//! When an error is raised, the stack as it stands is captured as its trace,
+//! with the call to the builtin that raised it, like `throw`, on top. `try*`
+//! keeps the trace of the last few errors it caught for `(ex-stack e)`, and
+//! the REPL prints the trace of an error nobody caught.
+//!
+//! A frame keeps where the call it is for was read, and the form and its
+//! name are only read back from the source for a trace. A call the reader
+//! did not give, which a macro or `eval` made, keeps the name of the function
+//! it applies instead.
+//!
+//! The `profile` follows the frames as they come and go.
+//!
+//! Only the tree-walker keeps the stack: the analyzer and the VM of `--analyze`
+//! and `--vm` do not carry where their code was read, so the errors they raise
+//! have no trace, `ex-stack` gives nil for them and the REPL prints the error
+//! alone.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    rc::Rc,
+};
+
+use crate::{printer::print_string, profile, reader::Pos, types::MalType};
+
+/// How many caught errors `ex-stack` knows the trace of.
+const CAUGHT: usize = 16;
+
+/// A Mal function application in progress.
+pub struct Frame {
+    /// Tells frames apart, as a tail call gives a new one at the same depth.
+    pub id: usize,
+    pub call: Call,
+    /// How many tail calls led to this one.
+    pub elided: usize,
+}
+
+/// The call of an application.
+pub enum Call {
+    /// A call read from source, where it was read.
+    Read(Rc<Pos>),
+    /// A call made by a macro or `eval`, by the name of the function.
+    Made(String),
+}
+
+thread_local! {
+    static FRAMES: RefCell<Vec<Frame>> = const { RefCell::new(Vec::new()) };
+    static NEXT_ID: Cell<usize> = const { Cell::new(0) };
+    /// How many `eval` calls are in progress.
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+    /// The error being raised, and its trace.
+    static RAISED: RefCell<Option<(MalType, MalType)>> = const { RefCell::new(None) };
+    /// The values `try*` caught last, and their traces.
+    static CAUGHT_TRACES: RefCell<VecDeque<(MalType, MalType)>> =
+        const { RefCell::new(VecDeque::new()) };
+}
+
+/// The state of one `eval` call, which ends when it is dropped.
+pub struct Scope {
+    frames: usize,
+}
+
+/// Starts an `eval` call.
+pub fn enter() -> Scope {
+    DEPTH.with(|d| d.set(d.get() + 1));
+    Scope {
+        frames: FRAMES.with(|f| f.borrow().len()),
+    }
+}
+
+impl Scope {
+    /// Starts the application of a Mal function by `call`, read at `pos`,
+    /// which replaces the one this `eval` call is in, if any, as a tail call.
+    pub fn apply(&self, call: &MalType, pos: Option<&Rc<Pos>>) {
+        let id = NEXT_ID.with(|n| n.replace(n.get() + 1));
+        FRAMES.with(|f| {
+            let mut frames = f.borrow_mut();
+            let mut frame = Frame {
+                id,
+                call: match pos {
+                    Some(pos) => Call::Read(pos.clone()),
+                    None => Call::Made(name(call)),
+                },
+                elided: 0,
+            };
+            if frames.len() > self.frames {
+                let replaced = frames.pop().unwrap();
+                frame.elided = replaced.elided + 1;
+                profile::leave();
+            }
+            profile::enter(call);
+            frames.push(frame);
+        });
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        DEPTH.with(|d| d.set(d.get() - 1));
+        FRAMES.with(|f| {
+            let mut frames = f.borrow_mut();
+            for _ in self.frames..frames.len() {
+                profile::leave();
+            }
+            frames.truncate(self.frames);
+        });
+    }
+}
+
+pub fn depth() -> usize {
+    DEPTH.with(|d| d.get())
+}
+
+/// How many applications are in progress, and the id of the innermost one.
+pub fn top() -> (usize, Option<usize>) {
+    FRAMES.with(|f| {
+        let frames = f.borrow();
+        (frames.len(), frames.last().map(|frame| frame.id))
+    })
+}
+
+/// The calls of the applications in progress, innermost first, as the
+/// debugger describes them.
+pub fn calls() -> Vec<String> {
+    FRAMES.with(|f| {
+        f.borrow()
+            .iter()
+            .rev()
+            .map(|frame| match &frame.call {
+                Call::Read(pos) => describe(&pos.form(), Some(pos)),
+                Call::Made(name) => format!("({name} ...)"),
+            })
+            .collect()
+    })
+}
+
+/// `form`, read at `pos`, as the debugger shows it.
+pub fn describe(form: &MalType, pos: Option<&Rc<Pos>>) -> String {
+    match pos {
+        Some(pos) => {
+            let (line, col) = pos.line_col();
+            format!("{}:{line}:{col}: {}", pos.source.file, short(form))
+        }
+        None => short(form),
+    }
+}
+
+/// `form` printed on one line, cut short if it is long.
+pub fn short(form: &MalType) -> String {
+    let text = print_string(form, true).replace('\n', " ");
+    match text.char_indices().nth(72) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text,
+    }
+}
+
+fn keyword(name: &str) -> MalType {
+    MalType::Symbol(format!(":{name}"))
+}
+
+/// The name of the function `call` applies, or `fn*` if it has none.
+pub fn name(call: &MalType) -> String {
+    match call {
+        MalType::List(items) => match items.first() {
+            Some(MalType::Symbol(name)) => name.clone(),
+            _ => "fn*".to_owned(),
+        },
+        _ => "fn*".to_owned(),
+    }
+}
+
+/// A frame of a trace: `{:name "f" :form (f x) :file "f.mal" :line 1 :col 1}`,
+/// without the position when the call was not read from source.
+fn entry(call: &MalType, pos: Option<&Rc<Pos>>) -> MalType {
+    let mut entry = vec![
+        keyword("name"),
+        MalType::String(name(call)),
+        keyword("form"),
+        call.clone(),
+    ];
+    if let Some(pos) = pos {
+        let (line, col) = pos.line_col();
+        entry.extend([
+            keyword("file"),
+            MalType::String(pos.source.file.clone()),
+            keyword("line"),
+            MalType::Number(line as i64),
+            keyword("col"),
+            MalType::Number(col as i64),
+        ]);
+    }
+    MalType::Dictionary(entry)
+}
+
+impl Call {
+    /// The frame of a trace for this call: `{:name "f"}` alone for a call
+    /// that was not read.
+    fn entry(&self) -> MalType {
+        match self {
+            Call::Read(pos) => entry(&pos.form(), Some(pos)),
+            Call::Made(name) => {
+                MalType::Dictionary(vec![keyword("name"), MalType::String(name.clone())])
+            }
+        }
+    }
+}
+
+/// Captures the trace of `err` unless it is already raised, with `call`, read
+/// at `pos`, on top when a builtin raised it.
+pub fn raised(err: &MalType, call: Option<&MalType>, pos: Option<&Rc<Pos>>) {
+    if RAISED.with(|r| r.borrow().as_ref().is_some_and(|(raised, _)| raised == err)) {
+        return;
+    }
+
+    let mut trace: Vec<MalType> = call.map(|call| entry(call, pos)).into_iter().collect();
+    FRAMES.with(|f| {
+        for frame in f.borrow().iter().rev() {
+            trace.push(frame.call.entry());
+            if frame.elided > 0 {
+                let elided = vec![keyword("elided"), MalType::Number(frame.elided as i64)];
+                trace.push(MalType::Dictionary(elided));
+            }
+        }
+    });
+    RAISED.with(|r| r.replace(Some((err.clone(), MalType::List(trace)))));
+}
+
+/// Takes the trace of `err`, if it is the error being raised.
+pub fn take(err: &MalType) -> Option<MalType> {
+    RAISED.with(|r| {
+        let mut raised = r.borrow_mut();
+        match raised.take() {
+            Some((raised, trace)) if raised == *err => Some(trace),
+            other => {
+                *raised = other;
+                None
+            }
+        }
+    })
+}
+
+/// Keeps the trace of the error `try*` caught, which threw `thrown`.
+pub fn catch(thrown: &MalType) {
+    let err = MalType::Error(Box::new(thrown.clone()));
+    if let Some(trace) = take(&err) {
+        CAUGHT_TRACES.with(|c| {
+            let mut caught = c.borrow_mut();
+            if caught.len() == CAUGHT {
+                caught.pop_front();
+            }
+            caught.push_back((thrown.clone(), trace));
+        });
+    }
+}
+
+/// `(ex-stack e)` gives the trace of the error that threw `e`, as a list of
+/// frames, innermost first, or nil if `e` was not caught lately, or was
+/// raised by the analyzer or the VM.
+pub fn ex_stack(args: Vec<MalType>) -> MalType {
+    if args.len() != 1 {
+        let message = format!(
+            "wrong number of arguments provided. Expected 1, got {}",
+            args.len()
+        );
+        return MalType::Error(Box::new(MalType::String(message)));
+    }
+
+    CAUGHT_TRACES.with(|c| {
+        c.borrow()
+            .iter()
+            .rev()
+            .find(|(thrown, _)| *thrown == args[0])
+            .map_or(MalType::Nil, |(_, trace)| trace.clone())
+    })
+}
+
+/// The lines the REPL prints for `trace`.
+pub fn format(trace: &MalType) -> String {
+    let MalType::List(frames) = trace else {
+        return String::new();
+    };
+
+    let mut lines = Vec::new();
+    for frame in frames {
+        let MalType::Dictionary(entries) = frame else {
+            continue;
+        };
+        let get = |key: &str| {
+            let key = keyword(key);
+            entries
+                .chunks(2)
+                .find(|pair| pair[0] == key)
+                .map(|pair| &pair[1])
+        };
+
+        match (
+            get("elided"),
+            get("form"),
+            get("file"),
+            get("line"),
+            get("col"),
+        ) {
+            (Some(MalType::Number(1)), ..) => lines.push("  ... 1 tail call elided".to_owned()),
+            (Some(elided), ..) => lines.push(format!("  ... {elided} tail calls elided")),
+            (_, Some(form), Some(MalType::String(file)), Some(line), Some(col)) => {
+                lines.push(format!("  at {} ({file}:{line}:{col})", short(form)))
+            }
+            (_, Some(form), ..) => lines.push(format!("  at {}", short(form))),
+            (_, None, ..) => {
+                if let Some(MalType::String(name)) = get("name") {
+                    lines.push(format!("  at ({name} ...)"));
+                }
+            }
+        }
+    }
+    lines.join("\n")
+}
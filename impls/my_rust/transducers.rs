@@ -0,0 +1,289 @@
+//! Reducing with early termination, and transducers.
+//!
+//! A reducing function takes the value accumulated so far and an element, and
+//! returns the new accumulated value, or `(reduced value)` to stop there. It
+//! is also called with no arguments for an initial value, and with only the
+//! accumulated value once the input is exhausted, to complete it.
+//!
+//! A transducer, like `(map f)`, `(filter pred)` or `(take n)`, turns a
+//! reducing function into another one that transforms each element on its way
+//! to it. Chained with `comp`, they process each element through the whole
+//! pipeline in turn, without building the collections in between.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    rc::Rc,
+};
+
+use crate::{seq, types::*};
+
+fn error(message: String) -> MalType {
+    MalType::Error(Box::new(MalType::String(message)))
+}
+
+pub fn native(f: impl Fn(Vec<MalType>) -> MalType + 'static) -> MalType {
+    MalType::Native(Rc::new(Native(Box::new(f))))
+}
+
+/// Wraps `value` to stop the reduction, unless it is already wrapped or is an
+/// error.
+pub fn reduced(value: MalType) -> MalType {
+    match value {
+        MalType::Reduced(_) | MalType::Error(_) => value,
+        _ => MalType::Reduced(Box::new(value)),
+    }
+}
+
+pub fn unreduced(value: MalType) -> MalType {
+    match value {
+        MalType::Reduced(value) => *value,
+        _ => value,
+    }
+}
+
+/// Calls the reducing function `rf` with `init` and each element in turn,
+/// until the elements run out or it returns a reduced value.
+pub fn fold(
+    rf: &MalType,
+    init: MalType,
+    items: impl Iterator<Item = Result<MalType, MalType>>,
+) -> MalType {
+    let mut acc = init;
+    for item in items {
+        let item = match item {
+            Ok(item) => item,
+            Err(err) => return err,
+        };
+
+        // moved in with vec!, as `to_vec` would copy the whole accumulated
+        // collection at every step
+        acc = rf.apply(vec![acc, item]);
+        match acc {
+            MalType::Error(_) => return acc,
+            MalType::Reduced(value) => return *value,
+            _ => {}
+        }
+    }
+
+    acc
+}
+
+/// A transducer built from `make`, which is given the reducing function to
+/// wrap.
+fn transducer(make: impl Fn(MalType) -> MalType + 'static) -> MalType {
+    native(move |args| match <[MalType; 1]>::try_from(args) {
+        Ok([rf]) => make(rf),
+        Err(args) => error(format!("a transducer takes 1 argument, got {}", args.len())),
+    })
+}
+
+/// A reducing function calling `step` with the accumulated value and an
+/// element, and `rf` itself for the initial value and the completion.
+fn reducing(
+    rf: MalType,
+    step: impl Fn(&MalType, MalType, MalType) -> MalType + 'static,
+) -> MalType {
+    native(move |args| match <[MalType; 2]>::try_from(args) {
+        Ok([acc, item]) => step(&rf, acc, item),
+        Err(args) => rf.apply(args),
+    })
+}
+
+/// `(map f)`
+pub fn map(f: MalType) -> MalType {
+    transducer(move |rf| {
+        let f = f.clone();
+        reducing(rf, move |rf, acc, item| match f.apply(vec![item]) {
+            err @ MalType::Error(_) => err,
+            item => rf.apply(vec![acc, item]),
+        })
+    })
+}
+
+/// `(filter pred)`
+pub fn filter(pred: MalType) -> MalType {
+    transducer(move |rf| {
+        let pred = pred.clone();
+        reducing(rf, move |rf, acc, item| {
+            match pred.apply([item.clone()].to_vec()) {
+                err @ MalType::Error(_) => err,
+                MalType::Nil | MalType::False => acc,
+                _ => rf.apply(vec![acc, item]),
+            }
+        })
+    })
+}
+
+/// `(take n)`, which stops the reduction once it has let `n` elements through.
+pub fn take(n: i64) -> MalType {
+    transducer(move |rf| {
+        // counted separately for each reduction the transducer is used in
+        let left = Cell::new(n);
+        reducing(rf, move |rf, acc, item| {
+            let n = left.get();
+            left.set(n - 1);
+
+            let acc = if n > 0 {
+                rf.apply(vec![acc, item])
+            } else {
+                acc
+            };
+            if n <= 1 {
+                reduced(acc)
+            } else {
+                acc
+            }
+        })
+    })
+}
+
+/// `(comp f g h)` calls `h` with its arguments, then `g` and `f` in turn with
+/// the result. Composed transducers thus apply from left to right.
+pub fn comp(fs: Vec<MalType>) -> MalType {
+    native(move |args| {
+        let Some((last, init)) = fs.split_last() else {
+            return args.into_iter().next().unwrap_or(MalType::Nil);
+        };
+
+        let mut res = last.apply(args);
+        for f in init.iter().rev() {
+            if res.is_error() {
+                break;
+            }
+            res = f.apply(vec![res]);
+        }
+        res
+    })
+}
+
+/// `(transduce xform f init coll)`, where `init` defaults to `(f)`.
+pub fn transduce(xform: &MalType, f: &MalType, init: Option<MalType>, coll: &MalType) -> MalType {
+    let rf = xform.apply(vec![f.clone()]);
+    if rf.is_error() {
+        return rf;
+    }
+
+    let init = match init {
+        Some(init) => init,
+        None => f.apply(Vec::new()),
+    };
+    if init.is_error() {
+        return init;
+    }
+
+    match fold(&rf, init, seq::iter(coll)) {
+        err @ MalType::Error(_) => err,
+        acc => rf.apply(vec![acc]),
+    }
+}
+
+/// Adds an element to a collection the way `conj` does: at the end of a
+/// vector, at the front of a list, to a set, and as a `[key value]` entry of a
+/// dictionary.
+pub fn conj(coll: MalType, item: MalType) -> MalType {
+    match (coll, item) {
+        (MalType::Vector(mut items), item) => {
+            items.push(item);
+            MalType::Vector(items)
+        }
+        (MalType::List(mut items), item) => {
+            items.insert(0, item);
+            MalType::List(items)
+        }
+        (MalType::Set(mut items), item) => {
+            items.insert(item);
+            MalType::Set(items)
+        }
+        (MalType::Nil, item) => MalType::List(vec![item]),
+        (coll @ MalType::LazySeq(_), item) => seq::cons(item, coll),
+        (MalType::Dictionary(mut items), MalType::Vector(entry)) if entry.len() == 2 => {
+            let [key, value] = <[MalType; 2]>::try_from(entry).unwrap();
+            match items.iter().step_by(2).position(|k| *k == key) {
+                Some(i) => items[i * 2 + 1] = value,
+                None => items.extend([key, value]),
+            }
+            MalType::Dictionary(items)
+        }
+        (coll, item) => error(format!(
+            "cannot add a {} to a {}",
+            MalType::discriminant_name(&item),
+            MalType::discriminant_name(&coll)
+        )),
+    }
+}
+
+/// The reducing function adding each element to the accumulated collection.
+fn conj_rf() -> MalType {
+    native(|args| match <[MalType; 2]>::try_from(args) {
+        Ok([coll, item]) => conj(coll, item),
+        Err(args) => args.into_iter().next().unwrap_or(MalType::List(Vec::new())),
+    })
+}
+
+/// `(into to coll)` or `(into to xform coll)`: the elements of `coll`, passed
+/// through `xform`, added to `to`.
+pub fn into(to: MalType, xform: Option<&MalType>, from: &MalType) -> MalType {
+    match xform {
+        Some(xform) => transduce(xform, &conj_rf(), Some(to), from),
+        None => fold(&conj_rf(), to, seq::iter(from)),
+    }
+}
+
+/// `(sequence xform coll)`: a lazy sequence of the elements of `coll` passed
+/// through `xform`, which only pulls elements from `coll` as they are needed.
+pub fn sequence(xform: &MalType, coll: MalType) -> MalType {
+    let buffer = Rc::new(RefCell::new(VecDeque::new()));
+    let collect = {
+        let buffer = buffer.clone();
+        native(move |args| {
+            let mut args = args.into_iter();
+            let acc = args.next().unwrap_or(MalType::Nil);
+            buffer.borrow_mut().extend(args);
+            acc
+        })
+    };
+
+    match xform.apply(vec![collect]) {
+        err @ MalType::Error(_) => err,
+        rf => produce(rf, buffer, Some(coll)),
+    }
+}
+
+/// The rest of a `sequence`: what the transducer already let through into
+/// `buffer`, then more of `input`, until it runs out or the reduction stops.
+fn produce(rf: MalType, buffer: Rc<RefCell<VecDeque<MalType>>>, input: Option<MalType>) -> MalType {
+    seq::lazy(move || {
+        let mut input = input.clone();
+        loop {
+            let buffered = buffer.borrow_mut().pop_front();
+            if let Some(item) = buffered {
+                return seq::cons(item, produce(rf.clone(), buffer.clone(), input));
+            }
+
+            let Some(coll) = &input else {
+                return MalType::Nil;
+            };
+
+            let done = match seq::step(coll) {
+                Ok(seq::Step::Cons(item, rest)) => match rf.apply(vec![MalType::Nil, item]) {
+                    err @ MalType::Error(_) => return err,
+                    MalType::Reduced(_) => true,
+                    _ => {
+                        input = Some(rest);
+                        false
+                    }
+                },
+                Ok(seq::Step::Empty) => true,
+                Err(err) => return err,
+            };
+
+            if done {
+                input = None;
+                if let err @ MalType::Error(_) = rf.apply(vec![MalType::Nil]) {
+                    return err;
+                }
+            }
+        }
+    })
+}
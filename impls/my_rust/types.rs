@@ -1,15 +1,58 @@
-use std::{cell::RefCell, fmt::Display, ops::Deref, rc::Rc};
+use std::{
+    cell::RefCell,
+    collections::hash_map::DefaultHasher,
+    fmt::Display,
+    hash::{Hash, Hasher},
+    ops::Deref,
+    rc::Rc,
+};
 
-use crate::{env::*, print_string};
+use indexmap::IndexSet;
+
+use crate::{
+    analyzer::Node,
+    env::*,
+    interpreter::{call_builtin, call_native},
+    isolate::{Future, Isolate},
+    pattern::Pattern,
+    print_string,
+    reader::{locate, Pos},
+    seq::LazySeq,
+    vm::Closure,
+};
 
 pub type Atom = Rc<RefCell<MalType>>;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// A builtin closing over values, like the transducer returned by `(map f)`.
+pub struct Native(pub Box<dyn Fn(Vec<MalType>) -> MalType>);
+
+impl std::fmt::Debug for Native {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Native").finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for Native {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Eq for Native {}
+
+#[derive(Debug, Clone)]
 pub enum MalType {
+    Analyzed(Rc<Node>),
     Atom(Atom),
+    Char(char),
+    Compiled(Rc<Closure>),
     Dictionary(Vec<MalType>),
+    Error(Box<MalType>),
     False,
     Func(fn(Vec<MalType>) -> MalType),
+    Future(Rc<Future>),
+    Isolate(Rc<Isolate>),
+    LazySeq(Rc<LazySeq>),
     List(Vec<MalType>),
     MalFunc {
         params: Box<MalType>,
@@ -17,19 +60,24 @@ pub enum MalType {
         env: Option<Env>,
         eval: fn(ast: MalType, env: Env) -> MalType,
         is_macro: bool,
+        /// Where the body was read, for the tree-walking evaluator.
+        pos: Option<Rc<Pos>>,
     },
+    Native(Rc<Native>),
     Nil,
     Number(i64),
+    Reduced(Box<MalType>),
+    Regex(Rc<Pattern>),
+    Set(IndexSet<MalType>),
     String(String),
     Symbol(String),
     True,
     Vector(Vec<MalType>),
+    /// Metadata and the value carrying it, as read from `^meta value` or
+    /// made by `with-meta`.
     WithMeta(Box<MalType>, Box<MalType>),
 }
 
-unsafe impl Send for MalType {}
-unsafe impl Sync for MalType {}
-
 impl MalType {
     pub fn boolean(value: bool) -> MalType {
         if value {
@@ -41,14 +89,25 @@ impl MalType {
 
     pub fn discriminant_name(value: &MalType) -> String {
         match value {
+            MalType::Analyzed(_) => "Analyzed".to_owned(),
             MalType::Atom(_) => "Atom".to_owned(),
+            MalType::Char(_) => "Char".to_owned(),
+            MalType::Compiled(_) => "Compiled".to_owned(),
             MalType::Dictionary(_) => "Dictionary".to_owned(),
+            MalType::Error(_) => "Error".to_owned(),
             MalType::False => "False".to_owned(),
             MalType::Func(_) => "Func".to_owned(),
+            MalType::Future(_) => "Future".to_owned(),
+            MalType::Isolate(_) => "Isolate".to_owned(),
+            MalType::LazySeq(_) => "LazySeq".to_owned(),
             MalType::List(_) => "List".to_owned(),
             MalType::MalFunc { .. } => "MalFunc".to_owned(),
+            MalType::Native(_) => "Native".to_owned(),
             MalType::Nil => "Nil".to_owned(),
             MalType::Number(_) => "Number".to_owned(),
+            MalType::Reduced(_) => "Reduced".to_owned(),
+            MalType::Regex(_) => "Regex".to_owned(),
+            MalType::Set(_) => "Set".to_owned(),
             MalType::String(_) => "String".to_owned(),
             MalType::Symbol(_) => "Symbol".to_owned(),
             MalType::True => "True".to_owned(),
@@ -57,6 +116,28 @@ impl MalType {
         }
     }
 
+    /// Whether this is a raised exception, on its way up to the nearest
+    /// `try*`. Every evaluator returns it as soon as a sub-form produces one.
+    pub fn is_error(&self) -> bool {
+        matches!(self, MalType::Error(_))
+    }
+
+    /// The value itself, for a value carrying metadata.
+    pub fn without_meta(self) -> MalType {
+        match self {
+            MalType::WithMeta(_, value) => *value,
+            value => value,
+        }
+    }
+
+    pub fn is_macro(&self) -> bool {
+        match self {
+            MalType::MalFunc { is_macro, .. } => *is_macro,
+            MalType::Compiled(closure) => closure.is_macro,
+            _ => false,
+        }
+    }
+
     pub fn apply(&self, args: Vec<MalType>) -> MalType {
         match self {
             MalType::MalFunc {
@@ -64,12 +145,20 @@ impl MalType {
                 env,
                 eval,
                 body,
-                is_macro,
+                pos,
+                ..
             } => {
                 let fn_env = env_bind(env.clone(), params.deref().clone(), args);
-                eval(body.deref().clone(), fn_env)
+                let body = body.deref().clone();
+                if let Some(pos) = pos {
+                    locate(&body, pos.clone());
+                }
+                eval(body, fn_env)
             }
-            MalType::Func(f) => (f)(args),
+            MalType::Func(f) => call_builtin(*f, args),
+            MalType::Native(f) => call_native(f, args),
+            MalType::WithMeta(_, f) => f.apply(args),
+            MalType::Compiled(closure) => crate::vm::call(closure, args),
             _ => {
                 println!("Trying to call a non-function");
                 MalType::Nil
@@ -78,6 +167,104 @@ impl MalType {
     }
 }
 
+/// What sets compare their elements with, and so agreeing with `=` on the
+/// values it compares structurally: a list equals the vector of the same
+/// elements, and dictionaries and sets are equal whatever their order. Lazy
+/// seqs, which `=` realizes, are only equal to themselves here.
+impl PartialEq for MalType {
+    fn eq(&self, other: &Self) -> bool {
+        use MalType::*;
+
+        match (self, other) {
+            (List(a) | Vector(a), List(b) | Vector(b)) => a == b,
+            (Dictionary(a), Dictionary(b)) => {
+                a.len() == b.len()
+                    && a.chunks(2).all(|entry| b.chunks(2).any(|other| entry == other))
+            }
+            (Set(a), Set(b)) => a == b,
+            (Analyzed(a), Analyzed(b)) => a == b,
+            (Atom(a), Atom(b)) => a == b,
+            (Char(a), Char(b)) => a == b,
+            (Compiled(a), Compiled(b)) => a == b,
+            (Error(a), Error(b)) | (Reduced(a), Reduced(b)) => a == b,
+            (Func(a), Func(b)) => std::ptr::fn_addr_eq(*a, *b),
+            (Future(a), Future(b)) => a == b,
+            (Isolate(a), Isolate(b)) => a == b,
+            (LazySeq(a), LazySeq(b)) => a == b,
+            (
+                MalFunc {
+                    params,
+                    body,
+                    env,
+                    eval,
+                    is_macro,
+                    ..
+                },
+                MalFunc {
+                    params: other_params,
+                    body: other_body,
+                    env: other_env,
+                    eval: other_eval,
+                    is_macro: other_is_macro,
+                    ..
+                },
+            ) => {
+                params == other_params
+                    && body == other_body
+                    && env == other_env
+                    && std::ptr::fn_addr_eq(*eval, *other_eval)
+                    && is_macro == other_is_macro
+            }
+            (Native(a), Native(b)) => a == b,
+            (Number(a), Number(b)) => a == b,
+            (Regex(a), Regex(b)) => a == b,
+            (String(a), String(b)) | (Symbol(a), Symbol(b)) => a == b,
+            (WithMeta(a, meta), WithMeta(b, other_meta)) => a == b && meta == other_meta,
+            (Nil, Nil) | (True, True) | (False, False) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for MalType {}
+
+/// Consistent with `PartialEq`: lists and vectors hash alike, and
+/// dictionaries and sets whatever the order of their entries. Values
+/// compared by identity, or by contents that are not plain data, only hash
+/// their kind.
+impl Hash for MalType {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            MalType::Vector(_) => std::mem::discriminant(&MalType::List(Vec::new())).hash(state),
+            _ => std::mem::discriminant(self).hash(state),
+        }
+        match self {
+            MalType::Number(n) => n.hash(state),
+            MalType::Char(c) => c.hash(state),
+            MalType::String(s) | MalType::Symbol(s) => s.hash(state),
+            MalType::List(items) | MalType::Vector(items) => items.hash(state),
+            MalType::Dictionary(items) => unordered_hash(items.chunks(2)).hash(state),
+            MalType::Set(items) => unordered_hash(items.iter()).hash(state),
+            MalType::Regex(re) => re.as_str().hash(state),
+            MalType::Error(value) | MalType::Reduced(value) => value.hash(state),
+            MalType::WithMeta(value, meta) => {
+                value.hash(state);
+                meta.hash(state);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The same whatever the order of `items`.
+fn unordered_hash<T: Hash>(items: impl Iterator<Item = T>) -> u64 {
+    items.fold(0u64, |sum, item| {
+        let mut hasher = DefaultHasher::new();
+        item.hash(&mut hasher);
+        sum.wrapping_add(hasher.finish())
+    })
+}
+
 impl Display for MalType {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(fmt, "{}", print_string(self, true))
@@ -100,6 +287,8 @@ pub enum TokenKind {
     // Literals
     Number(i64),
     String(String),
+    Char(char),
+    Regex(String),
     // Others
     Identifier(String),
     LeftParenthesis,
@@ -114,6 +303,7 @@ pub enum TokenKind {
     Unquote,
     WithMeta,
     Deref,
+    HashBrace,
     // EOF
     EOF,
 }
@@ -137,8 +327,11 @@ impl Display for TokenKind {
             TokenKind::Unquote => write!(fmt, "unquote"),
             TokenKind::WithMeta => write!(fmt, "with-meta"),
             TokenKind::Deref => write!(fmt, "deref"),
+            TokenKind::HashBrace => write!(fmt, "#{{"),
             TokenKind::SpliceUnquote => write!(fmt, "spliceunquote"),
             TokenKind::String(s) => write!(fmt, "{s}"),
+            TokenKind::Char(c) => write!(fmt, "\\{c}"),
+            TokenKind::Regex(source) => write!(fmt, "#\"{source}\""),
         }
     }
 }
@@ -173,3 +366,49 @@ impl Display for Operator {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::hash::DefaultHasher;
+
+    use super::*;
+
+    fn hash(value: &MalType) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn numbers(ns: &[i64]) -> Vec<MalType> {
+        ns.iter().map(|n| MalType::Number(*n)).collect()
+    }
+
+    #[test]
+    fn lists_and_vectors_hash_alike() {
+        let list = MalType::List(numbers(&[1, 2]));
+        let vector = MalType::Vector(numbers(&[1, 2]));
+        assert_eq!(list, vector);
+        assert_eq!(hash(&list), hash(&vector));
+    }
+
+    #[test]
+    fn dictionaries_hash_whatever_their_order() {
+        let a = MalType::Dictionary(numbers(&[1, 10, 2, 20]));
+        let b = MalType::Dictionary(numbers(&[2, 20, 1, 10]));
+        assert_eq!(a, b);
+        assert_eq!(hash(&a), hash(&b));
+        assert_ne!(a, MalType::Dictionary(numbers(&[1, 20, 2, 10])));
+    }
+
+    #[test]
+    fn a_set_holds_a_list_and_an_equal_vector_once() {
+        let set: IndexSet<MalType> = [
+            MalType::List(numbers(&[1, 2])),
+            MalType::Vector(numbers(&[1, 2])),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(set.len(), 1);
+        assert!(set.contains(&MalType::Vector(numbers(&[1, 2]))));
+    }
+}
@@ -0,0 +1,429 @@
+//! Stack virtual machine running the bytecode produced by `compiler`.
+//!
+//! Calls between compiled closures push a `Frame` instead of recursing on the
+//! Rust stack, and `TailCall` reuses the caller's frame. Builtins and functions
+//! created by the other evaluators are called through `MalType::apply`, and the
+//! `eval` special form goes through the evaluator of the running interpreter,
+//! which is this VM under `--vm`. It does not fall back to the tree-walker:
+//! `load-file` is an `eval`, so the code of every file would run there.
+//!
+//! A raised `MalType::Error` never stays on the value stack: it unwinds the
+//! frames down to the innermost `try*` handler of this VM, or is returned to
+//! the caller of `run` if there is none.
+
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{
+    compiler::{compile, Capture, Op, Proto},
+    env::*,
+    freshen, gc,
+    interpreter::{call_builtin, current_eval, tick, unbound},
+    macroexpand,
+    namespace::eval_env,
+    types::*,
+};
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Closure {
+    pub proto: Rc<Proto>,
+    pub upvalues: Vec<Rc<RefCell<MalType>>>,
+    pub is_macro: bool,
+}
+
+#[derive(Clone)]
+enum Slot {
+    Value(MalType),
+    Cell(Rc<RefCell<MalType>>),
+}
+
+struct Frame {
+    closure: Rc<Closure>,
+    ip: usize,
+    base: usize,
+}
+
+/// An active `try*`: how far to unwind, and where its handler starts.
+struct Handler {
+    frames: usize,
+    stack: usize,
+    target: usize,
+}
+
+#[derive(Default)]
+struct Vm {
+    stack: Vec<MalType>,
+    slots: Vec<Slot>,
+    frames: Vec<Frame>,
+    handlers: Vec<Handler>,
+}
+
+/// Entry point with the same signature as the tree-walking `eval`, used when
+/// the REPL runs with `--vm`. The forms of a top-level `do` are compiled one at
+/// a time, so macros defined by earlier forms are expanded in later ones.
+pub fn eval(ast: MalType, env: Env) -> MalType {
+    match &ast {
+        MalType::List(list) if list.first() == Some(&MalType::Symbol("do".to_owned())) => {
+            let mut res = MalType::Nil;
+            for item in list.iter().skip(1) {
+                res = eval(item.clone(), env.clone());
+                if res.is_error() {
+                    break;
+                }
+            }
+            return res;
+        }
+        _ => {}
+    }
+
+    let closure = Closure {
+        proto: compile(&ast, &env),
+        upvalues: Vec::new(),
+        is_macro: false,
+    };
+
+    call(&Rc::new(closure), Vec::new())
+}
+
+pub fn call(closure: &Rc<Closure>, args: Vec<MalType>) -> MalType {
+    let mut vm = Vm::default();
+    vm.enter(closure.clone(), args);
+    vm.run()
+}
+
+impl Vm {
+    fn enter(&mut self, closure: Rc<Closure>, args: Vec<MalType>) {
+        let proto = &closure.proto;
+        let base = self.slots.len();
+        self.slots
+            .resize(base + proto.slots, Slot::Value(MalType::Nil));
+
+        let mut args = args.into_iter();
+        for i in 0..proto.arity {
+            let arg = args.next().unwrap_or_else(|| missing(proto, i));
+            self.slots[base + i] = slot(proto.boxed[i], arg);
+        }
+
+        if proto.variadic {
+            let rest = MalType::List(args.collect());
+            self.slots[base + proto.arity] = slot(proto.boxed[proto.arity], rest);
+        }
+
+        self.frames.push(Frame {
+            closure,
+            ip: 0,
+            base,
+        });
+    }
+
+    fn leave(&mut self) {
+        let frame = self.frames.pop().unwrap();
+        self.slots.truncate(frame.base);
+    }
+
+    /// Unwinds to the innermost handler and jumps to it with the thrown value
+    /// on the stack. Gives the error back if this VM has no handler left.
+    fn raise(&mut self, err: MalType) -> Result<(), MalType> {
+        let Some(handler) = self.handlers.pop() else {
+            return Err(err);
+        };
+        let MalType::Error(thrown) = err else {
+            unreachable!()
+        };
+
+        while self.frames.len() > handler.frames {
+            self.leave();
+        }
+        self.stack.truncate(handler.stack);
+        self.stack.push(*thrown);
+        self.frames.last_mut().unwrap().ip = handler.target;
+
+        Ok(())
+    }
+
+    fn run(&mut self) -> MalType {
+        loop {
+            let (op, base) = {
+                let frame = self.frames.last_mut().unwrap();
+                let op = frame.closure.proto.code[frame.ip];
+                frame.ip += 1;
+                (op, frame.base)
+            };
+            let closure = &self.frames.last().unwrap().closure;
+            let proto = &closure.proto;
+
+            match op {
+                Op::Const(i) => self.stack.push(proto.constants[i].clone()),
+                Op::Global(i) => {
+                    let MalType::Symbol(s) = &proto.constants[i] else {
+                        unreachable!()
+                    };
+                    let value = env_get(&proto.globals, s).unwrap_or_else(|| unbound(s));
+                    self.stack.push(value);
+                }
+                Op::Local(i) => {
+                    let value = match &self.slots[base + i] {
+                        Slot::Value(v) => v.clone(),
+                        Slot::Cell(c) => c.borrow().clone(),
+                    };
+                    self.stack.push(value);
+                }
+                Op::Upvalue(i) => self.stack.push(closure.upvalues[i].borrow().clone()),
+                Op::Declare(i) => {
+                    if proto.boxed[i] {
+                        self.slots[base + i] = Slot::Cell(gc::atom(MalType::Nil));
+                    }
+                }
+                Op::SetLocal(i) => {
+                    let value = self.stack.pop().unwrap();
+                    match &mut self.slots[base + i] {
+                        Slot::Cell(c) => *c.borrow_mut() = value,
+                        s => *s = Slot::Value(value),
+                    }
+                }
+                Op::Def(i) => {
+                    let value = self.stack.pop().unwrap();
+                    env_set(&proto.globals, &proto.constants[i], value.clone());
+                    self.stack.push(value);
+                }
+                Op::DefMacro(i) => {
+                    let value = match self.stack.pop().unwrap() {
+                        MalType::MalFunc {
+                            params,
+                            body,
+                            env,
+                            eval,
+                            pos,
+                            ..
+                        } => MalType::MalFunc {
+                            params,
+                            body,
+                            env,
+                            eval,
+                            is_macro: true,
+                            pos,
+                        },
+                        MalType::Compiled(c) => MalType::Compiled(gc::closure(Closure {
+                            proto: c.proto.clone(),
+                            upvalues: c.upvalues.clone(),
+                            is_macro: true,
+                        })),
+                        _ => {
+                            println!(
+                                "Returned a non-MalFunc from evaluating {}",
+                                proto.constants[i]
+                            );
+                            MalType::Nil
+                        }
+                    };
+
+                    if !matches!(value, MalType::Nil) {
+                        env_set(&proto.globals, &proto.constants[i], value.clone());
+                    }
+                    self.stack.push(value);
+                }
+                Op::Pop => {
+                    self.stack.pop();
+                }
+                Op::Jump(target) => self.frames.last_mut().unwrap().ip = target,
+                Op::JumpIfFalse(target) => {
+                    if matches!(self.stack.pop(), Some(MalType::Nil | MalType::False)) {
+                        self.frames.last_mut().unwrap().ip = target;
+                    }
+                }
+                Op::Closure(i) => {
+                    let nested = proto.protos[i].clone();
+                    let upvalues = nested
+                        .captures
+                        .iter()
+                        .map(|capture| match capture {
+                            Capture::Local(s) => match &self.slots[base + s] {
+                                Slot::Cell(c) => c.clone(),
+                                Slot::Value(v) => gc::atom(v.clone()),
+                            },
+                            Capture::Upvalue(u) => closure.upvalues[*u].clone(),
+                        })
+                        .collect();
+
+                    self.stack.push(MalType::Compiled(gc::closure(Closure {
+                        proto: nested,
+                        upvalues,
+                        is_macro: false,
+                    })));
+                }
+                Op::Call(argc) => {
+                    let args = self.stack.split_off(self.stack.len() - argc);
+                    match (tick(), self.stack.pop().unwrap()) {
+                        (Err(err), _) => self.stack.push(err),
+                        (Ok(()), MalType::Compiled(c)) => self.enter(c, args),
+                        (Ok(()), func) => self.stack.push(apply(func, args)),
+                    }
+                }
+                Op::TailCall(argc) => {
+                    let args = self.stack.split_off(self.stack.len() - argc);
+                    match (tick(), self.stack.pop().unwrap()) {
+                        (Err(err), _) => self.stack.push(err),
+                        (Ok(()), MalType::Compiled(c)) => {
+                            self.leave();
+                            self.enter(c, args);
+                        }
+                        (Ok(()), func) => {
+                            let value = apply(func, args);
+                            self.leave();
+                            if self.frames.is_empty() {
+                                return value;
+                            }
+                            self.stack.push(value);
+                        }
+                    }
+                }
+                Op::Return => {
+                    self.leave();
+                    if self.frames.is_empty() {
+                        return self.stack.pop().unwrap();
+                    }
+                }
+                Op::Vector(n) => {
+                    let items = self.stack.split_off(self.stack.len() - n);
+                    self.stack.push(MalType::Vector(items));
+                }
+                Op::Dictionary(n) => {
+                    let items = self.stack.split_off(self.stack.len() - n);
+                    self.stack.push(MalType::Dictionary(items));
+                }
+                Op::Set(n) => {
+                    let items = self.stack.split_off(self.stack.len() - n);
+                    self.stack.push(MalType::Set(items.into_iter().collect()));
+                }
+                Op::Eval => {
+                    let form = self.stack.pop().unwrap();
+                    self.stack.push(current_eval()(form, eval_env(&proto.globals)));
+                }
+                Op::Freshen(i) => {
+                    let MalType::List(made) = &proto.constants[i] else {
+                        unreachable!()
+                    };
+                    let value = self.stack.pop().unwrap();
+                    self.stack.push(freshen(value, made));
+                }
+                Op::Macroexpand(i) => {
+                    let expanded = macroexpand(proto.constants[i].clone(), &proto.globals);
+                    self.stack.push(expanded);
+                }
+                Op::Try(target) => self.handlers.push(Handler {
+                    frames: self.frames.len(),
+                    stack: self.stack.len(),
+                    target,
+                }),
+                Op::EndTry => {
+                    self.handlers.pop();
+                }
+            }
+
+            if self.stack.last().is_some_and(MalType::is_error) {
+                let err = self.stack.pop().unwrap();
+                if let Err(err) = self.raise(err) {
+                    return err;
+                }
+            }
+        }
+    }
+}
+
+/// What a parameter no argument was passed for holds: like in an `Env` it
+/// binds nothing, so its name is looked up in the globals, and reading it
+/// raises if it is not bound there either.
+fn missing(proto: &Proto, i: usize) -> MalType {
+    let Some((MalType::List(params) | MalType::Vector(params), _)) = &proto.source else {
+        unreachable!()
+    };
+    let MalType::Symbol(name) = &params[i] else {
+        unreachable!()
+    };
+    env_get(&proto.globals, name).unwrap_or_else(|| unbound(name))
+}
+
+fn slot(boxed: bool, value: MalType) -> Slot {
+    if boxed {
+        Slot::Cell(gc::atom(value))
+    } else {
+        Slot::Value(value)
+    }
+}
+
+fn apply(func: MalType, args: Vec<MalType>) -> MalType {
+    match func {
+        MalType::Func(f) => call_builtin(f, args),
+        MalType::MalFunc { .. } | MalType::Native(_) | MalType::WithMeta(..) => func.apply(args),
+        _ => MalType::Nil,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{interpreter::Interpreter, printer::print_string, reader::read_str};
+
+    fn rep(interpreter: &Interpreter, source: &str) -> String {
+        print_string(&interpreter.eval(read_str(source)), true)
+    }
+
+    #[test]
+    fn stops_a_top_level_do_at_an_error() {
+        let interpreter = Interpreter::new(super::eval, Vec::new());
+        let res = interpreter.eval(read_str("(do (throw \"x\") (def! after 1))"));
+        assert!(res.is_error());
+        assert_eq!(
+            rep(&interpreter, "(try* after (catch* e e))"),
+            "\"'after' not found\""
+        );
+    }
+
+    #[test]
+    fn runs_closures_and_tail_calls() {
+        let interpreter = Interpreter::new(super::eval, Vec::new());
+        rep(
+            &interpreter,
+            "(def! adder (fn* [n] (fn* [x] (let* [y (+ x n)] y))))",
+        );
+        assert_eq!(rep(&interpreter, "((adder 2) 3)"), "5");
+
+        rep(
+            &interpreter,
+            "(def! down (fn* [n] (if (= n 0) :done (down (- n 1)))))",
+        );
+        assert_eq!(rep(&interpreter, "(down 100000)"), ":done");
+    }
+
+    #[test]
+    fn catches_what_is_thrown_in_a_call() {
+        let interpreter = Interpreter::new(super::eval, Vec::new());
+        rep(&interpreter, "(def! f (fn* [x] (throw {:x x})))");
+        assert_eq!(
+            rep(&interpreter, "(try* (+ 1 (f 2)) (catch* e (get e :x)))"),
+            "2"
+        );
+    }
+
+    #[test]
+    fn binds_a_def_in_a_local_scope_in_that_scope() {
+        let interpreter = Interpreter::new(super::eval, Vec::new());
+        assert_eq!(rep(&interpreter, "(let* [x 1] (do (def! yy 2) yy))"), "2");
+        assert_eq!(
+            rep(&interpreter, "(try* yy (catch* e e))"),
+            "\"'yy' not found\""
+        );
+        let source = "((fn* [] (do (def! f (fn* [n] (if (= n 0) :done (f (- n 1))))) (f 3))))";
+        assert_eq!(rep(&interpreter, source), ":done");
+    }
+
+    #[test]
+    fn raises_for_a_parameter_no_argument_was_passed_for() {
+        let interpreter = Interpreter::new(super::eval, Vec::new());
+        assert_eq!(
+            rep(&interpreter, "(try* ((fn* [x y] y) 1) (catch* e e))"),
+            "\"'y' not found\""
+        );
+        assert_eq!(rep(&interpreter, "((fn* [x y] x) 1)"), "1");
+        rep(&interpreter, "(def! y 5)");
+        assert_eq!(rep(&interpreter, "((fn* [x y] y) 1)"), "5");
+    }
+}